@@ -0,0 +1,223 @@
+// Admin API for managing the allow and deny lists and the disposable,
+// role-account and free-provider datasets at runtime, and for the usage of
+// the API keys.
+//
+// Rules and dataset entries added here are written to their files in
+// `files`. Rules are only kept in memory, and lost on restart, when
+// `files.access_lists` is not set, which `persisted` in the response reports.
+//
+// Every endpoint requires `Authorization: Bearer <admin_token>`. If
+// `server.admin_token` is not configured, the admin API is disabled.
+
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::auth;
+use crate::config;
+use crate::datasets::{self, Dataset};
+use crate::errors::ApiError;
+use crate::lists::{self, ListError, ListRule};
+use crate::mail::clear_cache;
+
+#[derive(Serialize)]
+struct Created {
+    id: u64,
+    persisted: bool,
+}
+
+#[derive(Deserialize)]
+struct NewEntries {
+    entries: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DatasetSizes {
+    disposable: usize,
+    role_accounts: usize,
+    free_providers: usize,
+}
+
+impl DatasetSizes {
+    fn current() -> Self {
+        let datasets = datasets::global()
+            .read()
+            .expect("Datasets lock is never poisoned. qed.");
+
+        DatasetSizes {
+            disposable: datasets.disposable.len(),
+            role_accounts: datasets.role_accounts.len(),
+            free_providers: datasets.free_providers.len(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Added {
+    added: usize,
+}
+
+pub(crate) fn authorize(req: &HttpRequest) -> Result<(), HttpResponse> {
+    let token = match &config::get().server.admin_token {
+        Some(token) if !token.is_empty() => token,
+        _ => {
+            return Err(HttpResponse::Forbidden()
+                .json(ApiError::new("admin_disabled", "Admin API is disabled")))
+        }
+    };
+
+    let authorized = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| auth::constant_time_eq(value.as_bytes(), token.as_bytes()))
+        .unwrap_or(false);
+
+    if authorized {
+        Ok(())
+    } else {
+        Err(HttpResponse::Unauthorized()
+            .insert_header(("WWW-Authenticate", "Bearer"))
+            .json(ApiError::new("invalid_admin_token", "Invalid admin token")))
+    }
+}
+
+#[get("/api/admin/lists")]
+async fn get_rules(req: HttpRequest) -> impl Responder {
+    if let Err(resp) = authorize(&req) {
+        return resp;
+    }
+
+    let lists = lists::global()
+        .read()
+        .expect("Lists lock is never poisoned. qed.");
+
+    HttpResponse::Ok().json(lists.rules())
+}
+
+#[post("/api/admin/lists")]
+async fn add_rule(req: HttpRequest, rule: web::Json<ListRule>) -> impl Responder {
+    if let Err(resp) = authorize(&req) {
+        return resp;
+    }
+
+    let (added, persisted) = {
+        let mut lists = lists::global()
+            .write()
+            .expect("Lists lock is never poisoned. qed.");
+        (lists.add(rule.into_inner()), lists.is_persisted())
+    };
+
+    match added {
+        Ok(id) => {
+            clear_cache().await;
+            HttpResponse::Created().json(Created { id, persisted })
+        }
+        Err(err @ ListError::IoError(_)) => {
+            HttpResponse::InternalServerError().json(ApiError::new("save_failed", err))
+        }
+        Err(err) => HttpResponse::BadRequest().json(ApiError::new("invalid_rule", err)),
+    }
+}
+
+#[delete("/api/admin/lists/{id}")]
+async fn remove_rule(req: HttpRequest, id: web::Path<u64>) -> impl Responder {
+    if let Err(resp) = authorize(&req) {
+        return resp;
+    }
+
+    let removed = lists::global()
+        .write()
+        .expect("Lists lock is never poisoned. qed.")
+        .remove(id.into_inner());
+
+    match removed {
+        Ok(true) => {
+            clear_cache().await;
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => {
+            HttpResponse::NotFound().json(ApiError::new("not_found", "No list rule with this id"))
+        }
+        Err(err) => HttpResponse::InternalServerError().json(ApiError::new("save_failed", err)),
+    }
+}
+
+#[get("/api/admin/datasets")]
+async fn get_datasets(req: HttpRequest) -> impl Responder {
+    if let Err(resp) = authorize(&req) {
+        return resp;
+    }
+
+    HttpResponse::Ok().json(DatasetSizes::current())
+}
+
+#[post("/api/admin/datasets/reload")]
+async fn reload_datasets(req: HttpRequest) -> impl Responder {
+    if let Err(resp) = authorize(&req) {
+        return resp;
+    }
+
+    let reloaded = datasets::global()
+        .write()
+        .expect("Datasets lock is never poisoned. qed.")
+        .reload();
+
+    match reloaded {
+        Ok(()) => {
+            clear_cache().await;
+            HttpResponse::Ok().json(DatasetSizes::current())
+        }
+        Err(err) => HttpResponse::InternalServerError().json(ApiError::new("reload_failed", err)),
+    }
+}
+
+#[post("/api/admin/datasets/{dataset}")]
+async fn add_entries(
+    req: HttpRequest,
+    dataset: web::Path<Dataset>,
+    new: web::Json<NewEntries>,
+) -> impl Responder {
+    if let Err(resp) = authorize(&req) {
+        return resp;
+    }
+
+    let added = datasets::global()
+        .write()
+        .expect("Datasets lock is never poisoned. qed.")
+        .list_mut(dataset.into_inner())
+        .add(&new.entries);
+
+    match added {
+        Ok(added) => {
+            clear_cache().await;
+            HttpResponse::Ok().json(Added { added })
+        }
+        Err(err) => HttpResponse::InternalServerError().json(ApiError::new("save_failed", err)),
+    }
+}
+
+#[get("/api/admin/usage")]
+async fn get_usage(req: HttpRequest) -> impl Responder {
+    if let Err(resp) = authorize(&req) {
+        return resp;
+    }
+
+    let usage = auth::global()
+        .lock()
+        .expect("API keys lock is never poisoned. qed.")
+        .usage();
+
+    HttpResponse::Ok().json(usage)
+}
+
+/// Register the admin endpoints on an `App`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_rules)
+        .service(add_rule)
+        .service(remove_rule)
+        .service(get_datasets)
+        .service(reload_datasets)
+        .service(add_entries)
+        .service(get_usage);
+}
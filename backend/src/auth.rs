@@ -0,0 +1,673 @@
+// API keys for the check endpoints, with per-key quotas and rate limits.
+//
+// Keys come from `auth.keys` in the config and from the JSON array of
+// `ApiKey` in `files.api_keys`; without any, the check endpoints are open.
+// Callers send their key as `Authorization: Bearer <key>` or `X-Api-Key`.
+// The middleware in `main` rejects missing or unknown keys and keys over
+// their request rate, then the handlers charge the addresses of the request
+// against the key's daily and monthly quotas, once they know how many there
+// are. Usage is kept in memory, so it starts over when the server restarts.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderMap;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::error::Error as SerdeError;
+
+use crate::config::AuthConfig;
+use crate::errors::ApiError;
+use crate::metrics;
+
+/// Header carrying the API key, as an alternative to `Authorization`.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+static API_KEYS: Lazy<Mutex<ApiKeys>> = Lazy::new(|| Mutex::new(ApiKeys::new()));
+
+/// The process-wide keys and their usage.
+pub fn global() -> &'static Mutex<ApiKeys> {
+    &API_KEYS
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    /// Identifies the key in usage reports, metrics and logs.
+    pub name: String,
+    pub key: String,
+    /// Addresses per UTC day, `auth.daily_quota` when unset.
+    #[serde(default)]
+    pub daily_quota: Option<u64>,
+    /// Addresses per UTC calendar month, `auth.monthly_quota` when unset.
+    #[serde(default)]
+    pub monthly_quota: Option<u64>,
+    /// Requests per minute, `auth.rate_limit` when unset.
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+}
+
+/// Name of the key a request was authenticated with, stored in the request
+/// extensions by `authenticate`.
+#[derive(Debug, Clone)]
+pub struct Caller(pub String);
+
+/// Why a request was turned away.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    MissingKey,
+    InvalidKey,
+    /// Over the request rate, can retry after this many seconds.
+    RateLimited(u64),
+    /// The request would take the key over its quota for `period`.
+    QuotaExceeded {
+        period: &'static str,
+        quota: u64,
+        used: u64,
+        requested: u64,
+    },
+}
+
+impl Rejection {
+    fn code(&self) -> &'static str {
+        match self {
+            Rejection::MissingKey => "missing_api_key",
+            Rejection::InvalidKey => "invalid_api_key",
+            Rejection::RateLimited(_) => "rate_limited",
+            Rejection::QuotaExceeded { .. } => "quota_exceeded",
+        }
+    }
+
+    /// 401 for key problems, 429 with `Retry-After` for limits.
+    pub fn response(&self) -> HttpResponse {
+        metrics::record_rejection(self.code());
+
+        let body = ApiError::new(self.code(), self);
+        match self {
+            Rejection::MissingKey | Rejection::InvalidKey => HttpResponse::Unauthorized()
+                .insert_header(("WWW-Authenticate", "Bearer"))
+                .json(body),
+            Rejection::RateLimited(retry_after) => HttpResponse::TooManyRequests()
+                .insert_header(("Retry-After", retry_after.to_string()))
+                .json(body),
+            Rejection::QuotaExceeded { period, .. } => HttpResponse::TooManyRequests()
+                .insert_header((
+                    "Retry-After",
+                    seconds_until_reset(period, now_secs()).to_string(),
+                ))
+                .json(body),
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::MissingKey => write!(
+                f,
+                "An API key is required, as `Authorization: Bearer <key>` or `X-Api-Key: <key>`"
+            ),
+            Rejection::InvalidKey => write!(f, "Unknown API key"),
+            Rejection::RateLimited(retry_after) => write!(
+                f,
+                "Too many requests for this API key, retry in {} seconds",
+                retry_after
+            ),
+            Rejection::QuotaExceeded {
+                period,
+                quota,
+                used,
+                requested,
+            } => write!(
+                f,
+                "{} addresses would exceed the {} quota of {} for this API key, {} used",
+                requested, period, quota, used
+            ),
+        }
+    }
+}
+
+/// Possible errors when loading keys.
+#[derive(Debug)]
+pub enum AuthError {
+    IoError(io::Error),
+    SerdeError(SerdeError),
+    /// A key without a name or value, or with one already taken.
+    Invalid(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<io::Error> for AuthError {
+    fn from(error: io::Error) -> Self {
+        AuthError::IoError(error)
+    }
+}
+
+impl From<SerdeError> for AuthError {
+    fn from(error: SerdeError) -> Self {
+        AuthError::SerdeError(error)
+    }
+}
+
+/// Usage of one key since the server started.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Usage {
+    pub requests: u64,
+    pub addresses: u64,
+    /// Addresses charged in the current UTC day.
+    pub today: u64,
+    /// Addresses charged in the current UTC month.
+    pub this_month: u64,
+    #[serde(skip)]
+    day: i64,
+    #[serde(skip)]
+    month: i64,
+    /// Requests left in the rate limit bucket, refilled continuously.
+    #[serde(skip)]
+    tokens: f64,
+    #[serde(skip)]
+    refilled: Option<Instant>,
+}
+
+impl Usage {
+    /// Start over the counters of a day or month that has ended.
+    fn roll(&mut self, day: i64) {
+        if self.day != day {
+            self.day = day;
+            self.today = 0;
+        }
+        let month = month_of(day);
+        if self.month != month {
+            self.month = month;
+            self.this_month = 0;
+        }
+    }
+
+    /// Take a request out of the bucket at `now`, or return the seconds
+    /// until one is available.
+    fn take_token(&mut self, rate_limit: u32, now: Instant) -> Result<(), u64> {
+        let capacity = f64::from(rate_limit);
+        let per_second = capacity / 60.0;
+        self.tokens = match self.refilled {
+            Some(refilled) => (self.tokens
+                + now.duration_since(refilled).as_secs_f64() * per_second)
+                .min(capacity),
+            None => capacity,
+        };
+        self.refilled = Some(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - self.tokens) / per_second).ceil() as u64)
+        }
+    }
+}
+
+/// A key's limits and usage, as reported by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyUsage {
+    pub name: String,
+    pub daily_quota: Option<u64>,
+    pub monthly_quota: Option<u64>,
+    pub rate_limit: Option<u32>,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+#[derive(Debug)]
+struct StoredKey {
+    /// With the limits from `AuthConfig` filled in.
+    key: ApiKey,
+    usage: Usage,
+}
+
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    keys: Vec<StoredKey>,
+}
+
+impl ApiKeys {
+    pub fn new() -> Self {
+        ApiKeys::default()
+    }
+
+    /// Add the keys from `config` and from the JSON array of `ApiKey` in
+    /// `path`.
+    pub fn load(&mut self, config: &AuthConfig, path: Option<&str>) -> Result<(), AuthError> {
+        let mut keys = config.keys.clone();
+        if let Some(path) = path {
+            let from_file: Vec<ApiKey> = serde_json::from_str(&fs::read_to_string(path)?)?;
+            keys.extend(from_file);
+        }
+
+        for mut key in keys {
+            key.daily_quota = key.daily_quota.or(config.daily_quota);
+            key.monthly_quota = key.monthly_quota.or(config.monthly_quota);
+            key.rate_limit = key.rate_limit.or(config.rate_limit);
+            self.add(key)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn add(&mut self, key: ApiKey) -> Result<(), AuthError> {
+        if key.name.trim().is_empty() || key.key.trim().is_empty() {
+            return Err(AuthError::Invalid("API keys need a name and a key".into()));
+        }
+        if key.rate_limit == Some(0) {
+            return Err(AuthError::Invalid(format!(
+                "API key \"{}\" has a rate limit of 0",
+                key.name
+            )));
+        }
+        if self
+            .keys
+            .iter()
+            .any(|stored| stored.key.name == key.name || stored.key.key == key.key)
+        {
+            return Err(AuthError::Invalid(format!(
+                "API key \"{}\" is defined twice",
+                key.name
+            )));
+        }
+
+        self.keys.push(StoredKey {
+            key,
+            usage: Usage::default(),
+        });
+
+        Ok(())
+    }
+
+    /// Whether requests need a key at all.
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Count a request against the key `secret` and return the key's name,
+    /// or `None` if keys are disabled.
+    pub fn authenticate(&mut self, secret: Option<&str>) -> Result<Option<String>, Rejection> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+
+        let secret = secret.ok_or(Rejection::MissingKey)?;
+        let stored = self
+            .keys
+            .iter_mut()
+            .find(|stored| constant_time_eq(stored.key.key.as_bytes(), secret.as_bytes()))
+            .ok_or(Rejection::InvalidKey)?;
+
+        if let Some(rate_limit) = stored.key.rate_limit {
+            stored
+                .usage
+                .take_token(rate_limit, Instant::now())
+                .map_err(Rejection::RateLimited)?;
+        }
+        stored.usage.requests += 1;
+        metrics::record_api_request(&stored.key.name);
+
+        Ok(Some(stored.key.name.clone()))
+    }
+
+    /// Charge `addresses` to the key called `name`, unless that takes it over
+    /// a quota. Requests over a quota are refused whole.
+    pub fn charge(&mut self, name: &str, addresses: u64) -> Result<(), Rejection> {
+        let stored = match self.keys.iter_mut().find(|stored| stored.key.name == name) {
+            Some(stored) => stored,
+            None => return Ok(()),
+        };
+        stored.usage.roll(today());
+
+        let quotas = [
+            ("daily", stored.key.daily_quota, stored.usage.today),
+            ("monthly", stored.key.monthly_quota, stored.usage.this_month),
+        ];
+        for (period, quota, used) in quotas.iter() {
+            if let Some(quota) = quota {
+                if used + addresses > *quota {
+                    return Err(Rejection::QuotaExceeded {
+                        period,
+                        quota: *quota,
+                        used: *used,
+                        requested: addresses,
+                    });
+                }
+            }
+        }
+
+        stored.usage.addresses += addresses;
+        stored.usage.today += addresses;
+        stored.usage.this_month += addresses;
+        metrics::record_api_addresses(&stored.key.name, addresses);
+
+        Ok(())
+    }
+
+    pub fn usage(&self) -> Vec<KeyUsage> {
+        let day = today();
+
+        self.keys
+            .iter()
+            .map(|stored| {
+                let mut usage = stored.usage.clone();
+                usage.roll(day);
+
+                KeyUsage {
+                    name: stored.key.name.clone(),
+                    daily_quota: stored.key.daily_quota,
+                    monthly_quota: stored.key.monthly_quota,
+                    rate_limit: stored.key.rate_limit,
+                    usage,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The key sent with a request, if any.
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| {
+            headers
+                .get("Authorization")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+        })
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+}
+
+/// Check the key of a request and remember it as the `Caller`, or return
+/// the response refusing the request.
+pub fn authenticate(req: &ServiceRequest) -> Result<(), HttpResponse> {
+    let caller = global()
+        .lock()
+        .expect("API keys lock is never poisoned. qed.")
+        .authenticate(api_key(req.headers()))
+        .map_err(|rejection| rejection.response())?;
+
+    if let Some(name) = caller {
+        req.extensions_mut().insert(Caller(name));
+    }
+
+    Ok(())
+}
+
+/// Charge `addresses` to the caller of `req`, or return the response
+/// refusing the request. Without a `Caller`, e.g. on a route `authenticate`
+/// didn't run for, it's refused whenever keys are configured.
+pub fn charge(req: &HttpRequest, addresses: usize) -> Result<(), HttpResponse> {
+    let caller = req.extensions().get::<Caller>().cloned();
+    let mut api_keys = global()
+        .lock()
+        .expect("API keys lock is never poisoned. qed.");
+
+    match caller {
+        Some(Caller(name)) => api_keys.charge(&name, addresses as u64),
+        None if api_keys.is_enabled() => Err(Rejection::MissingKey),
+        None => Ok(()),
+    }
+    .map_err(|rejection| rejection.response())
+}
+
+/// Compare secrets in a time that doesn't depend on where they differ.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Days since 1970-01-01, in UTC.
+fn today() -> i64 {
+    now_secs().div_euclid(86_400)
+}
+
+/// Months since January 1970 of a day since 1970-01-01.
+fn month_of(day: i64) -> i64 {
+    let (year, month) = year_month(day);
+    (year - 1970) * 12 + month - 1
+}
+
+/// Civil year and month (1 to 12) of a day since 1970-01-01, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+fn year_month(day: i64) -> (i64, i64) {
+    let z = day + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month)
+}
+
+/// Seconds from `now`, in seconds since the epoch, until the quota of
+/// `period` starts over.
+fn seconds_until_reset(period: &str, now: i64) -> i64 {
+    let day = now.div_euclid(86_400);
+    let next = if period == "monthly" {
+        let month = month_of(day);
+        let mut next = day + 1;
+        while month_of(next) == month {
+            next += 1;
+        }
+        next
+    } else {
+        day + 1
+    };
+
+    next * 86_400 - now
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    // Days since 1970-01-01.
+    const DEC_31_2023: i64 = 19_722;
+    const JAN_1_2024: i64 = 19_723;
+    const FEB_28_2024: i64 = 19_781;
+    const FEB_29_2024: i64 = 19_782;
+    const MAR_1_2024: i64 = 19_783;
+    const FEB_28_2023: i64 = 19_416;
+    const MAR_1_2023: i64 = 19_417;
+
+    fn key(name: &str, daily_quota: Option<u64>, monthly_quota: Option<u64>) -> ApiKey {
+        ApiKey {
+            name: name.into(),
+            key: format!("{}-secret", name),
+            daily_quota,
+            monthly_quota,
+            rate_limit: None,
+        }
+    }
+
+    fn retry_after(rejection: Rejection) -> Option<String> {
+        rejection
+            .response()
+            .headers()
+            .get("Retry-After")
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn year_month_of_days() {
+        assert_eq!(year_month(0), (1970, 1));
+        assert_eq!(year_month(-1), (1969, 12));
+        assert_eq!(year_month(DEC_31_2023), (2023, 12));
+        assert_eq!(year_month(JAN_1_2024), (2024, 1));
+        assert_eq!(year_month(FEB_29_2024), (2024, 2));
+        assert_eq!(year_month(MAR_1_2024), (2024, 3));
+        assert_eq!(year_month(11_016), (2000, 2));
+    }
+
+    #[test]
+    fn months_roll_over_the_year() {
+        assert_eq!(month_of(JAN_1_2024), month_of(DEC_31_2023) + 1);
+        assert_eq!(month_of(0), 0);
+    }
+
+    #[test]
+    fn roll_resets_the_day_and_month_counters() {
+        let mut usage = Usage::default();
+        usage.roll(DEC_31_2023);
+        usage.today = 5;
+        usage.this_month = 7;
+
+        usage.roll(DEC_31_2023);
+        assert_eq!((usage.today, usage.this_month), (5, 7));
+
+        usage.roll(JAN_1_2024);
+        assert_eq!((usage.today, usage.this_month), (0, 0));
+    }
+
+    #[test]
+    fn roll_over_leap_february() {
+        let mut usage = Usage::default();
+        usage.roll(FEB_28_2024);
+        usage.today = 5;
+        usage.this_month = 7;
+
+        usage.roll(FEB_29_2024);
+        assert_eq!((usage.today, usage.this_month), (0, 7));
+        usage.today = 1;
+        usage.this_month = 8;
+
+        usage.roll(MAR_1_2024);
+        assert_eq!((usage.today, usage.this_month), (0, 0));
+
+        usage.roll(FEB_28_2023);
+        usage.this_month = 3;
+        usage.roll(MAR_1_2023);
+        assert_eq!(usage.this_month, 0);
+    }
+
+    #[test]
+    fn quotas_reset_at_midnight_and_month_end() {
+        let one_am = DEC_31_2023 * 86_400 + 3_600;
+        assert_eq!(seconds_until_reset("daily", one_am), 23 * 3_600);
+        assert_eq!(seconds_until_reset("monthly", one_am), 23 * 3_600);
+
+        let leap_february = FEB_28_2024 * 86_400;
+        assert_eq!(seconds_until_reset("daily", leap_february), 86_400);
+        assert_eq!(seconds_until_reset("monthly", leap_february), 2 * 86_400);
+
+        let february = FEB_28_2023 * 86_400;
+        assert_eq!(seconds_until_reset("monthly", february), 86_400);
+    }
+
+    #[test]
+    fn rate_limit_refills_continuously() {
+        let start = Instant::now();
+        let mut usage = Usage::default();
+
+        for _ in 0..60 {
+            assert_eq!(usage.take_token(60, start), Ok(()));
+        }
+        assert_eq!(usage.take_token(60, start), Err(1));
+        assert_eq!(
+            usage.take_token(60, start + Duration::from_millis(1_500)),
+            Ok(())
+        );
+
+        let mut usage = Usage::default();
+        assert_eq!(usage.take_token(1, start), Ok(()));
+        assert_eq!(usage.take_token(1, start), Err(60));
+        assert_eq!(
+            usage.take_token(1, start + Duration::from_secs(30)),
+            Err(30)
+        );
+        assert_eq!(usage.take_token(1, start + Duration::from_secs(60)), Ok(()));
+    }
+
+    #[test]
+    fn authenticate_needs_a_known_key() {
+        let mut api_keys = ApiKeys::new();
+        assert_eq!(api_keys.authenticate(None), Ok(None));
+
+        api_keys.add(key("alice", None, None)).unwrap();
+        assert_eq!(api_keys.authenticate(None), Err(Rejection::MissingKey));
+        assert_eq!(
+            api_keys.authenticate(Some("bob-secret")),
+            Err(Rejection::InvalidKey)
+        );
+        assert_eq!(
+            api_keys.authenticate(Some("alice-secret")),
+            Ok(Some("alice".into()))
+        );
+    }
+
+    #[test]
+    fn requests_over_a_quota_are_refused_whole() {
+        let mut api_keys = ApiKeys::new();
+        api_keys.add(key("alice", Some(10), None)).unwrap();
+        api_keys.add(key("bob", None, Some(5))).unwrap();
+
+        assert_eq!(api_keys.charge("alice", 6), Ok(()));
+        assert_eq!(
+            api_keys.charge("alice", 5),
+            Err(Rejection::QuotaExceeded {
+                period: "daily",
+                quota: 10,
+                used: 6,
+                requested: 5,
+            })
+        );
+        assert_eq!(api_keys.charge("alice", 4), Ok(()));
+
+        assert_eq!(
+            api_keys.charge("bob", 6),
+            Err(Rejection::QuotaExceeded {
+                period: "monthly",
+                quota: 5,
+                used: 0,
+                requested: 6,
+            })
+        );
+
+        let usage = api_keys.usage();
+        assert_eq!(usage[0].usage.today, 10);
+        assert_eq!(usage[1].usage.this_month, 0);
+    }
+
+    #[test]
+    fn rejections_say_when_to_retry() {
+        assert_eq!(retry_after(Rejection::MissingKey), None);
+        assert_eq!(retry_after(Rejection::RateLimited(7)), Some("7".into()));
+
+        let retry = retry_after(Rejection::QuotaExceeded {
+            period: "daily",
+            quota: 10,
+            used: 10,
+            requested: 1,
+        })
+        .unwrap()
+        .parse::<i64>()
+        .unwrap();
+        assert!(retry > 0 && retry <= 86_400);
+    }
+}
@@ -0,0 +1,314 @@
+// Verify a list of addresses from the command line.
+//
+// Reads addresses from a CSV or TXT file, or from stdin, checks them with the
+// same batch logic as the server and writes the results as CSV, JSON or
+// NDJSON.
+//
+// Exit codes:
+// - 0: every address was checked and none is invalid
+// - 1: the input or output could not be read or written
+// - 3: at least one address is invalid
+// - 4: at least one address could not be verified (unknown or banned)
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+
+use extant::config::{self, Config};
+use extant::export::{result_row, Table, RESULT_COLUMNS};
+use extant::mail::{
+    check_batch_with_progress, BatchOptions, EmailCheckInput, EmailCheckResponse, MyReachable,
+};
+use extant::normalize::NormalizeOptions;
+use extant::syntax::Strictness;
+use indicatif::{ProgressBar, ProgressStyle};
+use structopt::StructOpt;
+
+const EXIT_ERROR: i32 = 1;
+const EXIT_INVALID: i32 = 3;
+const EXIT_UNVERIFIED: i32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InputFormat {
+    Csv,
+    Txt,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "txt" => Ok(InputFormat::Txt),
+            _ => Err(format!("Unknown input format \"{}\"", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Csv,
+    Json,
+    Ndjson,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            _ => Err(format!("Unknown output format \"{}\"", s)),
+        }
+    }
+}
+
+fn parse_strictness(s: &str) -> Result<Strictness, String> {
+    match s {
+        "rfc" => Ok(Strictness::Rfc),
+        "provider" => Ok(Strictness::Provider),
+        _ => Err(format!("Unknown syntax strictness \"{}\"", s)),
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "extant-cli", about = "Check whether email addresses exist.")]
+struct Opt {
+    /// File to read addresses from, stdin if omitted or "-".
+    #[structopt(parse(from_os_str))]
+    input: Option<PathBuf>,
+
+    /// Input format. Defaults to csv for `.csv` files, txt (one address per
+    /// line) otherwise.
+    #[structopt(long, possible_values = &["csv", "txt"])]
+    input_format: Option<InputFormat>,
+
+    /// CSV column holding the addresses. Defaults to the first column whose
+    /// header contains "email", or the first column.
+    #[structopt(long)]
+    column: Option<String>,
+
+    /// File to write results to, stdout if omitted.
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Output format.
+    #[structopt(
+        short,
+        long,
+        default_value = "csv",
+        possible_values = &["csv", "json", "ndjson"]
+    )]
+    format: OutputFormat,
+
+    /// Verdict policy to apply, see `VERDICT_POLICIES_FILE`.
+    #[structopt(long, default_value = "default")]
+    profile: String,
+
+    /// Syntax strictness, rfc or provider.
+    #[structopt(long, default_value = "rfc", parse(try_from_str = parse_strictness))]
+    syntax: Strictness,
+
+    /// Apply provider-aware dot and plus-tag rules when normalizing.
+    #[structopt(long)]
+    provider_rules: bool,
+
+    /// Config file, see `Config::load`.
+    #[structopt(long)]
+    config: Option<String>,
+
+    /// Attempts per address.
+    #[structopt(long)]
+    retries: Option<usize>,
+
+    /// How many addresses are checked at the same time.
+    #[structopt(long)]
+    concurrency: Option<usize>,
+
+    /// SMTP timeout in seconds.
+    #[structopt(long)]
+    smtp_timeout: Option<u64>,
+
+    /// Use fake DNS and SMTP results, see `extant::mock`.
+    #[structopt(long)]
+    mock: bool,
+
+    /// Don't show the progress bar.
+    #[structopt(long)]
+    no_progress: bool,
+}
+
+/// Read addresses, one per line.
+fn read_txt(reader: impl Read) -> io::Result<Vec<String>> {
+    let mut emails = vec![];
+    for line in BufReader::new(reader).lines() {
+        let line = line?;
+        let email = line.trim();
+        if !email.is_empty() {
+            emails.push(email.to_string());
+        }
+    }
+
+    Ok(emails)
+}
+
+/// Read addresses from one column of a CSV file with a header row.
+fn read_csv(reader: impl Read, column: Option<&str>) -> io::Result<Vec<String>> {
+    let table = Table::from_csv(reader)?;
+    let index = table.email_column(column).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("No column \"{}\" in the CSV header", column.unwrap_or("")),
+        )
+    })?;
+
+    Ok(table
+        .column(index)
+        .into_iter()
+        .filter(|email| !email.is_empty())
+        .collect())
+}
+
+fn read_input(opt: &Opt) -> io::Result<Vec<String>> {
+    let path = opt.input.as_ref().filter(|path| path.to_str() != Some("-"));
+    let format = opt
+        .input_format
+        .unwrap_or_else(|| match path.and_then(|path| path.extension()) {
+            Some(extension) if extension == "csv" => InputFormat::Csv,
+            _ => InputFormat::Txt,
+        });
+    let reader: Box<dyn Read> = match path {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin()),
+    };
+
+    match format {
+        InputFormat::Csv => read_csv(reader, opt.column.as_deref()),
+        InputFormat::Txt => read_txt(reader),
+    }
+}
+
+fn write_output(opt: &Opt, items: &[EmailCheckResponse]) -> io::Result<()> {
+    let mut writer: Box<dyn Write> = match &opt.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+
+    match opt.format {
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            let header = iter::once("email").chain(RESULT_COLUMNS.iter().copied());
+            writer.write_record(header)?;
+            for item in items {
+                let row = iter::once(item.email.clone()).chain(result_row(item));
+                writer.write_record(row)?;
+            }
+            writer.flush()
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, items)?;
+            writeln!(writer)
+        }
+        OutputFormat::Ndjson => {
+            for item in items {
+                serde_json::to_writer(&mut writer, item)?;
+                writeln!(writer)?;
+            }
+            writer.flush()
+        }
+    }
+}
+
+async fn run(opt: Opt) -> io::Result<i32> {
+    let mut config = Config::load(opt.config.as_deref())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    if let Some(retries) = opt.retries {
+        config.check.retries = retries;
+    }
+    if let Some(concurrency) = opt.concurrency {
+        config.check.concurrency = concurrency;
+    }
+    if let Some(smtp_timeout) = opt.smtp_timeout {
+        config.smtp.timeout = smtp_timeout;
+    }
+    if opt.mock {
+        config.mock.enabled = true;
+    }
+
+    let policies = config.files.load_data()?;
+    config::init(config).map_err(|err| io::Error::other(err.to_string()))?;
+
+    let policy = policies.get(&opt.profile).cloned().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown profile \"{}\"", opt.profile),
+        )
+    })?;
+
+    let emails = read_input(&opt)?;
+
+    let input = EmailCheckInput {
+        policy,
+        syntax_strictness: opt.syntax,
+        ..EmailCheckInput::from_config(config::get())
+    };
+    let options = NormalizeOptions {
+        provider_rules: opt.provider_rules,
+    };
+    let batch = BatchOptions::default();
+
+    let progress = if opt.no_progress {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(0)
+    };
+    progress.set_style(
+        ProgressStyle::default_bar().template("{bar:40} {pos}/{len} addresses, ETA {eta}"),
+    );
+
+    let items = check_batch_with_progress(&emails, &input, &options, &batch, |checked, total| {
+        progress.set_length(total as u64);
+        progress.set_position(checked as u64);
+    })
+    .await;
+    progress.finish_and_clear();
+
+    write_output(&opt, &items)?;
+
+    let unverified = items.iter().any(|item| {
+        item.is_reachable == MyReachable::Unknown || item.is_reachable == MyReachable::Banned
+    });
+    let invalid = items
+        .iter()
+        .any(|item| item.is_reachable == MyReachable::Invalid);
+
+    Ok(if unverified {
+        EXIT_UNVERIFIED
+    } else if invalid {
+        EXIT_INVALID
+    } else {
+        0
+    })
+}
+
+#[tokio::main]
+async fn main() {
+    config::require_init();
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("warn"));
+
+    let code = match run(Opt::from_args()).await {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("extant-cli: {}", err);
+            EXIT_ERROR
+        }
+    };
+
+    process::exit(code);
+}
@@ -0,0 +1,1089 @@
+// Typed configuration.
+//
+// Settings come from a TOML file (`CONFIG_FILE`, or `extant.toml` in the
+// working directory if it exists), then env vars override single values, e.g.
+// `PORT` or `SMTP_TIMEOUT`. Everything has a default, so neither is required.
+// `Config::load` validates the result, so a bad value fails at startup rather
+// than on the first check.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+use crate::auth::ApiKey;
+use crate::datasets;
+use crate::lists;
+use crate::policy::Policies;
+
+const DEFAULT_CONFIG_FILE: &str = "extant.toml";
+
+/// Seconds on top of `server.drain_timeout` for closing the SMTP sessions
+/// cut short and answering their requests, before connections are dropped.
+pub const SHUTDOWN_GRACE_SECS: u64 = 5;
+
+const REDACTED: &str = "<redacted>";
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Set by `require_init`, after which reading the config before `init` is a
+/// bug rather than a way to get the defaults.
+static INIT_REQUIRED: AtomicBool = AtomicBool::new(false);
+
+/// Set the process-wide config. Fails if it was already set, or already read
+/// and so pinned to the defaults.
+pub fn init(config: Config) -> Result<(), ConfigError> {
+    CONFIG.set(config).map_err(|_| ConfigError::AlreadySet)
+}
+
+/// Make `get` before `init` fail debug assertions. Binaries call this first
+/// thing, so no early read silently pins the defaults.
+pub fn require_init() {
+    INIT_REQUIRED.store(true, Ordering::SeqCst);
+}
+
+/// The process-wide config, the defaults if `init` wasn't called.
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        debug_assert!(
+            !INIT_REQUIRED.load(Ordering::SeqCst),
+            "config::get called before config::init"
+        );
+        Config::default()
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Env: `HOST`.
+    pub host: String,
+    /// Env: `PORT`.
+    pub port: u16,
+    /// Port serving `/metrics`, on `host` too. Keep it off the public
+    /// services, as the metrics hold API key names. 0 turns metrics off.
+    ///
+    /// Env: `METRICS_PORT`.
+    pub metrics_port: u16,
+    /// Bearer token for the admin API, which is disabled when unset.
+    ///
+    /// Env: `ADMIN_TOKEN`.
+    pub admin_token: Option<String>,
+    /// Seconds running checks get to finish on shutdown, before their SMTP
+    /// sessions are closed and what's left is saved to `unfinished_file`.
+    /// With `SHUTDOWN_GRACE_SECS` on top, it must stay below `kill_timeout`.
+    ///
+    /// Env: `DRAIN_TIMEOUT`.
+    pub drain_timeout: u64,
+    /// Seconds the platform waits after the shutdown signal before killing
+    /// the process, `kill_timeout` in `fly.toml`.
+    ///
+    /// Env: `KILL_TIMEOUT`.
+    pub kill_timeout: u64,
+    /// File the addresses of batches cut short by a shutdown are appended
+    /// to, one JSON object per batch and line.
+    ///
+    /// Env: `UNFINISHED_FILE`.
+    pub unfinished_file: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "0.0.0.0".into(),
+            port: 8080,
+            metrics_port: 9091,
+            admin_token: None,
+            drain_timeout: 20,
+            kill_timeout: 30,
+            unfinished_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    /// Email to use in the `MAIL FROM:` SMTP command.
+    ///
+    /// Env: `FROM_EMAIL`.
+    pub from_email: String,
+    /// Name to use in the `EHLO:` SMTP command. Defaults to the hostname.
+    ///
+    /// Env: `HELLO_NAME`.
+    pub hello_name: String,
+    /// Port to connect to on the MX host. Only worth changing to point the
+    /// checks at a local test server.
+    ///
+    /// Env: `SMTP_PORT`.
+    pub port: u16,
+    /// Timeout in seconds for the whole SMTP verification of one address.
+    ///
+    /// Env: `SMTP_TIMEOUT`.
+    pub timeout: u64,
+    /// Timeout in seconds for each read and write on the SMTP connection.
+    ///
+    /// Env: `SMTP_CONNECT_TIMEOUT`.
+    pub connect_timeout: u64,
+    /// Use Yahoo's signup API instead of their SMTP servers.
+    ///
+    /// Env: `YAHOO_USE_API`.
+    pub yahoo_use_api: bool,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        let hostname = match gethostname::gethostname().into_string() {
+            Ok(hostname) => hostname,
+            _ => String::from("localhost"),
+        };
+
+        SmtpConfig {
+            from_email: "user@example.com".into(),
+            hello_name: hostname,
+            port: 25,
+            timeout: 10,
+            connect_timeout: 30,
+            yahoo_use_api: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    /// Name servers to query for MX records. Google's public resolvers when
+    /// empty.
+    ///
+    /// Env: `DNS_NAMESERVERS`, comma-separated.
+    pub nameservers: Vec<IpAddr>,
+    /// Timeout in seconds for each DNS query.
+    ///
+    /// Env: `DNS_TIMEOUT`.
+    pub timeout: u64,
+    /// How many times a DNS query is attempted.
+    ///
+    /// Env: `DNS_ATTEMPTS`.
+    pub attempts: usize,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            nameservers: vec![],
+            timeout: 5,
+            attempts: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// How many check results are cached.
+    ///
+    /// Env: `CACHE_SIZE`.
+    pub size: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig { size: 100 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckConfig {
+    /// Attempts per address, see `retry`.
+    ///
+    /// Env: `RETRIES`.
+    pub retries: usize,
+    /// How many addresses of a batch are checked at the same time.
+    ///
+    /// Env: `CONCURRENCY`.
+    pub concurrency: usize,
+    /// Most addresses in one request to `/api/email_check` or rows in one
+    /// CSV upload. JSON bodies are capped to fit this many addresses.
+    ///
+    /// Env: `MAX_BATCH_SIZE`.
+    pub max_batch_size: usize,
+}
+
+impl Default for CheckConfig {
+    fn default() -> Self {
+        CheckConfig {
+            retries: 2,
+            concurrency: 20,
+            max_batch_size: 10_000,
+        }
+    }
+}
+
+/// Caps on what clients can override per request, see `CheckOptions`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverridesConfig {
+    /// Largest SMTP timeout in seconds a client can ask for.
+    ///
+    /// Env: `MAX_SMTP_TIMEOUT`.
+    pub max_smtp_timeout: u64,
+    /// Most attempts per address a client can ask for.
+    ///
+    /// Env: `MAX_RETRIES`.
+    pub max_retries: usize,
+    /// Let clients route checks through their own SOCKS5 proxy.
+    ///
+    /// Env: `ALLOW_PROXY`.
+    pub allow_proxy: bool,
+    /// Let clients pick the `MAIL FROM:` address.
+    ///
+    /// Env: `ALLOW_FROM_EMAIL`.
+    pub allow_from_email: bool,
+}
+
+impl Default for OverridesConfig {
+    fn default() -> Self {
+        OverridesConfig {
+            max_smtp_timeout: 30,
+            max_retries: 3,
+            allow_proxy: false,
+            allow_from_email: false,
+        }
+    }
+}
+
+/// Fake DNS and SMTP results, see `mock`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MockConfig {
+    /// Mock every check.
+    ///
+    /// Env: `MOCK_MODE`.
+    pub enabled: bool,
+    /// Let any client ask for mock results with the `X-Extant-Mock` header.
+    /// Off by default, so callers can't skip the real checks.
+    ///
+    /// Env: `MOCK_ALLOW_HEADER`.
+    pub allow_header: bool,
+    /// Added to each fake SMTP check, in milliseconds.
+    ///
+    /// Env: `MOCK_LATENCY_MS`.
+    pub latency_ms: u64,
+    /// Share of fake SMTP checks that fail, from 0 to 1.
+    ///
+    /// Env: `MOCK_FAILURE_RATE`.
+    pub failure_rate: f64,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            enabled: false,
+            allow_header: false,
+            latency_ms: 0,
+            failure_rate: 0.0,
+        }
+    }
+}
+
+/// Readiness probes and self-diagnostics, see `health`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Domain whose MX lookup tells whether DNS works.
+    ///
+    /// Env: `HEALTH_DNS_DOMAIN`.
+    pub dns_domain: String,
+    /// Host connected to on `smtp.port` to tell whether outbound SMTP works.
+    ///
+    /// Env: `HEALTH_CANARY_HOST`.
+    pub canary_host: String,
+    /// Timeout in seconds of each probe.
+    ///
+    /// Env: `HEALTH_TIMEOUT`.
+    pub timeout: u64,
+    /// How long in seconds a readiness result is reused, so frequent probes
+    /// don't hammer the canary host.
+    ///
+    /// Env: `HEALTH_CACHE_SECS`.
+    pub cache_secs: u64,
+    /// Answers with our public IP as plain text.
+    ///
+    /// Env: `PUBLIC_IP_URL`.
+    pub public_ip_url: String,
+    /// DNS blocklist zones our IP is looked up in.
+    ///
+    /// Env: `DNSBLS`, comma-separated.
+    pub dnsbls: Vec<String>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            dns_domain: "gmail.com".into(),
+            canary_host: "gmail-smtp-in.l.google.com".into(),
+            timeout: 5,
+            cache_secs: 30,
+            public_ip_url: "https://api.ipify.org".into(),
+            dnsbls: vec![
+                "zen.spamhaus.org".into(),
+                "bl.spamcop.net".into(),
+                "b.barracudacentral.org".into(),
+                "dnsbl.sorbs.net".into(),
+                "psbl.surriel.com".into(),
+            ],
+        }
+    }
+}
+
+/// API keys for the check endpoints, see `auth`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Addresses per UTC day, for keys that don't set their own. Unlimited
+    /// when unset.
+    ///
+    /// Env: `API_DAILY_QUOTA`.
+    pub daily_quota: Option<u64>,
+    /// Addresses per UTC month, for keys that don't set their own. Unlimited
+    /// when unset.
+    ///
+    /// Env: `API_MONTHLY_QUOTA`.
+    pub monthly_quota: Option<u64>,
+    /// Requests per minute, for keys that don't set their own. Unlimited
+    /// when unset.
+    ///
+    /// Env: `API_RATE_LIMIT`.
+    pub rate_limit: Option<u32>,
+    /// Keys allowed to use the check endpoints, added to the ones in
+    /// `files.api_keys`. The endpoints are open when there are none. Last,
+    /// as TOML needs tables after values.
+    ///
+    /// Env: `API_KEYS`, comma-separated `name:key` pairs.
+    pub keys: Vec<ApiKey>,
+}
+
+/// How log lines are written.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the enclosing spans.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How email addresses appear in log lines, see `pii`.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PiiMode {
+    /// Addresses as they are.
+    #[default]
+    Plain,
+    /// The local part hidden, the domain kept: `***@example.com`.
+    Redact,
+    /// A keyed hash of the whole address: `email:1f2e3d4c5b6a7988`.
+    Hash,
+}
+
+impl FromStr for PiiMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plain" => Ok(PiiMode::Plain),
+            "redact" => Ok(PiiMode::Redact),
+            "hash" => Ok(PiiMode::Hash),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Env: `LOG_FORMAT`, `text` or `json`.
+    pub format: LogFormat,
+    /// Which log lines to keep, in `tracing-subscriber`'s `EnvFilter`
+    /// syntax, e.g. `info` or `info,extant::smtp=debug`.
+    ///
+    /// Env: `RUST_LOG`.
+    pub filter: String,
+    /// How email addresses appear in log lines.
+    ///
+    /// Env: `LOG_PII`, `plain`, `redact` or `hash`.
+    pub pii: PiiMode,
+    /// Key for the `hash` mode. Set the same key on every instance to get
+    /// the same hashes everywhere; a random one is used when unset.
+    ///
+    /// Env: `LOG_PII_KEY`.
+    pub pii_key: Option<String>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::default(),
+            filter: "info".into(),
+            pii: PiiMode::default(),
+            pii_key: None,
+        }
+    }
+}
+
+/// Data files, all optional.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilesConfig {
+    /// Env: `VERDICT_POLICIES_FILE`.
+    pub policies: Option<String>,
+    /// Also where the rules added through the admin API are saved.
+    ///
+    /// Env: `ACCESS_LISTS_FILE`.
+    pub access_lists: Option<String>,
+    /// Env: `DISPOSABLE_DOMAINS_FILE`.
+    pub disposable_domains: Option<String>,
+    /// Env: `ROLE_ACCOUNTS_FILE`.
+    pub role_accounts: Option<String>,
+    /// Env: `FREE_PROVIDERS_FILE`.
+    pub free_providers: Option<String>,
+    /// JSON array of API keys, see `auth::ApiKey`.
+    ///
+    /// Env: `API_KEYS_FILE`.
+    pub api_keys: Option<String>,
+}
+
+impl FilesConfig {
+    /// Load the datasets and access lists into their globals, and return the
+    /// verdict policies.
+    pub fn load_data(&self) -> io::Result<Policies> {
+        datasets::global()
+            .write()
+            .expect("Datasets lock is never poisoned. qed.")
+            .load_files(self)?;
+
+        if let Some(path) = &self.access_lists {
+            lists::global()
+                .write()
+                .expect("Lists lock is never poisoned. qed.")
+                .load(path)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        }
+
+        match &self.policies {
+            Some(path) => Policies::load(path)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+            None => Ok(Policies::default()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub smtp: SmtpConfig,
+    pub dns: DnsConfig,
+    pub cache: CacheConfig,
+    pub check: CheckConfig,
+    pub overrides: OverridesConfig,
+    pub mock: MockConfig,
+    pub auth: AuthConfig,
+    pub health: HealthConfig,
+    pub log: LogConfig,
+    pub files: FilesConfig,
+}
+
+/// Possible errors when loading the config.
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(io::Error),
+    TomlError(toml::de::Error),
+    /// An env var override couldn't be parsed.
+    EnvError {
+        name: String,
+        value: String,
+    },
+    /// A setting has an unusable value.
+    Invalid(String),
+    /// `init` was called after the config was set or read.
+    AlreadySet,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(error: io::Error) -> Self {
+        ConfigError::IoError(error)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(error: toml::de::Error) -> Self {
+        ConfigError::TomlError(error)
+    }
+}
+
+/// Override `value` with the env var `name`, if set.
+fn env_override<T: FromStr>(name: &str, value: &mut T) -> Result<(), ConfigError> {
+    if let Ok(raw) = env::var(name) {
+        *value = raw.trim().parse().map_err(|_| ConfigError::EnvError {
+            name: name.into(),
+            value: raw.clone(),
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Set the optional `value` from the env var `name`, if set.
+fn env_override_optional(name: &str, value: &mut Option<String>) {
+    if let Ok(raw) = env::var(name) {
+        *value = Some(raw);
+    }
+}
+
+/// Set the optional `value` from the env var `name` if set, parsed.
+fn env_override_some<T: FromStr>(name: &str, value: &mut Option<T>) -> Result<(), ConfigError> {
+    if let Ok(raw) = env::var(name) {
+        *value = Some(raw.trim().parse().map_err(|_| ConfigError::EnvError {
+            name: name.into(),
+            value: raw.clone(),
+        })?);
+    }
+
+    Ok(())
+}
+
+impl Config {
+    /// Load `path`, or `CONFIG_FILE`, or `extant.toml` if it exists, apply the
+    /// env var overrides and validate.
+    pub fn load(path: Option<&str>) -> Result<Self, ConfigError> {
+        let path = path
+            .map(String::from)
+            .or_else(|| env::var("CONFIG_FILE").ok())
+            .or_else(|| {
+                Some(DEFAULT_CONFIG_FILE.to_string()).filter(|path| Path::new(path).exists())
+            });
+
+        let mut config = match path {
+            Some(path) => toml::from_str(&fs::read_to_string(path)?)?,
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        env_override("HOST", &mut self.server.host)?;
+        env_override("PORT", &mut self.server.port)?;
+        env_override("METRICS_PORT", &mut self.server.metrics_port)?;
+        env_override_optional("ADMIN_TOKEN", &mut self.server.admin_token);
+        env_override("DRAIN_TIMEOUT", &mut self.server.drain_timeout)?;
+        env_override("KILL_TIMEOUT", &mut self.server.kill_timeout)?;
+        env_override_optional("UNFINISHED_FILE", &mut self.server.unfinished_file);
+
+        env_override("FROM_EMAIL", &mut self.smtp.from_email)?;
+        env_override("HELLO_NAME", &mut self.smtp.hello_name)?;
+        env_override("SMTP_TIMEOUT", &mut self.smtp.timeout)?;
+        env_override("SMTP_PORT", &mut self.smtp.port)?;
+        env_override("SMTP_CONNECT_TIMEOUT", &mut self.smtp.connect_timeout)?;
+        env_override("YAHOO_USE_API", &mut self.smtp.yahoo_use_api)?;
+
+        if let Ok(raw) = env::var("DNS_NAMESERVERS") {
+            self.dns.nameservers = raw
+                .split(',')
+                .map(str::trim)
+                .filter(|ip| !ip.is_empty())
+                .map(|ip| ip.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| ConfigError::EnvError {
+                    name: "DNS_NAMESERVERS".into(),
+                    value: raw.clone(),
+                })?;
+        }
+        env_override("DNS_TIMEOUT", &mut self.dns.timeout)?;
+        env_override("DNS_ATTEMPTS", &mut self.dns.attempts)?;
+
+        env_override("CACHE_SIZE", &mut self.cache.size)?;
+
+        env_override("RETRIES", &mut self.check.retries)?;
+        env_override("CONCURRENCY", &mut self.check.concurrency)?;
+        env_override("MAX_BATCH_SIZE", &mut self.check.max_batch_size)?;
+
+        env_override("MAX_SMTP_TIMEOUT", &mut self.overrides.max_smtp_timeout)?;
+        env_override("MAX_RETRIES", &mut self.overrides.max_retries)?;
+        env_override("ALLOW_PROXY", &mut self.overrides.allow_proxy)?;
+        env_override("ALLOW_FROM_EMAIL", &mut self.overrides.allow_from_email)?;
+
+        env_override("MOCK_MODE", &mut self.mock.enabled)?;
+        env_override("MOCK_ALLOW_HEADER", &mut self.mock.allow_header)?;
+        env_override("MOCK_LATENCY_MS", &mut self.mock.latency_ms)?;
+        env_override("MOCK_FAILURE_RATE", &mut self.mock.failure_rate)?;
+
+        if let Ok(raw) = env::var("API_KEYS") {
+            for pair in raw
+                .split(',')
+                .map(str::trim)
+                .filter(|pair| !pair.is_empty())
+            {
+                let (name, key) = pair.split_once(':').ok_or_else(|| ConfigError::EnvError {
+                    name: "API_KEYS".into(),
+                    value: REDACTED.into(),
+                })?;
+                self.auth.keys.push(ApiKey {
+                    name: name.trim().into(),
+                    key: key.trim().into(),
+                    daily_quota: None,
+                    monthly_quota: None,
+                    rate_limit: None,
+                });
+            }
+        }
+        env_override_some("API_DAILY_QUOTA", &mut self.auth.daily_quota)?;
+        env_override_some("API_MONTHLY_QUOTA", &mut self.auth.monthly_quota)?;
+        env_override_some("API_RATE_LIMIT", &mut self.auth.rate_limit)?;
+
+        env_override("HEALTH_DNS_DOMAIN", &mut self.health.dns_domain)?;
+        env_override("HEALTH_CANARY_HOST", &mut self.health.canary_host)?;
+        env_override("HEALTH_TIMEOUT", &mut self.health.timeout)?;
+        env_override("HEALTH_CACHE_SECS", &mut self.health.cache_secs)?;
+        env_override("PUBLIC_IP_URL", &mut self.health.public_ip_url)?;
+        if let Ok(raw) = env::var("DNSBLS") {
+            self.health.dnsbls = raw
+                .split(',')
+                .map(str::trim)
+                .filter(|zone| !zone.is_empty())
+                .map(String::from)
+                .collect();
+        }
+
+        env_override("LOG_FORMAT", &mut self.log.format)?;
+        env_override("RUST_LOG", &mut self.log.filter)?;
+        env_override("LOG_PII", &mut self.log.pii)?;
+        env_override_optional("LOG_PII_KEY", &mut self.log.pii_key);
+
+        env_override_optional("VERDICT_POLICIES_FILE", &mut self.files.policies);
+        env_override_optional("ACCESS_LISTS_FILE", &mut self.files.access_lists);
+        env_override_optional(
+            "DISPOSABLE_DOMAINS_FILE",
+            &mut self.files.disposable_domains,
+        );
+        env_override_optional("ROLE_ACCOUNTS_FILE", &mut self.files.role_accounts);
+        env_override_optional("FREE_PROVIDERS_FILE", &mut self.files.free_providers);
+        env_override_optional("API_KEYS_FILE", &mut self.files.api_keys);
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let drain_below_kill = format!(
+            "server.drain_timeout must be at least {} seconds below server.kill_timeout",
+            SHUTDOWN_GRACE_SECS + 1
+        );
+        let checks = [
+            (self.server.port != 0, "server.port must not be 0"),
+            (
+                self.server.metrics_port != self.server.port,
+                "server.metrics_port must differ from server.port",
+            ),
+            (
+                self.server.drain_timeout + SHUTDOWN_GRACE_SECS < self.server.kill_timeout,
+                drain_below_kill.as_str(),
+            ),
+            (
+                self.smtp.from_email.contains('@'),
+                "smtp.from_email must be an email address",
+            ),
+            (
+                !self.smtp.hello_name.trim().is_empty(),
+                "smtp.hello_name must not be empty",
+            ),
+            (self.smtp.timeout > 0, "smtp.timeout must be positive"),
+            (
+                self.smtp.connect_timeout > 0,
+                "smtp.connect_timeout must be positive",
+            ),
+            (self.dns.timeout > 0, "dns.timeout must be positive"),
+            (self.dns.attempts > 0, "dns.attempts must be positive"),
+            (self.cache.size > 0, "cache.size must be positive"),
+            (self.check.retries > 0, "check.retries must be positive"),
+            (
+                self.check.concurrency > 0,
+                "check.concurrency must be positive",
+            ),
+            (
+                self.check.max_batch_size > 0,
+                "check.max_batch_size must be positive",
+            ),
+            (
+                self.overrides.max_smtp_timeout > 0,
+                "overrides.max_smtp_timeout must be positive",
+            ),
+            (
+                self.overrides.max_retries > 0,
+                "overrides.max_retries must be positive",
+            ),
+            (
+                (0.0..=1.0).contains(&self.mock.failure_rate),
+                "mock.failure_rate must be between 0 and 1",
+            ),
+            (self.health.timeout > 0, "health.timeout must be positive"),
+            (
+                self.auth.rate_limit != Some(0),
+                "auth.rate_limit must be positive",
+            ),
+            (
+                EnvFilter::try_new(&self.log.filter).is_ok(),
+                "log.filter is not a valid filter",
+            ),
+        ];
+
+        match checks.iter().find(|(valid, _)| !valid) {
+            Some((_, message)) => Err(ConfigError::Invalid(message.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// A copy safe to print, with secrets replaced.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        if config.server.admin_token.is_some() {
+            config.server.admin_token = Some(REDACTED.into());
+        }
+        for key in &mut config.auth.keys {
+            key.key = REDACTED.into();
+        }
+        if config.log.pii_key.is_some() {
+            config.log.pii_key = Some(REDACTED.into());
+        }
+
+        config
+    }
+
+    pub fn smtp_timeout(&self) -> Duration {
+        Duration::from_secs(self.smtp.timeout)
+    }
+
+    pub fn smtp_connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.smtp.connect_timeout)
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.server.drain_timeout)
+    }
+
+    pub fn health_timeout(&self) -> Duration {
+        Duration::from_secs(self.health.timeout)
+    }
+
+    pub fn dns_timeout(&self) -> Duration {
+        Duration::from_secs(self.dns.timeout)
+    }
+}
+
+/// The redacted config, as TOML.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match toml::to_string(&self.redacted()) {
+            Ok(toml) => write!(f, "{}", toml),
+            Err(_) => write!(f, "{:?}", self.redacted()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use once_cell::sync::Lazy;
+
+    use super::*;
+
+    /// The env is process-wide, so tests touching it take turns.
+    static ENV: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+    /// Run `f` with the env vars set, or removed for `None`, then restore
+    /// them.
+    fn with_env<T>(vars: &[(&str, Option<&str>)], f: impl FnOnce() -> T) -> T {
+        let _guard = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let saved: Vec<(&str, Option<String>)> = vars
+            .iter()
+            .map(|(name, _)| (*name, env::var(name).ok()))
+            .collect();
+        for (name, value) in vars {
+            match value {
+                Some(value) => env::set_var(name, value),
+                None => env::remove_var(name),
+            }
+        }
+
+        let result = f();
+
+        for (name, value) in saved {
+            match value {
+                Some(value) => env::set_var(name, value),
+                None => env::remove_var(name),
+            }
+        }
+
+        result
+    }
+
+    fn config_file(name: &str, content: &str) -> String {
+        let path = env::temp_dir().join(format!("extant-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+
+        path.to_str().unwrap().to_string()
+    }
+
+    fn invalid(config: &Config) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid(message)) => message,
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn mock_header_is_off_by_default() {
+        assert!(!Config::default().mock.allow_header);
+    }
+
+    #[test]
+    fn env_overrides_the_defaults() {
+        let path = config_file("empty", "");
+        let config = with_env(
+            &[
+                ("PORT", Some("9000")),
+                ("SMTP_TIMEOUT", Some(" 12 ")),
+                ("DNS_NAMESERVERS", Some("1.1.1.1, 8.8.8.8,")),
+                ("API_KEYS", Some("alice:k1, bob:k2")),
+                ("LOG_FORMAT", Some("JSON")),
+                ("ADMIN_TOKEN", Some("secret")),
+                ("DNSBLS", Some("zen.spamhaus.org,,")),
+                ("MOCK_ALLOW_HEADER", Some("true")),
+            ],
+            || Config::load(Some(&path)),
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.smtp.timeout, 12);
+        assert_eq!(
+            config.dns.nameservers,
+            vec![
+                "1.1.1.1".parse::<IpAddr>().unwrap(),
+                "8.8.8.8".parse().unwrap()
+            ]
+        );
+        let keys: Vec<(&str, &str)> = config
+            .auth
+            .keys
+            .iter()
+            .map(|key| (key.name.as_str(), key.key.as_str()))
+            .collect();
+        assert_eq!(keys, vec![("alice", "k1"), ("bob", "k2")]);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.server.admin_token.as_deref(), Some("secret"));
+        assert_eq!(config.health.dnsbls, vec!["zen.spamhaus.org"]);
+        assert!(config.mock.allow_header);
+    }
+
+    #[test]
+    fn env_wins_over_the_file() {
+        let path = config_file(
+            "precedence",
+            "[server]\nport = 8000\n\n[smtp]\ntimeout = 20\n",
+        );
+        let config = with_env(&[("PORT", Some("9000")), ("SMTP_TIMEOUT", None)], || {
+            Config::load(Some(&path))
+        })
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.smtp.timeout, 20);
+    }
+
+    #[test]
+    fn env_values_must_parse() {
+        let path = config_file("bad-env", "");
+        let port = with_env(&[("PORT", Some("eighty"))], || Config::load(Some(&path)));
+        let keys = with_env(&[("API_KEYS", Some("alice-without-key"))], || {
+            Config::load(Some(&path))
+        });
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            port,
+            Err(ConfigError::EnvError { name, value }) if name == "PORT" && value == "eighty"
+        ));
+        // The value of API_KEYS is a secret, so it's not echoed back.
+        assert!(matches!(
+            keys,
+            Err(ConfigError::EnvError { name, value }) if name == "API_KEYS" && value == REDACTED
+        ));
+    }
+
+    #[test]
+    fn file_must_exist_and_parse() {
+        assert!(matches!(
+            Config::load(Some("/nonexistent/extant.toml")),
+            Err(ConfigError::IoError(_))
+        ));
+
+        let path = config_file("unknown-field", "[server]\nprot = 8000\n");
+        let result = Config::load(Some(&path));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(ConfigError::TomlError(_))));
+    }
+
+    #[test]
+    fn file_values_are_validated() {
+        let path = config_file("invalid", "[cache]\nsize = 0\n");
+        let result = with_env(&[("CACHE_SIZE", None)], || Config::load(Some(&path)));
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(ConfigError::Invalid(message)) if message == "cache.size must be positive"
+        ));
+    }
+
+    /// Breaks one setting of a valid config.
+    type Breakage = fn(&mut Config);
+
+    #[test]
+    fn each_validation_error() {
+        let cases: Vec<(Breakage, &str)> = vec![
+            (|c| c.server.port = 0, "server.port must not be 0"),
+            (
+                |c| c.server.metrics_port = c.server.port,
+                "server.metrics_port must differ from server.port",
+            ),
+            (
+                |c| c.smtp.from_email = "user".into(),
+                "smtp.from_email must be an email address",
+            ),
+            (
+                |c| c.smtp.hello_name = " ".into(),
+                "smtp.hello_name must not be empty",
+            ),
+            (|c| c.smtp.timeout = 0, "smtp.timeout must be positive"),
+            (
+                |c| c.smtp.connect_timeout = 0,
+                "smtp.connect_timeout must be positive",
+            ),
+            (|c| c.dns.timeout = 0, "dns.timeout must be positive"),
+            (|c| c.dns.attempts = 0, "dns.attempts must be positive"),
+            (|c| c.cache.size = 0, "cache.size must be positive"),
+            (|c| c.check.retries = 0, "check.retries must be positive"),
+            (
+                |c| c.check.concurrency = 0,
+                "check.concurrency must be positive",
+            ),
+            (
+                |c| c.check.max_batch_size = 0,
+                "check.max_batch_size must be positive",
+            ),
+            (
+                |c| c.overrides.max_smtp_timeout = 0,
+                "overrides.max_smtp_timeout must be positive",
+            ),
+            (
+                |c| c.overrides.max_retries = 0,
+                "overrides.max_retries must be positive",
+            ),
+            (
+                |c| c.mock.failure_rate = 1.5,
+                "mock.failure_rate must be between 0 and 1",
+            ),
+            (|c| c.health.timeout = 0, "health.timeout must be positive"),
+            (
+                |c| c.auth.rate_limit = Some(0),
+                "auth.rate_limit must be positive",
+            ),
+            (
+                |c| c.log.filter = "extant=loud".into(),
+                "log.filter is not a valid filter",
+            ),
+        ];
+
+        for (break_it, message) in cases {
+            let mut config = Config::default();
+            break_it(&mut config);
+            assert_eq!(invalid(&config), message);
+        }
+    }
+
+    #[test]
+    fn drain_timeout_leaves_room_for_the_grace_period() {
+        let mut config = Config::default();
+        config.server.kill_timeout = 30;
+
+        config.server.drain_timeout = 30 - SHUTDOWN_GRACE_SECS - 1;
+        assert!(config.validate().is_ok());
+
+        config.server.drain_timeout = 30 - SHUTDOWN_GRACE_SECS;
+        assert_eq!(
+            invalid(&config),
+            format!(
+                "server.drain_timeout must be at least {} seconds below server.kill_timeout",
+                SHUTDOWN_GRACE_SECS + 1
+            )
+        );
+    }
+
+    #[test]
+    fn redacted_hides_secrets() {
+        let mut config = Config::default();
+        config.server.admin_token = Some("admin-secret".into());
+        config.log.pii_key = Some("pii-secret".into());
+        config.auth.keys.push(ApiKey {
+            name: "alice".into(),
+            key: "key-secret".into(),
+            daily_quota: None,
+            monthly_quota: None,
+            rate_limit: None,
+        });
+
+        let printed = config.to_string();
+        assert!(!printed.contains("secret"), "{}", printed);
+        assert!(printed.contains("alice"));
+    }
+}
@@ -0,0 +1,358 @@
+// Updatable disposable-domain, role-account and free-provider datasets.
+//
+// `check_misc` only knows the lists compiled into check-if-email-exists, so
+// new throwaway providers slip through until a dependency bump. The lists here
+// are merged on top of those built-in defaults, can be loaded from local files
+// (one entry per line, `#` starts a comment), reloaded at runtime and extended
+// through the admin API.
+//
+// The free-provider list has no equivalent in check-if-email-exists, so it
+// ships bundled with this crate and a file replaces it instead.
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::sync::RwLock;
+
+use check_if_email_exists::misc::MiscDetails;
+use check_if_email_exists::syntax::SyntaxDetails;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::config::FilesConfig;
+
+const FREE_PROVIDERS: &str = include_str!("../data/free_providers.txt");
+
+static DATASETS: Lazy<RwLock<Datasets>> = Lazy::new(|| RwLock::new(Datasets::default()));
+
+/// The process-wide datasets used by `check_single_email`.
+pub fn global() -> &'static RwLock<Datasets> {
+    &DATASETS
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Dataset {
+    Disposable,
+    RoleAccounts,
+    FreeProviders,
+}
+
+/// One list of entries, optionally backed by a file.
+#[derive(Debug, Default)]
+pub struct EntryList {
+    entries: HashSet<String>,
+    path: Option<String>,
+}
+
+fn parse_entries(content: &str) -> impl Iterator<Item = String> + '_ {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim().to_lowercase())
+        .filter(|line| !line.is_empty())
+}
+
+impl EntryList {
+    /// A list with `content` as its entries and no backing file.
+    pub fn bundled(content: &str) -> Self {
+        EntryList {
+            entries: parse_entries(content).collect(),
+            path: None,
+        }
+    }
+
+    /// Replace the entries with the content of `path`, and remember it for
+    /// `reload` and `add`.
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        self.entries = parse_entries(&fs::read_to_string(path)?).collect();
+        self.path = Some(path.to_string());
+
+        Ok(())
+    }
+
+    /// Re-read the backing file, if there is one.
+    pub fn reload(&mut self) -> io::Result<()> {
+        match self.path.clone() {
+            Some(path) => self.load(&path),
+            None => Ok(()),
+        }
+    }
+
+    /// Add entries, appending them to the backing file so they survive a
+    /// reload. Returns how many entries were new.
+    pub fn add(&mut self, entries: &[String]) -> io::Result<usize> {
+        let new: Vec<String> = entries
+            .iter()
+            .flat_map(|entry| parse_entries(entry))
+            .filter(|entry| !self.entries.contains(entry))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+            for entry in &new {
+                writeln!(file, "{}", entry)?;
+            }
+        }
+
+        self.entries.extend(new.iter().cloned());
+
+        Ok(new.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, entry: &str) -> bool {
+        self.entries.contains(&entry.to_lowercase())
+    }
+
+    /// Find the entry matching `domain` or one of its parent domains. An
+    /// entry `name.*` matches `name` under a TLD, or under one of the
+    /// `SECOND_LEVEL_SUFFIXES` like `co.uk`.
+    pub fn find_domain(&self, domain: &str) -> Option<String> {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        let labels: Vec<&str> = domain.split('.').collect();
+
+        (0..labels.len().saturating_sub(1)).find_map(|i| {
+            let exact = labels[i..].join(".");
+            let wildcard = format!("{}.*", labels[i]);
+
+            if self.entries.contains(&exact) {
+                Some(exact)
+            } else if is_public_suffix(&labels[i + 1..]) && self.entries.contains(&wildcard) {
+                Some(wildcard)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Public suffixes of two labels under which mailbox providers register
+/// their name, e.g. yahoo.co.uk. A name under anything else of two labels,
+/// like outlook.acme.com, is someone's subdomain.
+const SECOND_LEVEL_SUFFIXES: [&str; 24] = [
+    "co.uk", "org.uk", "me.uk", "com.au", "net.au", "co.nz", "co.za", "co.jp", "ne.jp", "or.jp",
+    "co.kr", "co.in", "co.id", "co.th", "co.il", "com.br", "com.mx", "com.ar", "com.tr", "com.cn",
+    "com.tw", "com.hk", "com.sg", "com.my",
+];
+
+fn is_public_suffix(labels: &[&str]) -> bool {
+    match labels.len() {
+        1 => true,
+        2 => SECOND_LEVEL_SUFFIXES.contains(&labels.join(".").as_str()),
+        _ => false,
+    }
+}
+
+/// What the datasets say about one address, merged with `check_misc`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DatasetMatch {
+    pub is_disposable: bool,
+    /// The list entry that flagged the address as disposable.
+    pub disposable_match: Option<String>,
+    pub is_role_account: bool,
+    /// The list entry that flagged the address as a role account.
+    pub role_match: Option<String>,
+    /// Is the domain on the free-provider list?
+    pub is_free_provider: bool,
+}
+
+impl DatasetMatch {
+    pub fn misc(&self) -> MiscDetails {
+        MiscDetails {
+            is_disposable: self.is_disposable,
+            is_role_account: self.is_role_account,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Datasets {
+    pub disposable: EntryList,
+    pub role_accounts: EntryList,
+    pub free_providers: EntryList,
+}
+
+impl Default for Datasets {
+    fn default() -> Self {
+        Datasets {
+            disposable: EntryList::default(),
+            role_accounts: EntryList::default(),
+            free_providers: EntryList::bundled(FREE_PROVIDERS),
+        }
+    }
+}
+
+impl Datasets {
+    /// Load the dataset files named in the config, when set.
+    pub fn load_files(&mut self, files: &FilesConfig) -> io::Result<()> {
+        if let Some(path) = &files.disposable_domains {
+            self.disposable.load(path)?;
+        }
+        if let Some(path) = &files.role_accounts {
+            self.role_accounts.load(path)?;
+        }
+        if let Some(path) = &files.free_providers {
+            self.free_providers.load(path)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn reload(&mut self) -> io::Result<()> {
+        self.disposable.reload()?;
+        self.role_accounts.reload()?;
+        self.free_providers.reload()
+    }
+
+    /// Find the role-account entry matching `username`, ignoring a `+tag`
+    /// subaddress, so `sales+eu` matches `sales`.
+    pub fn find_role(&self, username: &str) -> Option<String> {
+        let username = username.to_lowercase();
+        let mailbox = username.split('+').next().unwrap_or("");
+
+        Some(mailbox.to_string()).filter(|mailbox| self.role_accounts.contains(mailbox))
+    }
+
+    pub fn list_mut(&mut self, dataset: Dataset) -> &mut EntryList {
+        match dataset {
+            Dataset::Disposable => &mut self.disposable,
+            Dataset::RoleAccounts => &mut self.role_accounts,
+            Dataset::FreeProviders => &mut self.free_providers,
+        }
+    }
+
+    /// Merge our lists with the built-in `check_misc` result. For built-in
+    /// matches we don't know the exact entry, so we report the domain or the
+    /// username that matched.
+    pub fn check(&self, syntax: &SyntaxDetails, misc: &MiscDetails) -> DatasetMatch {
+        let disposable_match = self
+            .disposable
+            .find_domain(&syntax.domain)
+            .or_else(|| Some(syntax.domain.to_lowercase()).filter(|_| misc.is_disposable));
+
+        let role_match = self
+            .find_role(&syntax.username)
+            .or_else(|| Some(syntax.username.to_lowercase()).filter(|_| misc.is_role_account));
+
+        DatasetMatch {
+            is_disposable: disposable_match.is_some(),
+            disposable_match,
+            is_role_account: role_match.is_some(),
+            role_match,
+            is_free_provider: self.free_providers.find_domain(&syntax.domain).is_some(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn free_providers() -> EntryList {
+        EntryList::bundled("gmail.com\noutlook.*\nlive.*\naol.*\nyahoo.*")
+    }
+
+    #[test]
+    fn wildcard_matches_name_under_a_public_suffix() {
+        let list = free_providers();
+        assert_eq!(
+            list.find_domain("outlook.com").as_deref(),
+            Some("outlook.*")
+        );
+        assert_eq!(
+            list.find_domain("Outlook.FR.").as_deref(),
+            Some("outlook.*")
+        );
+        assert_eq!(list.find_domain("yahoo.co.uk").as_deref(), Some("yahoo.*"));
+        assert_eq!(list.find_domain("yahoo.com.br").as_deref(), Some("yahoo.*"));
+        assert_eq!(
+            list.find_domain("mail.yahoo.co.jp").as_deref(),
+            Some("yahoo.*")
+        );
+    }
+
+    #[test]
+    fn wildcard_ignores_subdomains_of_other_domains() {
+        let list = free_providers();
+        assert_eq!(list.find_domain("outlook.acme.com"), None);
+        assert_eq!(list.find_domain("live.university.edu"), None);
+        assert_eq!(list.find_domain("aol.mycorp.com"), None);
+        assert_eq!(list.find_domain("yahoo.example.co.uk"), None);
+    }
+
+    #[test]
+    fn exact_entries_match_parent_domains() {
+        let list = free_providers();
+        assert_eq!(list.find_domain("gmail.com").as_deref(), Some("gmail.com"));
+        assert_eq!(
+            list.find_domain("eu.gmail.com").as_deref(),
+            Some("gmail.com")
+        );
+        assert_eq!(list.find_domain("gmail.de"), None);
+        assert_eq!(list.find_domain("com"), None);
+    }
+
+    fn syntax(username: &str) -> SyntaxDetails {
+        SyntaxDetails {
+            address: None,
+            domain: "example.com".into(),
+            is_valid_syntax: true,
+            username: username.into(),
+        }
+    }
+
+    fn role_match(datasets: &Datasets, username: &str, built_in: bool) -> Option<String> {
+        let misc = MiscDetails {
+            is_disposable: false,
+            is_role_account: built_in,
+        };
+
+        datasets.check(&syntax(username), &misc).role_match
+    }
+
+    #[test]
+    fn role_match_reports_the_matched_entry() {
+        let datasets = Datasets {
+            role_accounts: EntryList::bundled("sales\nsupport"),
+            ..Datasets::default()
+        };
+
+        assert_eq!(
+            role_match(&datasets, "Sales", false).as_deref(),
+            Some("sales")
+        );
+        assert_eq!(
+            role_match(&datasets, "sales+eu", false).as_deref(),
+            Some("sales")
+        );
+        assert_eq!(
+            role_match(&datasets, "support+eu", true).as_deref(),
+            Some("support")
+        );
+        assert_eq!(role_match(&datasets, "salesperson", false), None);
+        assert_eq!(role_match(&datasets, "alice", false), None);
+    }
+
+    #[test]
+    fn built_in_role_match_reports_the_username() {
+        let datasets = Datasets::default();
+
+        assert_eq!(
+            role_match(&datasets, "Admin", true).as_deref(),
+            Some("admin")
+        );
+        assert!(datasets
+            .check(&syntax("admin"), &MiscDetails::default())
+            .role_match
+            .is_none());
+    }
+}
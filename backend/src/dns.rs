@@ -0,0 +1,155 @@
+// MX lookups behind the `MxResolver` trait.
+//
+// `check_single_email` asks the process-wide resolver, which is the real
+// `DnsResolver` unless `set_resolver` replaced it. Tests install a
+// `FakeResolver` there, so the MX, no-MX and lookup-failure outcomes can be
+// exercised without network access.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use async_std_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use async_std_resolver::{AsyncStdResolver, ResolveError};
+use cached::proc_macro::cached;
+use futures::future::{self, BoxFuture, FutureExt};
+use once_cell::sync::Lazy;
+use trust_dns_proto::op::{Query, ResponseCode};
+use trust_dns_proto::rr::{Name, RecordType};
+use trust_dns_resolver::error::ResolveErrorKind;
+
+use crate::config;
+
+/// Looks up the MX hosts of a domain.
+pub trait MxResolver: Send + Sync {
+    /// The MX hosts of `domain`, most preferred first. A domain without MX
+    /// records is an error too, `NoRecordsFound` like from a real lookup.
+    fn mx_hosts<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<Vec<Name>, ResolveError>>;
+}
+
+static RESOLVER: Lazy<RwLock<Arc<dyn MxResolver>>> =
+    Lazy::new(|| RwLock::new(Arc::new(DnsResolver)));
+
+/// The process-wide resolver used by `check_single_email`.
+pub fn resolver() -> Arc<dyn MxResolver> {
+    RESOLVER
+        .read()
+        .expect("Resolver lock is never poisoned. qed.")
+        .clone()
+}
+
+/// Replace the process-wide resolver.
+pub fn set_resolver(resolver: Arc<dyn MxResolver>) {
+    *RESOLVER
+        .write()
+        .expect("Resolver lock is never poisoned. qed.") = resolver;
+}
+
+/// Resolves through the name servers in the config. Answers are cached per
+/// domain. NXDOMAIN and answers without MX records come back as errors, so
+/// such addresses stay Unknown and are retried rather than cached.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DnsResolver;
+
+impl MxResolver for DnsResolver {
+    fn mx_hosts<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<Vec<Name>, ResolveError>> {
+        lookup_mx(domain.to_string()).boxed()
+    }
+}
+
+/// A resolver using the name servers and timeouts in the config, without
+/// any caching on our side.
+pub async fn configured_resolver() -> Result<AsyncStdResolver, ResolveError> {
+    let dns = &config::get().dns;
+    let resolver_config = if dns.nameservers.is_empty() {
+        ResolverConfig::default()
+    } else {
+        ResolverConfig::from_parts(
+            None,
+            vec![],
+            NameServerConfigGroup::from_ips_clear(&dns.nameservers, 53, true),
+        )
+    };
+    let resolver_opts = ResolverOpts {
+        timeout: config::get().dns_timeout(),
+        attempts: dns.attempts,
+        ..Default::default()
+    };
+
+    async_std_resolver::resolver(resolver_config, resolver_opts).await
+}
+
+#[cached(result = true)]
+async fn lookup_mx(domain: String) -> Result<Vec<Name>, ResolveError> {
+    let resolver = configured_resolver().await?;
+
+    let lookup = resolver.mx_lookup(domain.as_str()).await?;
+    let mut records: Vec<(u16, Name)> = lookup
+        .iter()
+        .map(|mx| (mx.preference(), mx.exchange().clone()))
+        .collect();
+    records.sort_by_key(|(preference, _)| *preference);
+
+    Ok(records.into_iter().map(|(_, exchange)| exchange).collect())
+}
+
+/// Answers from a table, without any network. Domains missing from the table
+/// don't exist, and fail with `NoRecordsFound` like an NXDOMAIN would.
+#[derive(Debug, Default, Clone)]
+pub struct FakeResolver {
+    answers: HashMap<String, Result<Vec<Name>, String>>,
+}
+
+fn table_key(domain: &str) -> String {
+    domain.trim_end_matches('.').to_lowercase()
+}
+
+impl FakeResolver {
+    pub fn new() -> FakeResolver {
+        FakeResolver::default()
+    }
+
+    /// Answer lookups for `domain` with `hosts`, most preferred first.
+    pub fn mx(mut self, domain: &str, hosts: &[&str]) -> FakeResolver {
+        let hosts = hosts
+            .iter()
+            .map(|host| Name::from_ascii(host).expect("MX host is a valid name"))
+            .collect();
+        self.answers.insert(table_key(domain), Ok(hosts));
+        self
+    }
+
+    /// Fail lookups for `domain` with `message`, like a timeout or SERVFAIL.
+    pub fn fail(mut self, domain: &str, message: &str) -> FakeResolver {
+        self.answers
+            .insert(table_key(domain), Err(message.to_string()));
+        self
+    }
+}
+
+/// The error a real lookup gives for a domain without MX records:
+/// `NXDomain` if it doesn't exist, `NoError` if it only has other records.
+fn no_records(domain: &str, response_code: ResponseCode) -> ResolveError {
+    let name = Name::from_ascii(domain).unwrap_or_else(|_| Name::root());
+
+    ResolveErrorKind::NoRecordsFound {
+        query: Query::query(name, RecordType::MX),
+        soa: None,
+        negative_ttl: None,
+        response_code,
+        trusted: true,
+    }
+    .into()
+}
+
+impl MxResolver for FakeResolver {
+    fn mx_hosts<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<Vec<Name>, ResolveError>> {
+        let answer = match self.answers.get(&table_key(domain)) {
+            Some(Ok(hosts)) if hosts.is_empty() => Err(no_records(domain, ResponseCode::NoError)),
+            Some(Ok(hosts)) => Ok(hosts.clone()),
+            Some(Err(message)) => Err(ResolveError::from(message.clone())),
+            None => Err(no_records(domain, ResponseCode::NXDomain)),
+        };
+
+        future::ready(answer).boxed()
+    }
+}
@@ -0,0 +1,33 @@
+// JSON body of the API's error responses.
+//
+// Every error of the check endpoints and the admin API, from bad JSON in
+// `json_error_handler` to a refused API key, is an object with a stable
+// `error` code for programs and a `message` for people.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ApiError {
+    pub error: String,
+    pub message: String,
+    /// Position of the offending entry, for errors about one entry of a
+    /// batch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+}
+
+impl ApiError {
+    pub fn new(error: &str, message: impl ToString) -> Self {
+        ApiError {
+            error: error.into(),
+            message: message.to_string(),
+            index: None,
+        }
+    }
+
+    /// The same error, about the entry at `index`.
+    pub fn at(mut self, index: usize) -> Self {
+        self.index = Some(index);
+        self
+    }
+}
@@ -0,0 +1,345 @@
+// Flattening check results into table rows, for CSV and XLSX output.
+//
+// `EmailCheckResponse` has nested and optional fields that CSV can't
+// represent directly: missing values become empty cells, enums their
+// snake_case name, and the reasons are joined into one cell.
+//
+// `Table` keeps every column of an uploaded CSV, so results can be appended
+// to the user's original data rather than replacing it.
+
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+use simple_excel_writer::{Row, Workbook};
+
+use crate::mail::EmailCheckResponse;
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Xlsx,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Xlsx => {
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+            }
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Xlsx => "xlsx",
+        }
+    }
+}
+
+/// Header of the columns `result_row` returns, in order.
+pub const RESULT_COLUMNS: [&str; 19] = [
+    "is_reachable",
+    "normalized_email",
+    "score",
+    "confidence",
+    "syntax_problem",
+    "suggestion",
+    "is_disposable",
+    "disposable_match",
+    "is_role_account",
+    "role_match",
+    "is_free_provider",
+    "can_connect_smtp",
+    "has_full_inbox",
+    "is_catch_all",
+    "is_deliverable",
+    "is_disabled",
+    "is_banned",
+    "provider",
+    "reasons",
+];
+
+fn cell<T: ToString>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .map(|value| value.to_string())
+        .unwrap_or_default()
+}
+
+/// The serialized name of a unit enum variant, e.g. `google_workspace`.
+fn variant<T: Serialize>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .and_then(|value| serde_json::to_value(value).ok())
+        .and_then(|value| value.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+/// One cell per entry of `RESULT_COLUMNS`.
+pub fn result_row(result: &EmailCheckResponse) -> Vec<String> {
+    vec![
+        result.is_reachable.to_string(),
+        cell(&result.normalized_email),
+        cell(&result.score),
+        cell(&result.confidence),
+        variant(&result.syntax_problem),
+        cell(&result.suggestion),
+        cell(&result.is_disposable),
+        cell(&result.disposable_match),
+        cell(&result.is_role_account),
+        cell(&result.role_match),
+        cell(&result.is_free_provider),
+        cell(&result.can_connect_smtp),
+        cell(&result.has_full_inbox),
+        cell(&result.is_catch_all),
+        cell(&result.is_deliverable),
+        cell(&result.is_disabled),
+        cell(&result.is_banned),
+        variant(&result.provider),
+        result.reasons.join("; "),
+    ]
+}
+
+/// A CSV file with a header row, kept as strings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table {
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    /// Parse a CSV file. Rows may have fewer or more cells than the header.
+    pub fn from_csv(reader: impl Read) -> csv::Result<Self> {
+        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+
+        let headers = reader.headers()?.iter().map(String::from).collect();
+        let mut rows = vec![];
+        for record in reader.records() {
+            rows.push(record?.iter().map(String::from).collect());
+        }
+
+        Ok(Table { headers, rows })
+    }
+
+    /// Index of the column holding the addresses: `column` if given,
+    /// otherwise the first column whose header contains "email", or the
+    /// first column.
+    pub fn email_column(&self, column: Option<&str>) -> Option<usize> {
+        match column {
+            Some(column) => self
+                .headers
+                .iter()
+                .position(|header| header.trim() == column),
+            None if self.headers.is_empty() => None,
+            None => self
+                .headers
+                .iter()
+                .position(|header| header.to_lowercase().contains("email"))
+                .or(Some(0)),
+        }
+    }
+
+    /// The trimmed cell of column `index` in every row, empty if missing.
+    pub fn column(&self, index: usize) -> Vec<String> {
+        self.rows
+            .iter()
+            .map(|row| {
+                row.get(index)
+                    .map(|cell| cell.trim())
+                    .unwrap_or("")
+                    .to_string()
+            })
+            .collect()
+    }
+
+    /// A copy of the table with `RESULT_COLUMNS` appended. `results` holds
+    /// one entry per row, in order. Rows are padded or cut to the header's
+    /// width first, so the results line up under their headers.
+    pub fn with_results(&self, results: &[EmailCheckResponse]) -> Table {
+        assert_eq!(
+            results.len(),
+            self.rows.len(),
+            "One result per row of the table."
+        );
+
+        let width = self.headers.len();
+        let headers = self
+            .headers
+            .iter()
+            .cloned()
+            .chain(RESULT_COLUMNS.iter().map(|column| column.to_string()))
+            .collect();
+        let rows = self
+            .rows
+            .iter()
+            .zip(results)
+            .map(|(row, result)| {
+                let mut row = row.clone();
+                row.resize(width, String::new());
+                row.extend(result_row(result));
+                row
+            })
+            .collect();
+
+        Table { headers, rows }
+    }
+
+    pub fn write_csv(&self, writer: impl Write) -> csv::Result<()> {
+        let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(writer);
+
+        writer.write_record(&self.headers)?;
+        for row in &self.rows {
+            writer.write_record(row)?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// The table as a single-sheet XLSX workbook.
+    pub fn to_xlsx(&self) -> io::Result<Vec<u8>> {
+        let mut workbook = Workbook::create_in_memory();
+        let mut sheet = workbook.create_sheet("Results");
+
+        workbook.write_sheet(&mut sheet, |writer| {
+            for cells in std::iter::once(&self.headers).chain(&self.rows) {
+                let mut row = Row::new();
+                for cell in cells {
+                    row.add_cell(cell.as_str());
+                }
+                writer.append_row(row)?;
+            }
+            Ok(())
+        })?;
+
+        Ok(workbook.close()?.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::mail::MyReachable;
+
+    fn table(csv: &str) -> Table {
+        Table::from_csv(csv.as_bytes()).unwrap()
+    }
+
+    fn result(is_reachable: MyReachable) -> EmailCheckResponse {
+        EmailCheckResponse {
+            is_reachable,
+            reasons: vec!["+10 first".into(), "+20 second".into()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn email_column_by_name_header_or_first() {
+        let table = table("id, Email Address ,name\n1,a@example.com,A\n");
+        assert_eq!(table.email_column(Some("Email Address")), Some(1));
+        assert_eq!(table.email_column(Some("name")), Some(2));
+        assert_eq!(table.email_column(Some("phone")), None);
+        assert_eq!(table.email_column(None), Some(1));
+
+        let table = Table {
+            headers: vec!["contact".into(), "name".into()],
+            rows: vec![],
+        };
+        assert_eq!(table.email_column(None), Some(0));
+        assert_eq!(Table::default().email_column(None), None);
+    }
+
+    #[test]
+    fn column_trims_and_fills_missing_cells() {
+        let table = table("name,email\nA, a@example.com \nB\n");
+        assert_eq!(table.column(1), vec!["a@example.com", ""]);
+    }
+
+    #[test]
+    fn ragged_rows_line_up_with_the_results() {
+        let table = table("name,email,note\nA,a@example.com\nB,b@example.com,x,extra\n");
+        let with_results =
+            table.with_results(&[result(MyReachable::Safe), result(MyReachable::Invalid)]);
+
+        assert_eq!(with_results.headers.len(), 3 + RESULT_COLUMNS.len());
+        for row in &with_results.rows {
+            assert_eq!(row.len(), with_results.headers.len());
+        }
+        assert_eq!(
+            with_results.rows[0][..4],
+            ["A", "a@example.com", "", "safe"]
+        );
+        assert_eq!(
+            with_results.rows[1][..4],
+            ["B", "b@example.com", "x", "invalid"]
+        );
+        assert_eq!(
+            with_results.rows[0].last().map(String::as_str),
+            Some("+10 first; +20 second")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "One result per row")]
+    fn with_results_needs_one_result_per_row() {
+        table("email\na@example.com\n").with_results(&[]);
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let table = table("name,email\n\"Smith, \"\"Al\"\"\",a@example.com\n");
+        assert_eq!(table.rows[0][0], "Smith, \"Al\"");
+
+        let with_results = table.with_results(&[result(MyReachable::Risky)]);
+        let mut csv = vec![];
+        with_results.write_csv(&mut csv).unwrap();
+
+        assert_eq!(Table::from_csv(csv.as_slice()).unwrap(), with_results);
+    }
+
+    /// The cells of the first sheet, which the writer stores as inline
+    /// strings.
+    fn read_xlsx(bytes: Vec<u8>) -> Vec<Vec<String>> {
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut sheet = String::new();
+        archive
+            .by_name("xl/worksheets/sheet1.xml")
+            .unwrap()
+            .read_to_string(&mut sheet)
+            .unwrap();
+
+        let value = |cell: &str| {
+            let from = cell.find("<v>").map_or(cell.len(), |at| at + 3);
+            let to = cell[from..].find("</v>").map_or(cell.len(), |at| from + at);
+            cell[from..to]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        };
+
+        sheet
+            .split("<row")
+            .skip(1)
+            .map(|row| row.split("<c ").skip(1).map(value).collect())
+            .collect()
+    }
+
+    #[test]
+    fn xlsx_round_trip() {
+        let table = table("name,email\nA & <B>,a@example.com\n");
+        let with_results = table.with_results(&[result(MyReachable::Safe)]);
+
+        let rows = read_xlsx(with_results.to_xlsx().unwrap());
+
+        assert_eq!(rows[0], with_results.headers);
+        assert_eq!(rows[1..], with_results.rows[..]);
+    }
+}
@@ -0,0 +1,329 @@
+// Verification strategy for mailboxes hosted on Google's MX servers.
+//
+// Consumer Gmail answers `RCPT TO` reliably and never routes unknown
+// recipients to a catch-all, so we skip the catch-all probe there. Workspace
+// domains can have catch-all routing configured by their admins, so for those
+// we probe a random address first and report the result as `is_catch_all`.
+
+use std::time::Instant;
+
+use async_smtp::{smtp::error::Error as AsyncSmtpError, SmtpTransport};
+use check_if_email_exists::CheckEmailInput;
+use trust_dns_proto::rr::Name;
+
+use crate::metrics;
+use crate::shutdown;
+use crate::smtp::{
+    connect_to_host, finish_session, is_io_incomplete_smtp_error, needs_smtputf8, parse_smtp_error,
+    random_email, smtp_deadline, with_smtp_deadline, Deliverability, Provider, RcptTo, SmtpError,
+};
+
+/// Domains served by consumer Gmail. Every other domain with a Google MX is a
+/// Workspace domain.
+const CONSUMER_DOMAINS: [&str; 2] = ["gmail.com", "googlemail.com"];
+
+/// Does this MX host belong to Google?
+pub fn is_google_mx(host: &Name) -> bool {
+    let host = host.to_utf8().trim_end_matches('.').to_lowercase();
+
+    host.ends_with(".google.com") || host.ends_with(".googlemail.com")
+}
+
+/// Tell consumer Gmail apart from a Workspace domain hosted on Google's MX.
+pub fn provider(domain: &str) -> Provider {
+    if CONSUMER_DOMAINS.contains(&domain.to_lowercase().as_ref()) {
+        Provider::GoogleConsumer
+    } else {
+        Provider::GoogleWorkspace
+    }
+}
+
+/// Map a rejected `RCPT TO` to a deliverability verdict, using the enhanced
+/// status codes Google puts at the start of its replies. Returns `None` for
+/// replies we don't recognise, which go to the generic parser instead.
+fn parse_google_error(err: &AsyncSmtpError) -> Option<Deliverability> {
+    let response = match err {
+        AsyncSmtpError::Permanent(response) | AsyncSmtpError::Transient(response) => response,
+        _ => return None,
+    };
+    let message = response.message.join(" ").to_lowercase();
+
+    let deliverability = |is_deliverable, has_full_inbox, is_disabled, is_banned| {
+        Some(Deliverability {
+            has_full_inbox,
+            is_deliverable,
+            is_disabled,
+            is_banned,
+        })
+    };
+
+    // 550-5.1.1 The email account that you tried to reach does not exist.
+    if message.contains("5.1.1") || message.contains("does not exist") {
+        return deliverability(false, false, false, false);
+    }
+
+    // 550-5.2.1 The email account that you tried to reach is disabled.
+    // 550-5.2.1 The user you are trying to contact is receiving mail at a rate
+    // that prevents additional messages from being delivered.
+    if message.contains("5.2.1") || message.contains("4.2.1") {
+        if message.contains("receiving mail at a rate") {
+            return deliverability(true, false, false, false);
+        }
+
+        return deliverability(false, false, true, false);
+    }
+
+    // 552-5.2.2 The email account that you tried to reach is over quota.
+    // 452-4.2.2 The email account that you tried to reach is over quota.
+    if message.contains("5.2.2") || message.contains("4.2.2") {
+        return deliverability(false, true, false, false);
+    }
+
+    // 421-4.7.0 Our system has detected an unusual rate of unsolicited mail
+    // originating from your IP address.
+    // 550-5.7.1 Our system has detected that this message is likely unsolicited
+    // mail.
+    //
+    // Google also answers 5.7.1 when the recipient's own policy refuses us,
+    // "The user you are trying to contact is not accepting mail from you", or
+    // "rejected due to domain policy". That says nothing about our IP.
+    if (message.contains("4.7.0") || message.contains("5.7.1"))
+        && (message.contains("unsolicited") || message.contains("unusual rate"))
+    {
+        return deliverability(false, false, false, true);
+    }
+
+    None
+}
+
+async fn google_deliverable(
+    smtp_client: &mut SmtpTransport,
+    to_email: &str,
+) -> Result<Deliverability, SmtpError> {
+    let start = Instant::now();
+    let response = smtp_client.command(RcptTo(to_email)).await;
+    metrics::observe_rcpt(start);
+
+    match response {
+        Ok(_) => Ok(Deliverability {
+            has_full_inbox: false,
+            is_deliverable: true,
+            is_disabled: false,
+            is_banned: false,
+        }),
+        Err(err) => match parse_google_error(&err) {
+            Some(deliverability) => Ok(deliverability),
+            None => parse_smtp_error(err),
+        },
+    }
+}
+
+pub(crate) async fn create_google_future(
+    to_email: &str,
+    host: &Name,
+    port: u16,
+    domain: &str,
+    provider: Provider,
+    input: &CheckEmailInput,
+) -> Result<(bool, Deliverability), SmtpError> {
+    let smtputf8 = needs_smtputf8(to_email);
+    let deadline = smtp_deadline(input.smtp_timeout);
+    let mut smtp_client =
+        with_smtp_deadline(deadline, connect_to_host(host, port, input, smtputf8)).await?;
+
+    let result = shutdown::or_deadline(with_smtp_deadline(
+        deadline,
+        google_conversation(
+            &mut smtp_client,
+            to_email,
+            host,
+            port,
+            domain,
+            provider,
+            input,
+            smtputf8,
+        ),
+    ))
+    .await;
+
+    finish_session(smtp_client, result).await
+}
+
+/// Probe Workspace domains for a catch-all, then check `to_email`.
+#[allow(clippy::too_many_arguments)]
+async fn google_conversation(
+    smtp_client: &mut SmtpTransport,
+    to_email: &str,
+    host: &Name,
+    port: u16,
+    domain: &str,
+    provider: Provider,
+    input: &CheckEmailInput,
+    smtputf8: bool,
+) -> Result<(bool, Deliverability), SmtpError> {
+    let is_catch_all = if provider == Provider::GoogleWorkspace {
+        google_deliverable(smtp_client, &random_email(domain))
+            .await
+            .map(|deliverability| deliverability.is_deliverable)
+            .unwrap_or(false)
+    } else {
+        false
+    };
+
+    let deliverability = if is_catch_all {
+        Deliverability {
+            has_full_inbox: false,
+            is_deliverable: true,
+            is_disabled: false,
+            is_banned: false,
+        }
+    } else {
+        let mut result = google_deliverable(smtp_client, to_email).await;
+
+        // Same as the generic strategy: Google sometimes drops the connection
+        // after a rejected recipient.
+        if is_io_incomplete_smtp_error(&result) {
+            let _ = smtp_client.close().await;
+            *smtp_client = connect_to_host(host, port, input, smtputf8).await?;
+            result = google_deliverable(smtp_client, to_email).await;
+        }
+
+        result?
+    };
+
+    Ok((is_catch_all, deliverability))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(reply: &str) -> AsyncSmtpError {
+        let response = reply.parse().expect("valid SMTP reply");
+        if reply.starts_with('4') {
+            AsyncSmtpError::Transient(response)
+        } else {
+            AsyncSmtpError::Permanent(response)
+        }
+    }
+
+    /// `(is_deliverable, has_full_inbox, is_disabled, is_banned)` of a reply.
+    fn verdict(reply: &str) -> Option<(bool, bool, bool, bool)> {
+        parse_google_error(&rejected(reply)).map(|deliverability| {
+            (
+                deliverability.is_deliverable,
+                deliverability.has_full_inbox,
+                deliverability.is_disabled,
+                deliverability.is_banned,
+            )
+        })
+    }
+
+    #[test]
+    fn detects_google_mx() {
+        for host in &[
+            "gmail-smtp-in.l.google.com.",
+            "ASPMX.L.GOOGLE.COM.",
+            "alt1.gmail-smtp-in.l.google.com",
+            "aspmx2.googlemail.com.",
+        ] {
+            assert!(is_google_mx(&Name::from_utf8(host).unwrap()), "{}", host);
+        }
+        for host in &[
+            "mx.example.com.",
+            "google.com.evil.example.",
+            "notgoogle.com.",
+        ] {
+            assert!(!is_google_mx(&Name::from_utf8(host).unwrap()), "{}", host);
+        }
+    }
+
+    #[test]
+    fn tells_consumer_from_workspace() {
+        assert_eq!(provider("gmail.com"), Provider::GoogleConsumer);
+        assert_eq!(provider("GoogleMail.com"), Provider::GoogleConsumer);
+        assert_eq!(provider("example.com"), Provider::GoogleWorkspace);
+        assert_eq!(provider("gmail.co.uk"), Provider::GoogleWorkspace);
+    }
+
+    #[test]
+    fn parses_unknown_account() {
+        assert_eq!(
+            verdict(
+                "550-5.1.1 The email account that you tried to reach does not exist. Please try\r\n\
+                 550 5.1.1 double-checking the recipient's email address for typos\r\n"
+            ),
+            Some((false, false, false, false))
+        );
+    }
+
+    #[test]
+    fn parses_disabled_account() {
+        assert_eq!(
+            verdict("550 5.2.1 The email account that you tried to reach is disabled.\r\n"),
+            Some((false, false, true, false))
+        );
+    }
+
+    #[test]
+    fn parses_rate_limited_account_as_deliverable() {
+        assert_eq!(
+            verdict(
+                "450-4.2.1 The user you are trying to contact is receiving mail at a rate that\r\n\
+                 450 4.2.1 prevents additional messages from being delivered.\r\n"
+            ),
+            Some((true, false, false, false))
+        );
+    }
+
+    #[test]
+    fn parses_over_quota() {
+        assert_eq!(
+            verdict("552 5.2.2 The email account that you tried to reach is over quota.\r\n"),
+            Some((false, true, false, false))
+        );
+        assert_eq!(
+            verdict("452 4.2.2 The email account that you tried to reach is over quota.\r\n"),
+            Some((false, true, false, false))
+        );
+    }
+
+    #[test]
+    fn parses_unsolicited_mail_as_banned() {
+        assert_eq!(
+            verdict(
+                "421-4.7.0 Our system has detected an unusual rate of unsolicited mail\r\n\
+                 421 4.7.0 originating from your IP address.\r\n"
+            ),
+            Some((false, false, false, true))
+        );
+        assert_eq!(
+            verdict(
+                "550-5.7.1 Our system has detected that this message is likely unsolicited\r\n\
+                 550 5.7.1 mail.\r\n"
+            ),
+            Some((false, false, false, true))
+        );
+    }
+
+    #[test]
+    fn leaves_recipient_policy_rejections_to_the_generic_parser() {
+        for reply in &[
+            "550 5.7.1 The user you are trying to contact is not accepting mail from you.\r\n",
+            "550 5.7.1 Message rejected due to domain policy.\r\n",
+        ] {
+            assert_eq!(verdict(reply), None, "{}", reply);
+            assert!(parse_smtp_error(rejected(reply)).is_err(), "{}", reply);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_generic_parser() {
+        let reply = "550 5.1.0 Recipient address rejected: User unknown\r\n";
+        assert_eq!(verdict(reply), None);
+
+        let deliverability = parse_smtp_error(rejected(reply)).expect("a verdict");
+        assert!(!deliverability.is_deliverable);
+        assert!(!deliverability.is_banned);
+    }
+}
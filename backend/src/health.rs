@@ -0,0 +1,490 @@
+// Liveness, readiness and self-diagnostics.
+//
+// `/health/live` only says the server answers. `/health/ready` also checks
+// what every check needs: that DNS resolves and that we can reach a canary
+// host on the SMTP port, as some hosts block outbound port 25. Its result is
+// reused for `health.cache_secs`.
+//
+// `/api/diagnostics` looks for the usual causes of mass Banned verdicts: a
+// PTR record of our public IP not matching `HELLO_NAME`, or that IP being
+// listed on DNS blocklists. It queries outside services on every call, so
+// it needs the admin token.
+
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use async_std::future;
+use async_std::net::TcpStream;
+use async_std_resolver::AsyncStdResolver;
+use futures::future::join_all;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use trust_dns_resolver::error::ResolveErrorKind;
+
+use crate::admin;
+use crate::config;
+use crate::dns;
+use crate::shutdown;
+
+/// Outcome of one readiness probe.
+#[derive(Debug, Clone, Serialize)]
+pub struct Probe {
+    pub ok: bool,
+    pub detail: String,
+    pub took_ms: u64,
+}
+
+impl Probe {
+    fn new(start: Instant, result: Result<String, String>) -> Self {
+        let took_ms = start.elapsed().as_millis() as u64;
+        match result {
+            Ok(detail) => Probe {
+                ok: true,
+                detail,
+                took_ms,
+            },
+            Err(detail) => Probe {
+                ok: false,
+                detail,
+                took_ms,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// Set once a shutdown began, which makes the server unready whatever
+    /// the probes say.
+    pub shutting_down: bool,
+    pub dns: Probe,
+    pub smtp: Probe,
+}
+
+static LAST_READINESS: Lazy<Mutex<Option<(Instant, Readiness)>>> = Lazy::new(|| Mutex::new(None));
+
+async fn probe_dns() -> Probe {
+    let start = Instant::now();
+    let domain = &config::get().health.dns_domain;
+    let result = match dns::configured_resolver().await {
+        Ok(resolver) => match resolver.mx_lookup(domain.as_str()).await {
+            Ok(lookup) => Ok(format!(
+                "{} has {} MX records",
+                domain,
+                lookup.iter().count()
+            )),
+            Err(err) => match err.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => {
+                    Ok(format!("{} has no MX records", domain))
+                }
+                _ => Err(format!("MX lookup of {} failed: {}", domain, err)),
+            },
+        },
+        Err(err) => Err(format!("No resolver: {}", err)),
+    };
+
+    Probe::new(start, result)
+}
+
+async fn probe_smtp() -> Probe {
+    let start = Instant::now();
+    let config = config::get();
+    let (host, port) = (config.health.canary_host.as_str(), config.smtp.port);
+    let result =
+        match future::timeout(config.health_timeout(), TcpStream::connect((host, port))).await {
+            Ok(Ok(_)) => Ok(format!("Connected to {}:{}", host, port)),
+            Ok(Err(err)) => Err(format!("Connecting to {}:{} failed: {}", host, port, err)),
+            Err(_) => Err(format!("Connecting to {}:{} timed out", host, port)),
+        };
+
+    Probe::new(start, result)
+}
+
+/// The cached readiness, if it's younger than `cache_secs`.
+fn cached(
+    last: &Option<(Instant, Readiness)>,
+    cache_secs: u64,
+    shutting_down: bool,
+) -> Option<Readiness> {
+    match last {
+        Some((at, readiness)) if at.elapsed().as_secs() < cache_secs => Some(Readiness {
+            ready: readiness.ready && !shutting_down,
+            shutting_down,
+            ..readiness.clone()
+        }),
+        _ => None,
+    }
+}
+
+/// Whether checks can run, probed at most once per `health.cache_secs`.
+pub async fn readiness() -> Readiness {
+    let shutting_down = shutdown::is_shutting_down();
+    let cache_secs = config::get().health.cache_secs;
+    if let Some(readiness) = cached(
+        &LAST_READINESS
+            .lock()
+            .expect("Readiness lock is never poisoned. qed."),
+        cache_secs,
+        shutting_down,
+    ) {
+        return readiness;
+    }
+
+    let (dns, smtp) = futures::join!(probe_dns(), probe_smtp());
+    let readiness = Readiness {
+        ready: dns.ok && smtp.ok && !shutting_down,
+        shutting_down,
+        dns,
+        smtp,
+    };
+    *LAST_READINESS
+        .lock()
+        .expect("Readiness lock is never poisoned. qed.") =
+        Some((Instant::now(), readiness.clone()));
+
+    readiness
+}
+
+/// Whether our IP is on one blocklist. `listed` is `None` when the zone
+/// couldn't tell.
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsblListing {
+    pub zone: String,
+    pub listed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Diagnostics {
+    pub public_ip: Option<IpAddr>,
+    pub hello_name: String,
+    /// PTR records of the public IP.
+    pub ptr: Vec<String>,
+    pub ptr_matches_hello_name: bool,
+    /// Whether `hello_name` resolves back to the public IP, which some
+    /// servers require on top of the PTR record.
+    pub hello_name_resolves_to_ip: bool,
+    pub dnsbl: Vec<DnsblListing>,
+    /// Everything above likely to get checks refused, in plain words.
+    pub problems: Vec<String>,
+}
+
+async fn public_ip() -> Result<IpAddr, String> {
+    let health = &config::get().health;
+    let client = reqwest::Client::builder()
+        .timeout(config::get().health_timeout())
+        .build()
+        .map_err(|err| err.to_string())?;
+    let body = client
+        .get(&health.public_ip_url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| err.to_string())?
+        .text()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    body.trim()
+        .parse()
+        .map_err(|_| format!("{} answered {:?}, not an IP", health.public_ip_url, body))
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// The name to look `ip` up at in `zone`: its octets reversed, then the zone.
+/// Only IPv4 is looked up.
+fn dnsbl_query(ip: IpAddr, zone: &str) -> Option<String> {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            Some(format!(
+                "{}.{}.{}.{}.{}.",
+                octets[3],
+                octets[2],
+                octets[1],
+                octets[0],
+                zone.trim_end_matches('.')
+            ))
+        }
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// Spamhaus answers 127.255.255.x to queries it refuses, e.g. through public
+/// resolvers.
+fn is_refused(answers: &[IpAddr]) -> bool {
+    answers.iter().any(|answer| match answer {
+        IpAddr::V4(answer) => answer.octets()[..3] == [127, 255, 255],
+        IpAddr::V6(_) => false,
+    })
+}
+
+/// The listing told by the answers of a lookup in `zone`.
+fn listing_from_answers(zone: &str, answers: &[IpAddr]) -> DnsblListing {
+    let detail = answers
+        .iter()
+        .map(IpAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let (listed, detail) = if is_refused(answers) {
+        (None, format!("Query refused: {}", detail))
+    } else {
+        (Some(!answers.is_empty()), detail)
+    };
+
+    DnsblListing {
+        zone: zone.to_string(),
+        listed,
+        detail: Some(detail),
+    }
+}
+
+/// Look `ip` up in `zone`. Blocklists answer with an address in 127.0.0.0/8
+/// when it's listed, and with no record when it isn't.
+async fn dnsbl_listing(resolver: &AsyncStdResolver, ip: IpAddr, zone: &str) -> DnsblListing {
+    let listing = |listed, detail: Option<String>| DnsblListing {
+        zone: zone.to_string(),
+        listed,
+        detail,
+    };
+
+    let query = match dnsbl_query(ip, zone) {
+        Some(query) => query,
+        None => return listing(None, Some("Only IPv4 is looked up".into())),
+    };
+
+    match resolver.lookup_ip(query.as_str()).await {
+        Ok(lookup) => listing_from_answers(zone, &lookup.iter().collect::<Vec<_>>()),
+        Err(err) => match err.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => listing(Some(false), None),
+            _ => listing(None, Some(err.to_string())),
+        },
+    }
+}
+
+/// Look for the usual causes of mass Banned verdicts.
+pub async fn diagnostics() -> Diagnostics {
+    let config = config::get();
+    let mut diagnostics = Diagnostics {
+        hello_name: config.smtp.hello_name.clone(),
+        ..Default::default()
+    };
+
+    let ip = match public_ip().await {
+        Ok(ip) => ip,
+        Err(err) => {
+            diagnostics
+                .problems
+                .push(format!("Public IP unknown: {}", err));
+            return diagnostics;
+        }
+    };
+    diagnostics.public_ip = Some(ip);
+
+    let resolver = match dns::configured_resolver().await {
+        Ok(resolver) => resolver,
+        Err(err) => {
+            diagnostics.problems.push(format!("No resolver: {}", err));
+            return diagnostics;
+        }
+    };
+
+    match resolver.reverse_lookup(ip).await {
+        Ok(lookup) => {
+            diagnostics.ptr = lookup.iter().map(|name| name.to_utf8()).collect();
+        }
+        Err(err) => match err.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => {}
+            _ => diagnostics
+                .problems
+                .push(format!("PTR lookup of {} failed: {}", ip, err)),
+        },
+    }
+    diagnostics.ptr_matches_hello_name = diagnostics
+        .ptr
+        .iter()
+        .any(|name| same_name(name, &config.smtp.hello_name));
+    if diagnostics.ptr.is_empty() {
+        diagnostics
+            .problems
+            .push(format!("{} has no PTR record", ip));
+    } else if !diagnostics.ptr_matches_hello_name {
+        diagnostics.problems.push(format!(
+            "PTR of {} is {}, not HELLO_NAME {}",
+            ip,
+            diagnostics.ptr.join(", "),
+            config.smtp.hello_name
+        ));
+    }
+
+    diagnostics.hello_name_resolves_to_ip = match resolver
+        .lookup_ip(format!("{}.", config.smtp.hello_name.trim_end_matches('.')).as_str())
+        .await
+    {
+        Ok(lookup) => lookup.iter().any(|address| address == ip),
+        Err(_) => false,
+    };
+    if !diagnostics.hello_name_resolves_to_ip {
+        diagnostics.problems.push(format!(
+            "HELLO_NAME {} doesn't resolve to {}",
+            config.smtp.hello_name, ip
+        ));
+    }
+
+    diagnostics.dnsbl = join_all(
+        config
+            .health
+            .dnsbls
+            .iter()
+            .map(|zone| dnsbl_listing(&resolver, ip, zone)),
+    )
+    .await;
+    for listing in &diagnostics.dnsbl {
+        if listing.listed == Some(true) {
+            diagnostics
+                .problems
+                .push(format!("{} is listed on {}", ip, listing.zone));
+        }
+    }
+
+    diagnostics
+}
+
+#[get("/health/live")]
+async fn live() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "live": true }))
+}
+
+#[get("/health/ready")]
+async fn ready() -> impl Responder {
+    let readiness = readiness().await;
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+#[get("/api/diagnostics")]
+async fn get_diagnostics(req: HttpRequest) -> impl Responder {
+    if let Err(resp) = admin::authorize(&req) {
+        return resp;
+    }
+
+    HttpResponse::Ok().json(diagnostics().await)
+}
+
+/// Register the health and diagnostics endpoints on an `App`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(live).service(ready).service(get_diagnostics);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn probe(ok: bool) -> Probe {
+        Probe {
+            ok,
+            detail: String::new(),
+            took_ms: 0,
+        }
+    }
+
+    fn ready(ready: bool) -> Readiness {
+        Readiness {
+            ready,
+            shutting_down: false,
+            dns: probe(ready),
+            smtp: probe(true),
+        }
+    }
+
+    #[test]
+    fn dnsbl_query_reverses_the_octets() {
+        assert_eq!(
+            dnsbl_query(ip("192.0.2.10"), "zen.spamhaus.org").as_deref(),
+            Some("10.2.0.192.zen.spamhaus.org.")
+        );
+        assert_eq!(
+            dnsbl_query(ip("192.0.2.10"), "bl.spamcop.net.").as_deref(),
+            Some("10.2.0.192.bl.spamcop.net.")
+        );
+        assert_eq!(dnsbl_query(ip("2001:db8::1"), "zen.spamhaus.org"), None);
+    }
+
+    #[test]
+    fn listing_follows_the_answers() {
+        let listed = listing_from_answers("zen.spamhaus.org", &[ip("127.0.0.2"), ip("127.0.0.4")]);
+        assert_eq!(listed.listed, Some(true));
+        assert_eq!(listed.detail.as_deref(), Some("127.0.0.2, 127.0.0.4"));
+
+        assert_eq!(
+            listing_from_answers("zen.spamhaus.org", &[]).listed,
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn refused_queries_tell_nothing() {
+        assert!(is_refused(&[ip("127.255.255.254")]));
+        assert!(is_refused(&[ip("127.0.0.2"), ip("127.255.255.252")]));
+        assert!(!is_refused(&[ip("127.0.0.2")]));
+        assert!(!is_refused(&[ip("127.255.254.1")]));
+        assert!(!is_refused(&[ip("::ffff:127.255.255.254")]));
+
+        let listing = listing_from_answers("zen.spamhaus.org", &[ip("127.255.255.254")]);
+        assert_eq!(listing.listed, None);
+        assert_eq!(
+            listing.detail.as_deref(),
+            Some("Query refused: 127.255.255.254")
+        );
+    }
+
+    #[test]
+    fn same_name_ignores_case_and_the_root_dot() {
+        assert!(same_name("mail.example.com.", "Mail.Example.com"));
+        assert!(same_name("mail.example.com", "mail.example.com"));
+        assert!(!same_name("mail.example.com", "mx.example.com"));
+        assert!(!same_name("mail.example.com", "example.com"));
+    }
+
+    #[test]
+    fn readiness_is_reused_while_fresh() {
+        let fresh = Some((Instant::now(), ready(true)));
+        let readiness = cached(&fresh, 30, false).expect("Fresh result is reused");
+        assert!(readiness.ready);
+        assert!(!readiness.shutting_down);
+
+        let unready = Some((Instant::now(), ready(false)));
+        assert!(!cached(&unready, 30, false).unwrap().ready);
+
+        assert!(cached(&None, 30, false).is_none());
+        assert!(cached(&fresh, 0, false).is_none());
+        let stale = Some((Instant::now() - Duration::from_secs(31), ready(true)));
+        assert!(cached(&stale, 30, false).is_none());
+    }
+
+    #[test]
+    fn shutdown_overrides_cached_readiness() {
+        let fresh = Some((Instant::now(), ready(true)));
+        let readiness = cached(&fresh, 30, true).unwrap();
+        assert!(!readiness.ready);
+        assert!(readiness.shutting_down);
+        assert!(readiness.dns.ok);
+    }
+}
@@ -1,4 +1,24 @@
+pub mod admin;
+pub mod auth;
+pub mod config;
+pub mod datasets;
+pub mod dns;
+pub mod errors;
+pub mod export;
+pub mod google;
+pub mod health;
+pub mod lists;
+pub mod logging;
 pub mod mail;
+pub mod metrics;
+pub mod mock;
+pub mod normalize;
+pub mod pii;
+pub mod policy;
+pub mod score;
+pub mod shutdown;
 pub mod smtp;
+pub mod suggest;
+pub mod syntax;
 pub mod util;
 pub mod yahoo;
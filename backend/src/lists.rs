@@ -0,0 +1,459 @@
+// Custom allow and deny lists, consulted before any network activity.
+//
+// Rules match an address, a domain or an MX host, either exactly, with `*`
+// and `?` wildcards, or with a regex. All three are compiled to a
+// case-insensitive regex when the rule is added.
+//
+// Rules loaded from a file are written back to it whenever one is added or
+// removed, so the admin API's changes survive a restart. Without a file they
+// only live in memory.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+use regex::{Error as RegexError, Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::error::Error as SerdeError;
+
+use crate::mail::MyReachable;
+
+static ACCESS_LISTS: Lazy<RwLock<AccessLists>> = Lazy::new(|| RwLock::new(AccessLists::new()));
+
+/// The process-wide lists used by `check_single_email`.
+pub fn global() -> &'static RwLock<AccessLists> {
+    &ACCESS_LISTS
+}
+
+/// What a rule is matched against.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Address,
+    Domain,
+    MxHost,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchKind {
+    Exact,
+    /// `*` matches any run of characters, `?` a single one.
+    Wildcard,
+    Regex,
+}
+
+/// What to do with an address matching a rule. When several rules match,
+/// `Deny` wins over `Allow`, which wins over `SkipSmtp`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Report as Invalid without checking.
+    Deny,
+    /// Report as Safe without checking.
+    Allow,
+    /// Run the syntax and DNS stages, but never connect to the MX.
+    SkipSmtp,
+}
+
+impl Action {
+    /// The verdict reported for an address matching a rule with this action.
+    pub fn verdict(&self) -> MyReachable {
+        match *self {
+            Action::Deny => MyReachable::Invalid,
+            Action::Allow => MyReachable::Safe,
+            Action::SkipSmtp => MyReachable::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::Deny => write!(f, "denied"),
+            Action::Allow => write!(f, "allowed"),
+            Action::SkipSmtp => write!(f, "SMTP skipped"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListRule {
+    pub target: Target,
+    pub kind: MatchKind,
+    pub pattern: String,
+    pub action: Action,
+    /// Reported as the verdict reason when this rule matches.
+    pub reason: Option<String>,
+}
+
+impl ListRule {
+    fn compile(&self) -> Result<Regex, RegexError> {
+        let pattern = match self.kind {
+            MatchKind::Exact => format!("^{}$", regex::escape(&self.pattern)),
+            MatchKind::Wildcard => format!(
+                "^{}$",
+                regex::escape(&self.pattern)
+                    .replace(r"\*", ".*")
+                    .replace(r"\?", ".")
+            ),
+            MatchKind::Regex => self.pattern.clone(),
+        };
+
+        RegexBuilder::new(&pattern).case_insensitive(true).build()
+    }
+}
+
+/// A rule as stored in the lists, with the id used by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredRule {
+    pub id: u64,
+    #[serde(flatten)]
+    pub rule: ListRule,
+    #[serde(skip)]
+    matcher: Regex,
+}
+
+/// The rule that decided what to do with an address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListMatch {
+    pub id: u64,
+    pub action: Action,
+    pub reason: String,
+}
+
+impl fmt::Display for ListMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} by list rule #{}: {}",
+            self.action, self.id, self.reason
+        )
+    }
+}
+
+/// Possible errors when adding rules.
+#[derive(Debug)]
+pub enum ListError {
+    IoError(io::Error),
+    RegexError(RegexError),
+    SerdeError(SerdeError),
+}
+
+impl fmt::Display for ListError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<io::Error> for ListError {
+    fn from(error: io::Error) -> Self {
+        ListError::IoError(error)
+    }
+}
+
+impl From<RegexError> for ListError {
+    fn from(error: RegexError) -> Self {
+        ListError::RegexError(error)
+    }
+}
+
+impl From<SerdeError> for ListError {
+    fn from(error: SerdeError) -> Self {
+        ListError::SerdeError(error)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AccessLists {
+    next_id: u64,
+    rules: Vec<StoredRule>,
+    path: Option<String>,
+}
+
+impl AccessLists {
+    pub fn new() -> Self {
+        AccessLists::default()
+    }
+
+    /// Add the rules from the JSON array of `ListRule` in `path`, and
+    /// remember it for `add` and `remove`.
+    pub fn load(&mut self, path: &str) -> Result<(), ListError> {
+        let rules: Vec<ListRule> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        for rule in rules {
+            self.push(rule)?;
+        }
+        self.path = Some(path.to_string());
+
+        Ok(())
+    }
+
+    /// Whether changes are written to a file, rather than lost on restart.
+    pub fn is_persisted(&self) -> bool {
+        self.path.is_some()
+    }
+
+    fn push(&mut self, rule: ListRule) -> Result<u64, ListError> {
+        let matcher = rule.compile()?;
+        self.next_id += 1;
+        self.rules.push(StoredRule {
+            id: self.next_id,
+            rule,
+            matcher,
+        });
+
+        Ok(self.next_id)
+    }
+
+    /// Write the rules back to the file they were loaded from, if any.
+    fn save(&self) -> Result<(), ListError> {
+        if let Some(path) = &self.path {
+            let rules: Vec<&ListRule> = self.rules.iter().map(|stored| &stored.rule).collect();
+            fs::write(path, serde_json::to_string_pretty(&rules)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a rule, returning its id. The rule is not added if it can't be
+    /// saved.
+    pub fn add(&mut self, rule: ListRule) -> Result<u64, ListError> {
+        let id = self.push(rule)?;
+        if let Err(err) = self.save() {
+            self.rules.pop();
+            return Err(err);
+        }
+
+        Ok(id)
+    }
+
+    /// Remove the rule with this id. Returns false if there was none.
+    pub fn remove(&mut self, id: u64) -> Result<bool, ListError> {
+        let position = match self.rules.iter().position(|stored| stored.id == id) {
+            Some(position) => position,
+            None => return Ok(false),
+        };
+
+        let removed = self.rules.remove(position);
+        if let Err(err) = self.save() {
+            self.rules.insert(position, removed);
+            return Err(err);
+        }
+
+        Ok(true)
+    }
+
+    pub fn rules(&self) -> &[StoredRule] {
+        &self.rules
+    }
+
+    /// Find the strongest rule matching any of the `(target, value)` pairs.
+    pub fn check(&self, candidates: &[(Target, &str)]) -> Option<ListMatch> {
+        self.rules
+            .iter()
+            .filter(|stored| {
+                candidates.iter().any(|(target, value)| {
+                    stored.rule.target == *target
+                        && stored.matcher.is_match(value.trim_end_matches('.'))
+                })
+            })
+            .min_by_key(|stored| stored.rule.action)
+            .map(|stored| ListMatch {
+                id: stored.id,
+                action: stored.rule.action,
+                reason: stored
+                    .rule
+                    .reason
+                    .clone()
+                    .unwrap_or_else(|| stored.rule.pattern.clone()),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(target: Target, kind: MatchKind, pattern: &str, action: Action) -> ListRule {
+        ListRule {
+            target,
+            kind,
+            pattern: pattern.into(),
+            action,
+            reason: None,
+        }
+    }
+
+    fn lists(rules: Vec<ListRule>) -> AccessLists {
+        let mut lists = AccessLists::new();
+        for rule in rules {
+            lists.add(rule).unwrap();
+        }
+
+        lists
+    }
+
+    fn action(lists: &AccessLists, target: Target, value: &str) -> Option<Action> {
+        lists.check(&[(target, value)]).map(|found| found.action)
+    }
+
+    #[test]
+    fn exact_matches_whole_value_ignoring_case() {
+        let lists = lists(vec![rule(
+            Target::Domain,
+            MatchKind::Exact,
+            "example.com",
+            Action::Deny,
+        )]);
+
+        assert_eq!(
+            action(&lists, Target::Domain, "example.com"),
+            Some(Action::Deny)
+        );
+        assert_eq!(
+            action(&lists, Target::Domain, "EXAMPLE.com."),
+            Some(Action::Deny)
+        );
+        assert_eq!(action(&lists, Target::Domain, "sub.example.com"), None);
+        assert_eq!(action(&lists, Target::Domain, "examplexcom"), None);
+        assert_eq!(action(&lists, Target::MxHost, "example.com"), None);
+    }
+
+    #[test]
+    fn wildcard_matches_runs_and_single_characters() {
+        let lists = lists(vec![
+            rule(
+                Target::MxHost,
+                MatchKind::Wildcard,
+                "*.mx.example.com",
+                Action::SkipSmtp,
+            ),
+            rule(
+                Target::Address,
+                MatchKind::Wildcard,
+                "test?@example.com",
+                Action::Allow,
+            ),
+        ]);
+
+        assert_eq!(
+            action(&lists, Target::MxHost, "a.b.mx.example.com"),
+            Some(Action::SkipSmtp)
+        );
+        assert_eq!(action(&lists, Target::MxHost, "mx.example.com"), None);
+        assert_eq!(
+            action(&lists, Target::Address, "test1@example.com"),
+            Some(Action::Allow)
+        );
+        assert_eq!(action(&lists, Target::Address, "test12@example.com"), None);
+        // Only `*` and `?` are special, the dots are literal.
+        assert_eq!(action(&lists, Target::Address, "test1@exampleXcom"), None);
+    }
+
+    #[test]
+    fn regex_matches_as_written() {
+        let lists = lists(vec![rule(
+            Target::Address,
+            MatchKind::Regex,
+            r"^noreply\+.*@",
+            Action::Deny,
+        )]);
+
+        assert_eq!(
+            action(&lists, Target::Address, "NoReply+abc@example.com"),
+            Some(Action::Deny)
+        );
+        assert_eq!(action(&lists, Target::Address, "noreply@example.com"), None);
+    }
+
+    #[test]
+    fn invalid_regex_is_rejected() {
+        let mut lists = AccessLists::new();
+        let added = lists.add(rule(Target::Domain, MatchKind::Regex, "(", Action::Deny));
+
+        assert!(matches!(added, Err(ListError::RegexError(_))));
+        assert!(lists.rules().is_empty());
+    }
+
+    #[test]
+    fn deny_wins_over_allow_and_skip_smtp() {
+        let lists = lists(vec![
+            rule(
+                Target::Domain,
+                MatchKind::Exact,
+                "example.com",
+                Action::SkipSmtp,
+            ),
+            rule(
+                Target::Domain,
+                MatchKind::Exact,
+                "example.com",
+                Action::Allow,
+            ),
+            rule(
+                Target::Address,
+                MatchKind::Exact,
+                "bob@example.com",
+                Action::Deny,
+            ),
+        ]);
+        let candidates = [
+            (Target::Address, "bob@example.com"),
+            (Target::Domain, "example.com"),
+        ];
+
+        let found = lists.check(&candidates).unwrap();
+        assert_eq!(found.action, Action::Deny);
+        assert_eq!(found.id, 3);
+        assert_eq!(found.reason, "bob@example.com");
+        assert_eq!(
+            action(&lists, Target::Domain, "example.com"),
+            Some(Action::Allow)
+        );
+    }
+
+    #[test]
+    fn changes_are_saved_to_the_loaded_file() {
+        let path = std::env::temp_dir().join(format!("lists-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "[]").unwrap();
+
+        let mut lists = AccessLists::new();
+        assert!(!lists.is_persisted());
+        lists.load(path).unwrap();
+        assert!(lists.is_persisted());
+
+        let kept = lists
+            .add(rule(
+                Target::Domain,
+                MatchKind::Exact,
+                "kept.com",
+                Action::Deny,
+            ))
+            .unwrap();
+        let removed = lists
+            .add(rule(
+                Target::Domain,
+                MatchKind::Exact,
+                "removed.com",
+                Action::Deny,
+            ))
+            .unwrap();
+        assert!(lists.remove(removed).unwrap());
+        assert!(!lists.remove(removed).unwrap());
+
+        let mut reloaded = AccessLists::new();
+        reloaded.load(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(reloaded.rules().len(), 1);
+        assert_eq!(reloaded.rules()[0].rule.pattern, "kept.com");
+        assert_eq!(
+            action(&reloaded, Target::Domain, "kept.com"),
+            Some(Action::Deny)
+        );
+        assert_eq!(kept, 1);
+    }
+}
@@ -0,0 +1,34 @@
+// Log output for the server.
+//
+// Everything logs through `tracing`, and lines from the `log` macros are
+// forwarded to it, so every line carries the fields of the spans it happened
+// in: the request ID, the address and the attempt. Addresses go through
+// `pii` first.
+
+use std::error::Error;
+
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{LogConfig, LogFormat, PiiMode};
+
+/// Install the global subscriber. Fails if one is installed already, or the
+/// `log` macros already have a logger.
+pub fn init(config: &LogConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let filter = EnvFilter::try_new(&config.filter).unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match config.format {
+        LogFormat::Text => builder.try_init()?,
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init()?,
+    }
+
+    if config.pii == PiiMode::Hash && config.pii_key.is_none() {
+        tracing::warn!("No log.pii_key set, address hashes will change on restart");
+    }
+
+    Ok(())
+}
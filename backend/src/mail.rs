@@ -1,28 +1,37 @@
 // https://github.com/reacherhq/check-if-email-exist
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::hash::Hash;
-use std::time::Duration;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
 
-use async_recursion::async_recursion;
-use async_std_resolver::lookup::MxLookup;
-use async_std_resolver::{config, resolver, ResolveError};
 use cached::proc_macro::cached;
-use cached::SizedCache;
-use check_if_email_exists::misc::{check_misc, MiscDetails};
-use check_if_email_exists::mx::MxDetails;
+use cached::{Cached, SizedCache};
+use check_if_email_exists::misc::check_misc;
 use check_if_email_exists::syntax::check_syntax;
-use check_if_email_exists::CheckEmailInput;
+use check_if_email_exists::{CheckEmailInput, CheckEmailInputProxy};
+use futures::stream::{self, StreamExt};
 use log::debug;
-use rand::{
-    distributions::{Distribution, Standard},
-    Rng,
-};
 use serde::{Deserialize, Serialize};
-
-use crate::smtp::{check_smtp, SmtpDetails, SmtpError};
-
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+use tracing::{field, info, info_span, Instrument, Span};
+
+use crate::config::{self, Config, OverridesConfig};
+use crate::datasets;
+use crate::dns;
+use crate::lists::{self, Action, Target};
+use crate::metrics;
+use crate::mock;
+use crate::normalize::{normalize, normalize_domain, NormalizeOptions};
+use crate::pii;
+use crate::policy::Policy;
+use crate::score::ReachabilityScore;
+use crate::shutdown;
+use crate::smtp::{check_smtp, Provider};
+use crate::suggest::suggest_email;
+use crate::syntax::{self, Strictness, SyntaxBreakdown, SyntaxProblem};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum MyReachable {
     Safe,
     Risky,
@@ -43,31 +52,45 @@ impl fmt::Display for MyReachable {
     }
 }
 
-impl Distribution<MyReachable> for Standard {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> MyReachable {
-        match rng.gen_range(0..=5) {
-            0 => MyReachable::Invalid,
-            1 => MyReachable::Risky,
-            2 => MyReachable::Safe,
-            3 => MyReachable::Unknown,
-            4 => MyReachable::Banned,
-            _ => MyReachable::Safe,
-        }
-    }
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmailCheckResponse {
+    /// Coarse verdict, kept for backwards compatibility: the bucket `score`
+    /// falls in, see `ReachabilityScore::bucket`. `score`, `confidence` and
+    /// `reasons` carry the finer-grained picture.
     pub is_reachable: MyReachable,
+    /// The address exactly as it was submitted.
     pub email: String,
+    /// The address that was actually checked, see `normalize`.
+    pub normalized_email: Option<String>,
+    /// Everything before the last `@`.
+    pub local_part: Option<String>,
+    /// Everything after the last `@`.
+    pub domain: Option<String>,
+    /// Why the syntax is invalid, under the requested `Strictness`.
+    pub syntax_problem: Option<SyntaxProblem>,
     pub is_disposable: Option<bool>,
+    /// The disposable-domain list entry that matched.
+    pub disposable_match: Option<String>,
     pub is_role_account: Option<bool>,
+    /// The role-account list entry that matched.
+    pub role_match: Option<String>,
+    /// Is this a free or webmail provider such as gmail.com?
+    pub is_free_provider: Option<bool>,
     pub can_connect_smtp: Option<bool>,
     pub has_full_inbox: Option<bool>,
     pub is_catch_all: Option<bool>,
     pub is_deliverable: Option<bool>,
     pub is_disabled: Option<bool>,
     pub is_banned: Option<bool>,
+    pub provider: Option<Provider>,
+    /// Reachability from 0 to 100, see `ReachabilityScore`.
+    pub score: Option<u8>,
+    /// How much we trust `score`, from 0 to 100.
+    pub confidence: Option<u8>,
+    /// Human-readable explanation of `score`.
+    pub reasons: Vec<String>,
+    /// Corrected address if the domain looks like a typo of a popular one.
+    pub suggestion: Option<String>,
 }
 
 impl Default for EmailCheckResponse {
@@ -75,18 +98,47 @@ impl Default for EmailCheckResponse {
         EmailCheckResponse {
             is_reachable: MyReachable::Unknown,
             email: String::from("Unknown"),
+            normalized_email: None,
+            local_part: None,
+            domain: None,
+            syntax_problem: None,
             is_disposable: None,
+            disposable_match: None,
             is_role_account: None,
+            role_match: None,
+            is_free_provider: None,
             can_connect_smtp: None,
             has_full_inbox: None,
             is_catch_all: None,
             is_deliverable: None,
             is_disabled: None,
             is_banned: None,
+            provider: None,
+            score: None,
+            confidence: None,
+            reasons: vec![],
+            suggestion: None,
         }
     }
 }
 
+impl EmailCheckResponse {
+    fn with_score(mut self, score: ReachabilityScore) -> Self {
+        self.is_reachable = score.bucket();
+        self.score = Some(score.score);
+        self.confidence = Some(score.confidence);
+        self.reasons = score.reasons;
+        self
+    }
+
+    fn with_syntax(mut self, syntax: &SyntaxBreakdown) -> Self {
+        self.local_part = syntax.local_part.clone();
+        self.domain = syntax.domain.clone();
+        self.syntax_problem = syntax.problem;
+        self
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub struct EmailCheckInput {
     pub to_emails: Vec<String>,
@@ -98,6 +150,10 @@ pub struct EmailCheckInput {
     ///
     /// Defaults to "localhost" (note: "localhost" is not a FQDN).
     pub hello_name: String,
+    /// Port to connect to on the MX host.
+    ///
+    /// Defaults to 25.
+    pub smtp_port: u16,
     /// Add optional timeout for the SMTP verification step.
     pub smtp_timeout: Option<Duration>,
     /// For Yahoo email addresses, use Yahoo's API instead of connecting
@@ -105,6 +161,25 @@ pub struct EmailCheckInput {
     ///
     /// Defaults to true.
     pub yahoo_use_api: bool,
+    /// Policy mapping the check results to `is_reachable`.
+    ///
+    /// Defaults to the "default" profile.
+    pub policy: Policy,
+    /// How strictly to check the address syntax.
+    ///
+    /// Defaults to `Strictness::Rfc`.
+    pub syntax_strictness: Strictness,
+    /// SOCKS5 proxy for the SMTP connection and the Yahoo API.
+    pub proxy: Option<ProxyInput>,
+    /// Stop after the DNS stage, without connecting to the mail server.
+    ///
+    /// Defaults to false.
+    pub skip_smtp: bool,
+    /// Replace the DNS and SMTP stages with deterministic fake results, see
+    /// `mock`.
+    ///
+    /// Defaults to false.
+    pub mock: bool,
 }
 
 impl Default for EmailCheckInput {
@@ -113,8 +188,14 @@ impl Default for EmailCheckInput {
             to_emails: vec![],
             from_email: "user@example.org".into(),
             hello_name: "localhost".into(),
+            smtp_port: 25,
             smtp_timeout: None,
             yahoo_use_api: true,
+            policy: Policy::default(),
+            syntax_strictness: Strictness::default(),
+            proxy: None,
+            skip_smtp: false,
+            mock: false,
         }
     }
 }
@@ -128,6 +209,19 @@ impl EmailCheckInput {
         }
     }
 
+    /// Create an input with the SMTP settings of `config`.
+    pub fn from_config(config: &Config) -> EmailCheckInput {
+        EmailCheckInput {
+            from_email: config.smtp.from_email.clone(),
+            hello_name: config.smtp.hello_name.clone(),
+            smtp_port: config.smtp.port,
+            smtp_timeout: Some(config.smtp_timeout()),
+            yahoo_use_api: config.smtp.yahoo_use_api,
+            mock: config.mock.enabled,
+            ..Default::default()
+        }
+    }
+
     /// Set the email to use in the `MAIL FROM:` SMTP command. Defaults to
     /// `user@example.org` if not explicitly set.
     pub fn set_from_email(&mut self, email: String) -> &mut EmailCheckInput {
@@ -142,6 +236,12 @@ impl EmailCheckInput {
         self
     }
 
+    /// Set the port to connect to on the MX host. Defaults to 25.
+    pub fn set_smtp_port(&mut self, port: u16) -> &mut EmailCheckInput {
+        self.smtp_port = port;
+        self
+    }
+
     /// Add optional timeout for the SMTP verification step.
     pub fn set_smtp_timeout(&mut self, duration: Duration) -> &mut EmailCheckInput {
         self.smtp_timeout = Some(duration);
@@ -154,75 +254,299 @@ impl EmailCheckInput {
         self.yahoo_use_api = use_api;
         self
     }
+
+    /// Set the policy deciding `is_reachable`. Defaults to the "default"
+    /// profile.
+    pub fn set_policy(&mut self, policy: Policy) -> &mut EmailCheckInput {
+        self.policy = policy;
+        self
+    }
+
+    /// Set how strictly to check the address syntax. Defaults to
+    /// `Strictness::Rfc`.
+    pub fn set_syntax_strictness(&mut self, strictness: Strictness) -> &mut EmailCheckInput {
+        self.syntax_strictness = strictness;
+        self
+    }
+
+    /// Use a SOCKS5 proxy for the SMTP connection and the Yahoo API.
+    pub fn set_proxy(&mut self, proxy: ProxyInput) -> &mut EmailCheckInput {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Set whether to skip the SMTP stage. Defaults to false.
+    pub fn set_skip_smtp(&mut self, skip_smtp: bool) -> &mut EmailCheckInput {
+        self.skip_smtp = skip_smtp;
+        self
+    }
+
+    /// Set whether to use fake DNS and SMTP results. Defaults to false.
+    pub fn set_mock(&mut self, mock: bool) -> &mut EmailCheckInput {
+        self.mock = mock;
+        self
+    }
 }
 
-fn calculate_reachable(misc: &MiscDetails, smtp: &Result<SmtpDetails, SmtpError>) -> MyReachable {
-    if let Ok(smtp) = smtp {
-        if misc.is_disposable || misc.is_role_account || smtp.is_catch_all || smtp.has_full_inbox {
-            return MyReachable::Risky;
-        }
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub struct ProxyInput {
+    pub host: String,
+    pub port: u16,
+}
 
-        if smtp.is_banned {
-            return MyReachable::Banned;
-        }
+/// Per-request overrides of the check settings, as accepted by the HTTP API.
+/// Unset fields keep the server's config.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckOptions {
+    /// SMTP timeout in seconds, capped at `overrides.max_smtp_timeout`.
+    pub smtp_timeout: Option<u64>,
+    /// Attempts per address, capped at `overrides.max_retries`.
+    pub retries: Option<usize>,
+    pub yahoo_use_api: Option<bool>,
+    /// Only accepted if `overrides.allow_proxy` is set.
+    pub proxy: Option<ProxyInput>,
+    /// Only accepted if `overrides.allow_from_email` is set.
+    pub from_email: Option<String>,
+    pub skip_smtp: Option<bool>,
+}
+
+/// Possible errors when applying `CheckOptions`.
+#[derive(Debug)]
+pub enum CheckOptionsError {
+    /// The server doesn't let clients override this option.
+    NotAllowed(&'static str),
+    /// `from_email` is no address a mail server takes in `MAIL FROM`.
+    InvalidFromEmail(SyntaxProblem),
+}
+
+impl fmt::Display for CheckOptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
 
-        if !smtp.is_deliverable || !smtp.can_connect_smtp || smtp.is_disabled {
-            return MyReachable::Invalid;
+impl CheckOptions {
+    /// Apply the options to `input` and `batch`, capped by `limits`.
+    pub fn apply(
+        &self,
+        input: &mut EmailCheckInput,
+        batch: &mut BatchOptions,
+        limits: &OverridesConfig,
+    ) -> Result<(), CheckOptionsError> {
+        if let Some(smtp_timeout) = self.smtp_timeout {
+            let smtp_timeout = smtp_timeout.clamp(1, limits.max_smtp_timeout);
+            input.set_smtp_timeout(Duration::from_secs(smtp_timeout));
+        }
+        if let Some(retries) = self.retries {
+            batch.retries = retries.clamp(1, limits.max_retries);
+        }
+        if let Some(yahoo_use_api) = self.yahoo_use_api {
+            input.set_yahoo_use_api(yahoo_use_api);
+        }
+        if let Some(proxy) = &self.proxy {
+            if !limits.allow_proxy {
+                return Err(CheckOptionsError::NotAllowed("proxy"));
+            }
+            input.set_proxy(proxy.clone());
+        }
+        if let Some(from_email) = &self.from_email {
+            if !limits.allow_from_email {
+                return Err(CheckOptionsError::NotAllowed("from_email"));
+            }
+            // `MAIL FROM` is sent without SMTPUTF8, so ASCII only.
+            let problem = syntax::parse(from_email, Strictness::Provider)
+                .problem
+                .or_else(|| {
+                    Some(SyntaxProblem::InvalidCharacter).filter(|_| !from_email.is_ascii())
+                });
+            if let Some(problem) = problem {
+                return Err(CheckOptionsError::InvalidFromEmail(problem));
+            }
+            input.set_from_email(from_email.clone());
+        }
+        if let Some(skip_smtp) = self.skip_smtp {
+            input.set_skip_smtp(skip_smtp);
         }
 
-        MyReachable::Safe
-    } else {
-        MyReachable::Unknown
+        Ok(())
     }
 }
 
-#[async_recursion]
-pub async fn retry(input: EmailCheckInput, count: usize) -> EmailCheckResponse {
-    log::info!("[email={}] attempt #{}", input.to_emails[0], count,);
+/// How `check_batch` runs its checks.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct BatchOptions {
+    /// Attempts per address, see `retry`.
+    ///
+    /// Defaults to `check.retries` from the config.
+    pub retries: usize,
+    /// How many addresses are checked at the same time.
+    ///
+    /// Defaults to `check.concurrency` from the config.
+    pub concurrency: usize,
+}
 
-    let result = match check_single_email(input.clone()).await {
-        Ok(result) => result,
-        Err(result) => result,
-    };
+impl Default for BatchOptions {
+    fn default() -> Self {
+        let check = &config::get().check;
 
-    log::debug!(
-        "[email={}] Got result, attempt #{}, is_reachable={:?}",
-        input.to_emails[0],
-        count,
-        result.is_reachable
-    );
+        BatchOptions {
+            retries: check.retries,
+            concurrency: check.concurrency,
+        }
+    }
+}
+
+/// Check a batch of addresses. Addresses are normalized first, each distinct
+/// normalized address is checked once with `retry`, and its result is fanned
+/// back out to every input row that normalized to it.
+pub async fn check_batch(
+    emails: &[String],
+    template: &EmailCheckInput,
+    options: &NormalizeOptions,
+    batch: &BatchOptions,
+) -> Vec<EmailCheckResponse> {
+    check_batch_with_progress(emails, template, options, batch, |_, _| {}).await
+}
+
+/// Same as `check_batch`, calling `progress(checked, total)` each time a
+/// distinct address is done.
+pub async fn check_batch_with_progress<F>(
+    emails: &[String],
+    template: &EmailCheckInput,
+    options: &NormalizeOptions,
+    batch: &BatchOptions,
+    mut progress: F,
+) -> Vec<EmailCheckResponse>
+where
+    F: FnMut(usize, usize),
+{
+    let normalized: Vec<String> = emails
+        .iter()
+        .map(|email| normalize(email, options))
+        .collect();
+
+    let mut unique: Vec<&String> = Vec::new();
+    let mut seen = HashSet::new();
+    for email in &normalized {
+        if seen.insert(email) {
+            unique.push(email);
+        }
+    }
+
+    let total = unique.len();
+    let pending = shutdown::Batch::start(&unique);
+    let mut checks = stream::iter(unique.into_iter().map(|email| {
+        let input = EmailCheckInput {
+            to_emails: vec![email.to_string()],
+            ..template.clone()
+        };
+        async move { (email, retry(input, batch.retries).await) }
+    }))
+    .buffer_unordered(batch.concurrency.max(1));
+
+    let mut results: HashMap<&String, EmailCheckResponse> = HashMap::new();
+    while let Some((email, result)) = checks.next().await {
+        // An Unknown after the drain deadline may be a check cut short.
+        if !(shutdown::is_cut_short() && result.is_reachable == MyReachable::Unknown) {
+            pending.done(email);
+        }
+        results.insert(email, result);
+        progress(results.len(), total);
+    }
+
+    emails
+        .iter()
+        .zip(normalized.iter())
+        .map(|(email, normalized)| EmailCheckResponse {
+            email: email.to_string(),
+            normalized_email: Some(normalized.to_string()),
+            ..results[normalized].clone()
+        })
+        .collect()
+}
 
-    if result.is_reachable == MyReachable::Unknown {
-        if count <= 1 {
+/// Check an address, up to `count` times while the verdict is Unknown.
+pub async fn retry(input: EmailCheckInput, count: usize) -> EmailCheckResponse {
+    let span = info_span!("check", email = %pii::email(&input.to_emails[0]));
+
+    async move {
+        let mut attempt = 1;
+        loop {
+            if shutdown::is_cut_short() {
+                return EmailCheckResponse {
+                    email: input.to_emails[0].clone(),
+                    ..Default::default()
+                }
+                .with_score(ReachabilityScore::shutting_down());
+            }
+
+            let span = info_span!(
+                "attempt",
+                attempt,
+                mx = field::Empty,
+                port = field::Empty,
+                outcome = field::Empty
+            );
+            span.in_scope(|| info!("checking"));
+
+            let start = Instant::now();
+            let result = match check_single_email(input.clone())
+                .instrument(span.clone())
+                .await
+            {
+                Ok(result) => result,
+                Err(result) => result,
+            };
+            metrics::observe_check(start);
+
+            span.record("outcome", &field::display(result.is_reachable));
+            span.in_scope(|| info!(score = ?result.score, "checked"));
+
+            if result.is_reachable == MyReachable::Unknown && attempt < count {
+                metrics::record_retry();
+                attempt += 1;
+                continue;
+            }
+
+            metrics::record_verdict(result.is_reachable);
             return result;
-        } else {
-            retry(input, count - 1).await
         }
-    } else {
-        return result;
     }
+    .instrument(span)
+    .await
 }
 
-#[cached(result = true)]
-pub async fn check_mx_domain(domain: String) -> Result<MxLookup, ResolveError> {
-    let resolver = resolver(
-        config::ResolverConfig::default(),
-        config::ResolverOpts::default(),
+/// Cache hits and misses of `check_single_email` since startup.
+pub async fn cache_stats() -> (u64, u64) {
+    let cache = CHECK_SINGLE_EMAIL.lock().await;
+
+    (
+        cache.cache_hits().unwrap_or(0),
+        cache.cache_misses().unwrap_or(0),
     )
-    .await?;
+}
 
-    match resolver.mx_lookup(domain.as_ref()).await {
-        Ok(lookup) => Ok(lookup),
-        Err(err) => Err(err),
-    }
+/// Drop every cached check result, e.g. after the allow and deny lists
+/// changed.
+pub async fn clear_cache() {
+    CHECK_SINGLE_EMAIL.lock().await.cache_clear();
+}
+
+/// Every field of the input can change the result, so the cache key covers
+/// all of them.
+fn cache_key(input: &EmailCheckInput) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    input.hash(&mut hasher);
+    hasher.finish()
 }
 
 // Return a `Result` here simply to bust cache, it will always have the expected value
 //  but an `Err` means it won't be cached, while `Ok` means it will be cached
 #[cached(
-    type = "SizedCache<String, EmailCheckResponse>",
-    create = "{ SizedCache::with_size(100) }",
-    convert = r#"{ input.to_emails[0].to_owned() }"#,
+    type = "SizedCache<u64, EmailCheckResponse>",
+    create = "{ SizedCache::with_size(config::get().cache.size) }",
+    convert = r#"{ cache_key(&input) }"#,
     result = true
 )]
 pub async fn check_single_email(
@@ -231,92 +555,233 @@ pub async fn check_single_email(
     let ciee_input = CheckEmailInput {
         from_email: input.from_email.clone(),
         hello_name: input.hello_name.clone(),
-        proxy: None,
-        smtp_timeout: input.smtp_timeout.clone(),
+        proxy: input.proxy.as_ref().map(|proxy| CheckEmailInputProxy {
+            host: proxy.host.clone(),
+            port: proxy.port,
+        }),
+        smtp_timeout: input.smtp_timeout,
         to_emails: input.to_emails.clone(),
-        yahoo_use_api: input.yahoo_use_api.clone(),
+        yahoo_use_api: input.yahoo_use_api,
     };
 
     let to_email = &input.to_emails[0];
 
-    let my_syntax = check_syntax(to_email.as_ref());
+    let my_breakdown = syntax::parse(to_email, input.syntax_strictness);
+    if let Some(problem) = my_breakdown.problem {
+        return Ok(EmailCheckResponse {
+            email: to_email.to_string(),
+            ..Default::default()
+        }
+        .with_syntax(&my_breakdown)
+        .with_score(ReachabilityScore::invalid_syntax(problem)));
+    }
+
+    // `check_syntax` only understands unquoted ASCII addresses, so also run
+    // it on an ASCII stand-in: the domain in punycode, and the characters it
+    // doesn't know about in the local part replaced by an `x`.
+    let local = my_breakdown
+        .local_part
+        .as_deref()
+        .expect("Syntax has no problem, so it has a local part. qed.");
+    let domain = normalize_domain(
+        my_breakdown
+            .domain
+            .as_deref()
+            .expect("Syntax has no problem, so it has a domain. qed."),
+    );
+    let quoted = local.starts_with('"');
+    let ascii_local: String = local
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || (c.is_ascii() && !quoted) {
+                c
+            } else {
+                'x'
+            }
+        })
+        .collect();
+
+    let mut my_syntax = check_syntax(&format!("{}@{}", ascii_local, domain));
     if !my_syntax.is_valid_syntax {
+        let mut result = EmailCheckResponse {
+            email: to_email.to_string(),
+            ..Default::default()
+        }
+        .with_syntax(&my_breakdown)
+        .with_score(ReachabilityScore::invalid_syntax(SyntaxProblem::Rejected));
+        result.syntax_problem = Some(SyntaxProblem::Rejected);
+
+        return Ok(result);
+    }
+
+    // Keep the real local part for the role-account check and `RCPT TO`.
+    my_syntax.username = local.to_string();
+    let rcpt_email = format!("{}@{}", local, my_syntax.domain);
+
+    debug!("Syntax of {} is valid", pii::email(&rcpt_email));
+
+    // Computed before the DNS stage, so addresses on domains that don't
+    // resolve get one. Dropped below if the domain has MX records.
+    let suggestion = suggest_email(to_email);
+
+    let list_match = lists::global()
+        .read()
+        .expect("Lists lock is never poisoned. qed.")
+        .check(&[
+            (Target::Address, to_email.as_str()),
+            (Target::Domain, my_syntax.domain.as_str()),
+        ])
+        .filter(|list_match| list_match.action != Action::SkipSmtp);
+
+    if let Some(list_match) = list_match {
         return Ok(EmailCheckResponse {
             email: to_email.to_string(),
-            is_reachable: MyReachable::Invalid,
+            suggestion,
             ..Default::default()
-        });
+        }
+        .with_syntax(&my_breakdown)
+        .with_score(ReachabilityScore::listed(
+            list_match.action,
+            &list_match.to_string(),
+        )));
     }
 
-    debug!("{:?}", my_syntax);
+    let mx_host = if input.mock {
+        mock::mx_host(&my_syntax.domain)
+    } else {
+        let start = Instant::now();
+        let my_mx = dns::resolver().mx_hosts(&my_syntax.domain).await;
+        metrics::observe_dns(start);
+
+        let my_mx = match my_mx {
+            Ok(hosts) => hosts,
+            Err(err) => {
+                debug!("{:?}", err);
+
+                return Err(EmailCheckResponse {
+                    email: to_email.to_string(),
+                    suggestion,
+                    ..Default::default()
+                }
+                .with_syntax(&my_breakdown)
+                .with_score(ReachabilityScore::dns_error()));
+            }
+        };
+
+        debug!("{:?}", my_mx);
+
+        my_mx.into_iter().next()
+    };
+    if let Some(mx_host) = &mx_host {
+        Span::current()
+            .record("mx", &field::display(mx_host))
+            .record("port", &input.smtp_port);
+    }
 
-    let my_mx = match check_mx_domain(my_syntax.domain.clone()).await {
-        Ok(m) => MxDetails::from(m),
-        _e => {
+    let mx_host = match mx_host {
+        Some(mx_host) => mx_host,
+        None => {
             return Err(EmailCheckResponse {
                 email: to_email.to_string(),
-                is_reachable: MyReachable::Unknown,
+                suggestion,
                 ..Default::default()
-            });
+            }
+            .with_syntax(&my_breakdown)
+            .with_score(ReachabilityScore::no_mx()));
         }
     };
 
-    debug!("{:?}", my_mx);
+    // A domain that accepts mail is real, however close it is to a popular
+    // one, like life.com to live.com.
+    let suggestion: Option<String> = None;
 
-    if my_mx.lookup.is_err() {
-        return Err(EmailCheckResponse {
+    let my_datasets = datasets::global()
+        .read()
+        .expect("Datasets lock is never poisoned. qed.")
+        .check(&my_syntax, &check_misc(&my_syntax));
+    let my_misc = my_datasets.misc();
+
+    debug!("{:?}", my_misc);
+
+    // Check the lists again now that we know the MX host. Address and domain
+    // rules can only be `SkipSmtp` at this point.
+    let list_match = lists::global()
+        .read()
+        .expect("Lists lock is never poisoned. qed.")
+        .check(&[
+            (Target::Address, to_email.as_str()),
+            (Target::Domain, my_syntax.domain.as_str()),
+            (Target::MxHost, mx_host.to_utf8().as_str()),
+        ]);
+
+    let skip = match list_match {
+        Some(list_match) => Some((list_match.action, list_match.to_string())),
+        None if input.skip_smtp => Some((
+            Action::SkipSmtp,
+            String::from("SMTP check skipped by request"),
+        )),
+        None => None,
+    };
+
+    if let Some((action, reason)) = skip {
+        return Ok(EmailCheckResponse {
             email: to_email.to_string(),
-            is_reachable: MyReachable::Invalid,
+            is_disposable: Some(my_misc.is_disposable),
+            is_role_account: Some(my_misc.is_role_account),
+            disposable_match: my_datasets.disposable_match,
+            role_match: my_datasets.role_match,
+            is_free_provider: Some(my_datasets.is_free_provider),
+            suggestion,
             ..Default::default()
-        });
+        }
+        .with_syntax(&my_breakdown)
+        .with_score(ReachabilityScore::listed(action, &reason)));
     }
 
-    let my_misc = check_misc(&my_syntax);
-
-    debug!("{:?}", my_misc);
-
-    let my_smtp = my_mx
-        .lookup
-        .as_ref()
-        .expect("If lookup is error, we already returned. qed.")
-        .iter()
-        .next()
-        .map(|host| {
-            check_smtp(
-                my_syntax
-                    .address
-                    .as_ref()
-                    .expect("We already checked that the email has valid format. qed."),
-                host.exchange(),
-                // FIXME We could add ports 465 and 587 too.
-                25,
-                my_syntax.domain.as_ref(),
-                &ciee_input,
-            )
-        })
-        .expect("Lookup cannot be empty. qed.")
-        .await;
+    let my_smtp = if input.mock {
+        mock::check_smtp(&rcpt_email, &my_syntax.domain).await
+    } else {
+        check_smtp(
+            &rcpt_email,
+            &mx_host,
+            // FIXME We could add ports 465 and 587 too.
+            input.smtp_port,
+            my_syntax.domain.as_ref(),
+            &ciee_input,
+        )
+        .await
+    };
 
-    debug!("{:?}", my_smtp);
+    debug!("{}", pii::text(&format!("{:?}", my_smtp)));
+    metrics::record_smtp(&my_smtp);
 
+    let verdict = input.policy.evaluate(&my_misc, &my_smtp);
     let mut result = EmailCheckResponse {
         email: to_email.to_string(),
-        is_reachable: calculate_reachable(&my_misc, &my_smtp),
         is_disposable: Some(my_misc.is_disposable),
         is_role_account: Some(my_misc.is_role_account),
+        disposable_match: my_datasets.disposable_match,
+        role_match: my_datasets.role_match,
+        is_free_provider: Some(my_datasets.is_free_provider),
+        suggestion,
         ..Default::default()
-    };
+    }
+    .with_syntax(&my_breakdown)
+    .with_score(
+        ReachabilityScore::from_checks(&my_misc, &my_smtp, &mx_host.to_utf8()).in_bucket(
+            verdict,
+            &format!("{} policy rates the address {}", input.policy.name, verdict),
+        ),
+    );
 
-    match my_smtp {
-        Ok(smtp) => {
-            result.has_full_inbox = Some(smtp.has_full_inbox);
-            result.is_catch_all = Some(smtp.is_catch_all);
-            result.is_deliverable = Some(smtp.is_deliverable);
-            result.is_disabled = Some(smtp.is_disabled);
-            result.can_connect_smtp = Some(smtp.can_connect_smtp);
-            result.is_banned = Some(smtp.is_banned);
-        }
-        _ => {}
+    if let Ok(smtp) = my_smtp {
+        result.has_full_inbox = Some(smtp.has_full_inbox);
+        result.is_catch_all = Some(smtp.is_catch_all);
+        result.is_deliverable = Some(smtp.is_deliverable);
+        result.is_disabled = Some(smtp.is_disabled);
+        result.can_connect_smtp = Some(smtp.can_connect_smtp);
+        result.is_banned = Some(smtp.is_banned);
+        result.provider = Some(smtp.provider);
     }
 
     if result.is_reachable == MyReachable::Unknown
@@ -328,3 +793,155 @@ pub async fn check_single_email(
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> OverridesConfig {
+        OverridesConfig {
+            max_smtp_timeout: 30,
+            max_retries: 3,
+            allow_proxy: true,
+            allow_from_email: true,
+        }
+    }
+
+    fn apply(
+        options: CheckOptions,
+        limits: &OverridesConfig,
+    ) -> Result<(EmailCheckInput, BatchOptions), CheckOptionsError> {
+        let mut input = EmailCheckInput::default();
+        let mut batch = BatchOptions {
+            retries: 2,
+            concurrency: 4,
+        };
+        options.apply(&mut input, &mut batch, limits)?;
+
+        Ok((input, batch))
+    }
+
+    #[test]
+    fn no_options_change_nothing() {
+        let (input, batch) = apply(CheckOptions::default(), &limits()).unwrap();
+        assert_eq!(input, EmailCheckInput::default());
+        assert_eq!(
+            batch,
+            BatchOptions {
+                retries: 2,
+                concurrency: 4
+            }
+        );
+    }
+
+    #[test]
+    fn smtp_timeout_is_capped() {
+        let smtp_timeout = |seconds| {
+            let options = CheckOptions {
+                smtp_timeout: Some(seconds),
+                ..Default::default()
+            };
+            apply(options, &limits()).unwrap().0.smtp_timeout
+        };
+
+        assert_eq!(smtp_timeout(12), Some(Duration::from_secs(12)));
+        assert_eq!(smtp_timeout(0), Some(Duration::from_secs(1)));
+        assert_eq!(smtp_timeout(3600), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn retries_are_capped() {
+        let retries = |retries| {
+            let options = CheckOptions {
+                retries: Some(retries),
+                ..Default::default()
+            };
+            apply(options, &limits()).unwrap().1.retries
+        };
+
+        assert_eq!(retries(2), 2);
+        assert_eq!(retries(0), 1);
+        assert_eq!(retries(100), 3);
+    }
+
+    #[test]
+    fn yahoo_use_api_and_skip_smtp() {
+        let options = CheckOptions {
+            yahoo_use_api: Some(false),
+            skip_smtp: Some(true),
+            ..Default::default()
+        };
+        let (input, _) = apply(options, &limits()).unwrap();
+
+        assert!(!input.yahoo_use_api);
+        assert!(input.skip_smtp);
+    }
+
+    #[test]
+    fn proxy_only_if_allowed() {
+        let proxy = ProxyInput {
+            host: "proxy.example.com".into(),
+            port: 1080,
+        };
+        let options = CheckOptions {
+            proxy: Some(proxy.clone()),
+            ..Default::default()
+        };
+
+        let (input, _) = apply(options.clone(), &limits()).unwrap();
+        assert_eq!(input.proxy, Some(proxy));
+
+        let limits = OverridesConfig {
+            allow_proxy: false,
+            ..limits()
+        };
+        assert!(matches!(
+            apply(options, &limits),
+            Err(CheckOptionsError::NotAllowed("proxy"))
+        ));
+    }
+
+    #[test]
+    fn from_email_only_if_allowed_and_valid() {
+        let from_email = |from_email: &str, limits: &OverridesConfig| {
+            let options = CheckOptions {
+                from_email: Some(from_email.into()),
+                ..Default::default()
+            };
+            apply(options, limits).map(|(input, _)| input.from_email)
+        };
+
+        assert_eq!(
+            from_email("bounces@example.com", &limits()).unwrap(),
+            "bounces@example.com"
+        );
+        assert!(matches!(
+            from_email("bounces@example.com", &OverridesConfig::default()),
+            Err(CheckOptionsError::NotAllowed("from_email"))
+        ));
+        assert!(matches!(
+            from_email("bounces", &limits()),
+            Err(CheckOptionsError::InvalidFromEmail(
+                SyntaxProblem::MissingAt
+            ))
+        ));
+        assert!(matches!(
+            from_email("@example.com", &limits()),
+            Err(CheckOptionsError::InvalidFromEmail(
+                SyntaxProblem::EmptyLocalPart
+            ))
+        ));
+        assert!(matches!(
+            from_email("bounces@localhost", &limits()),
+            Err(CheckOptionsError::InvalidFromEmail(
+                SyntaxProblem::InvalidTld
+            ))
+        ));
+        assert!(matches!(
+            from_email("jürgen@example.com", &limits()),
+            Err(CheckOptionsError::InvalidFromEmail(
+                SyntaxProblem::InvalidCharacter
+            ))
+        ));
+    }
+}
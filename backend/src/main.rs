@@ -1,25 +1,81 @@
 // https://github.com/reacherhq/check-if-email-exist
 
+use actix_multipart::Multipart;
+use actix_web::dev::{Service, ServiceRequest};
+use actix_web::http::header::{HeaderName, HeaderValue};
 use actix_web::{
     error, get, middleware, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
-use extant::mail::{retry, EmailCheckInput, EmailCheckResponse, MyReachable};
-use futures::{executor, future};
-use log::info;
+use extant::admin;
+use extant::auth;
+use extant::config::{self, Config, SHUTDOWN_GRACE_SECS};
+use extant::errors::ApiError;
+use extant::export::{ExportFormat, Table};
+use extant::health;
+use extant::logging;
+use extant::mail::{
+    check_batch, BatchOptions, CheckOptions, EmailCheckInput, EmailCheckResponse, MyReachable,
+};
+use extant::normalize::NormalizeOptions;
+use extant::policy::{Policies, DEFAULT_PROFILE};
+use extant::shutdown;
+use extant::syntax::Strictness;
+use futures::future::{self, Either};
+use futures::TryStreamExt;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::env;
-use std::time::Duration;
-use std::{sync::mpsc, thread};
+use serde_json::Value;
+use std::io;
+use tracing::{info_span, Instrument};
+
+/// Header asking for fake DNS and SMTP results, see `extant::mock`.
+const MOCK_HEADER: &str = "X-Extant-Mock";
+
+/// Scope of the check endpoints, which need an API key when keys are
+/// configured. See `extant::auth`.
+const AUTHENTICATED_PATH: &str = "/api/email_check";
+
+/// Longest entry accepted in a batch. Shorter entries still too long for an
+/// address get an Invalid verdict from the syntax check instead.
+const MAX_ENTRY_LENGTH: usize = 1024;
+
+/// Largest CSV accepted by `/api/email_check/csv`.
+const MAX_CSV_BYTES: usize = 10 * 1024 * 1024;
+
+/// Header carrying the request ID, taken from the request if it has a
+/// usable one and echoed in the response.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The caller's request ID, or a new random one.
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 64
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        })
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()))
+}
+
 fn json_error_handler(err: error::JsonPayloadError, _req: &HttpRequest) -> error::Error {
     use actix_web::error::JsonPayloadError;
 
     let detail = err.to_string();
     let resp = match &err {
-        JsonPayloadError::ContentType => HttpResponse::UnsupportedMediaType().body(detail),
+        JsonPayloadError::ContentType => HttpResponse::UnsupportedMediaType()
+            .json(ApiError::new("unsupported_media_type", detail)),
         JsonPayloadError::Deserialize(json_err) if json_err.is_data() => {
-            HttpResponse::UnprocessableEntity().body(detail)
+            HttpResponse::UnprocessableEntity().json(ApiError::new("invalid_body", detail))
         }
-        _ => HttpResponse::BadRequest().body(detail),
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => {
+            HttpResponse::PayloadTooLarge().json(ApiError::new("payload_too_large", detail))
+        }
+        _ => HttpResponse::BadRequest().json(ApiError::new("invalid_json", detail)),
     };
     error::InternalError::from_response(err, resp).into()
 }
@@ -32,10 +88,20 @@ struct Stats {
     unknown: i32,
     banned: i32,
     total: i32,
+    /// Addresses on a free or webmail provider, whatever their verdict.
+    free_provider: i32,
 }
 
 impl Stats {
-    fn new(risky: i32, safe: i32, invalid: i32, unknown: i32, banned: i32, total: i32) -> Stats {
+    fn new(
+        risky: i32,
+        safe: i32,
+        invalid: i32,
+        unknown: i32,
+        banned: i32,
+        total: i32,
+        free_provider: i32,
+    ) -> Stats {
         Stats {
             risky,
             safe,
@@ -43,6 +109,7 @@ impl Stats {
             unknown,
             banned,
             total,
+            free_provider,
         }
     }
 }
@@ -53,41 +120,218 @@ struct ResponseData {
     stats: Stats,
 }
 
-#[post("/api/email_check")]
-async fn email_check(emails: web::Json<Vec<String>>) -> impl Responder {
-    let items: Vec<EmailCheckResponse>;
+#[derive(Deserialize)]
+struct EmailCheckQuery {
+    /// Name of the verdict policy to apply, see `Policies`.
+    profile: Option<String>,
+    /// Apply provider-aware dot and plus-tag rules when normalizing, off
+    /// unless set.
+    provider_rules: Option<bool>,
+    /// Syntax strictness, `rfc` or `provider`.
+    syntax: Option<Strictness>,
+}
 
-    if emails[0] == "test@test.com" {
-        let mut test_inputs: Vec<EmailCheckResponse> = Vec::new();
-        for _ in 1..2000 {
-            let random_status: MyReachable = rand::random();
-            test_inputs.push(EmailCheckResponse {
-                is_reachable: random_status,
-                ..Default::default()
-            })
+#[derive(Deserialize)]
+struct CsvCheckQuery {
+    /// Header of the column holding the addresses, see `Table::email_column`.
+    column: Option<String>,
+    /// `csv` or `xlsx`, defaults to `csv`.
+    format: Option<ExportFormat>,
+    profile: Option<String>,
+    provider_rules: Option<bool>,
+    syntax: Option<Strictness>,
+}
+
+/// The input shared by every address of a request, or a 400 response if the
+/// profile is unknown and a 403 if it asks for mock results it may not have.
+fn check_input(
+    req: &HttpRequest,
+    policies: &Policies,
+    profile: Option<&str>,
+    syntax: Option<Strictness>,
+) -> Result<EmailCheckInput, HttpResponse> {
+    let profile = profile.unwrap_or(DEFAULT_PROFILE);
+    let policy = match policies.get(profile) {
+        Some(policy) => policy.clone(),
+        None => {
+            return Err(HttpResponse::BadRequest().json(ApiError::new(
+                "unknown_profile",
+                format!("Unknown profile \"{}\"", profile),
+            )));
         }
+    };
 
-        items = test_inputs;
-    } else {
-        let hostname = match gethostname::gethostname().into_string() {
-            Ok(hostname) => hostname,
-            _ => String::from("localhost"),
-        };
-        let inputs = emails.iter().map(|email| EmailCheckInput {
-            to_emails: vec![email.to_string()],
-            from_email: env::var("FROM_EMAIL").unwrap_or("user@example.com".to_string()),
-            hello_name: env::var("HELLO_NAME").unwrap_or(hostname.to_owned()),
-            smtp_timeout: Some(Duration::from_secs(10)),
-            ..Default::default()
-        });
+    let mut input = EmailCheckInput {
+        policy,
+        syntax_strictness: syntax.unwrap_or_default(),
+        ..EmailCheckInput::from_config(config::get())
+    };
+
+    let wants_mock = req
+        .headers()
+        .get(MOCK_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+    if wants_mock {
+        if !config::get().mock.allow_header {
+            return Err(HttpResponse::Forbidden().json(ApiError::new(
+                "mock_not_allowed",
+                format!("The {} header is disabled on this server", MOCK_HEADER),
+            )));
+        }
+        input.set_mock(true);
+    }
+
+    Ok(input)
+}
+
+/// Body of `/api/email_check`: either a plain array of addresses, or an
+/// object with the addresses and per-request `CheckOptions`. The entries are
+/// checked by `batch_emails` and the options by `check_options`, to refuse
+/// the bad ones with a clear error rather than serde's "did not match any
+/// variant".
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EmailCheckRequest {
+    Emails(Vec<Value>),
+    WithOptions {
+        emails: Vec<Value>,
+        #[serde(default)]
+        options: Value,
+    },
+}
+
+/// The `CheckOptions` of a request, or the 400 response naming the field
+/// that's wrong.
+fn check_options(options: Value) -> Result<CheckOptions, HttpResponse> {
+    if options.is_null() {
+        return Ok(CheckOptions::default());
+    }
+
+    serde_json::from_value(options)
+        .map_err(|err| HttpResponse::BadRequest().json(ApiError::new("invalid_options", err)))
+}
+
+fn json_kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Largest JSON body accepted, enough for `check.max_batch_size` entries of
+/// `MAX_ENTRY_LENGTH` with their quotes and commas, plus the options.
+fn max_json_bytes(max_batch_size: usize) -> usize {
+    max_batch_size
+        .saturating_mul(MAX_ENTRY_LENGTH + 4)
+        .saturating_add(64 * 1024)
+}
+
+/// The 413 response if a request has more than `check.max_batch_size`
+/// addresses.
+fn check_batch_size(addresses: usize) -> Result<(), HttpResponse> {
+    let max_batch_size = config::get().check.max_batch_size;
+    if addresses > max_batch_size {
+        return Err(HttpResponse::PayloadTooLarge().json(ApiError::new(
+            "batch_too_large",
+            format!(
+                "{} addresses, at most {} are checked per request",
+                addresses, max_batch_size
+            ),
+        )));
+    }
+
+    Ok(())
+}
+
+/// The addresses of a batch, or the 400 or 413 response refusing it.
+fn batch_emails(entries: Vec<Value>) -> Result<Vec<String>, HttpResponse> {
+    if entries.is_empty() {
+        return Err(
+            HttpResponse::BadRequest().json(ApiError::new("empty_batch", "No addresses to check"))
+        );
+    }
+    check_batch_size(entries.len())?;
+
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(position, entry)| match entry {
+            Value::String(email) if email.len() > MAX_ENTRY_LENGTH => {
+                Err(HttpResponse::BadRequest().json(
+                    ApiError::new(
+                        "entry_too_long",
+                        format!(
+                            "Entry {} is longer than {} bytes",
+                            position, MAX_ENTRY_LENGTH
+                        ),
+                    )
+                    .at(position),
+                ))
+            }
+            Value::String(email) => Ok(email),
+            other => Err(HttpResponse::BadRequest().json(
+                ApiError::new(
+                    "not_a_string",
+                    format!("Entry {} is {}, not a string", position, json_kind(&other)),
+                )
+                .at(position),
+            )),
+        })
+        .collect()
+}
+
+/// `POST /api/email_check`, in the `AUTHENTICATED_PATH` scope.
+#[post("")]
+async fn email_check(
+    req: HttpRequest,
+    request: web::Json<EmailCheckRequest>,
+    query: web::Query<EmailCheckQuery>,
+    policies: web::Data<Policies>,
+) -> impl Responder {
+    let (entries, options) = match request.into_inner() {
+        EmailCheckRequest::Emails(entries) => (entries, Value::Null),
+        EmailCheckRequest::WithOptions { emails, options } => (emails, options),
+    };
+    let options = match check_options(options) {
+        Ok(options) => options,
+        Err(resp) => return resp,
+    };
+    let emails = match batch_emails(entries) {
+        Ok(emails) => emails,
+        Err(resp) => return resp,
+    };
+
+    let mut input = match check_input(&req, &policies, query.profile.as_deref(), query.syntax) {
+        Ok(input) => input,
+        Err(resp) => return resp,
+    };
+    let mut batch = BatchOptions::default();
+    if let Err(err) = options.apply(&mut input, &mut batch, &config::get().overrides) {
+        return HttpResponse::BadRequest().json(ApiError::new("invalid_options", err));
+    }
 
-        items = future::join_all(inputs.map(|i| retry(i, 2))).await;
+    if let Err(resp) = auth::charge(&req, emails.len()) {
+        return resp;
     }
 
-    let mut stats = Stats::new(0, 0, 0, 0, 0, 0);
+    let options = NormalizeOptions {
+        provider_rules: query.provider_rules.unwrap_or(false),
+    };
+    let items = check_batch(&emails, &input, &options, &batch).await;
+
+    let mut stats = Stats::new(0, 0, 0, 0, 0, 0, 0);
 
     items.iter().for_each(|item| {
         stats.total += 1;
+        if item.is_free_provider == Some(true) {
+            stats.free_provider += 1;
+        }
         match item.is_reachable {
             MyReachable::Invalid => stats.invalid += 1,
             MyReachable::Risky => stats.risky += 1,
@@ -100,39 +344,264 @@ async fn email_check(emails: web::Json<Vec<String>>) -> impl Responder {
     HttpResponse::Ok().json(ResponseData { items, stats })
 }
 
+/// Read the first part of a multipart upload.
+async fn read_upload(mut payload: Multipart) -> Result<Vec<u8>, HttpResponse> {
+    let bad_request = |err: actix_multipart::MultipartError| {
+        HttpResponse::BadRequest().json(ApiError::new("invalid_upload", err))
+    };
+
+    let mut field = match payload.try_next().await.map_err(bad_request)? {
+        Some(field) => field,
+        None => {
+            return Err(
+                HttpResponse::BadRequest().json(ApiError::new("missing_file", "No file uploaded"))
+            );
+        }
+    };
+
+    let mut data = Vec::new();
+    while let Some(chunk) = field.try_next().await.map_err(bad_request)? {
+        if data.len() + chunk.len() > MAX_CSV_BYTES {
+            return Err(HttpResponse::PayloadTooLarge().json(ApiError::new(
+                "payload_too_large",
+                format!("CSV is larger than {} bytes", MAX_CSV_BYTES),
+            )));
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(data)
+}
+
+/// Check the addresses in one column of an uploaded CSV, and return the
+/// file with the result columns appended. `POST /api/email_check/csv`, in
+/// the `AUTHENTICATED_PATH` scope.
+#[post("/csv")]
+async fn email_check_csv(
+    req: HttpRequest,
+    payload: Multipart,
+    query: web::Query<CsvCheckQuery>,
+    policies: web::Data<Policies>,
+) -> impl Responder {
+    let input = match check_input(&req, &policies, query.profile.as_deref(), query.syntax) {
+        Ok(input) => input,
+        Err(resp) => return resp,
+    };
+
+    let data = match read_upload(payload).await {
+        Ok(data) => data,
+        Err(resp) => return resp,
+    };
+
+    let table = match Table::from_csv(data.as_slice()) {
+        Ok(table) => table,
+        Err(err) => {
+            return HttpResponse::BadRequest().json(ApiError::new(
+                "invalid_csv",
+                format!("Invalid CSV: {}", err),
+            ));
+        }
+    };
+
+    let column = match table.email_column(query.column.as_deref()) {
+        Some(column) => column,
+        None => {
+            return HttpResponse::BadRequest().json(ApiError::new(
+                "unknown_column",
+                format!(
+                    "No column \"{}\" in the CSV header",
+                    query.column.as_deref().unwrap_or("")
+                ),
+            ));
+        }
+    };
+
+    let emails = table.column(column);
+    if let Err(resp) = check_batch_size(emails.len()) {
+        return resp;
+    }
+    if let Err(resp) = auth::charge(&req, emails.len()) {
+        return resp;
+    }
+
+    let options = NormalizeOptions {
+        provider_rules: query.provider_rules.unwrap_or(false),
+    };
+    let results = check_batch(&emails, &input, &options, &BatchOptions::default()).await;
+    let table = table.with_results(&results);
+
+    let format = query.format.unwrap_or_default();
+    let body = match format {
+        ExportFormat::Csv => {
+            let mut body = Vec::new();
+            table
+                .write_csv(&mut body)
+                .map(|_| body)
+                .map_err(|err| err.to_string())
+        }
+        ExportFormat::Xlsx => table.to_xlsx().map_err(|err| err.to_string()),
+    };
+
+    match body {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"results.{}\"", format.extension()),
+            ))
+            .body(body),
+        Err(err) => HttpResponse::InternalServerError().json(ApiError::new("export_failed", err)),
+    }
+}
+
 #[get("/")]
 async fn index() -> impl Responder {
     HttpResponse::Ok().body("OK")
 }
 
+#[get("/metrics")]
+async fn metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(extant::metrics::render().await)
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("*"));
+    config::require_init();
+    let config = Config::load(None)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    logging::init(&config.log)
+        .map_err(|err| io::Error::other(format!("Logging not initialized: {}", err)))?;
 
     info!("Hostname: {:?}", gethostname::gethostname());
+    info!("Config:\n{}", config);
 
-    let port = env::var("PORT").unwrap_or(String::from("8080"));
-    let host = env::var("HOST").unwrap_or(String::from("0.0.0.0"));
+    let policies = web::Data::new(config.files.load_data()?);
+    {
+        let mut api_keys = auth::global()
+            .lock()
+            .expect("API keys lock is never poisoned. qed.");
+        api_keys
+            .load(&config.auth, config.files.api_keys.as_deref())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        if !api_keys.is_enabled() {
+            warn!(
+                "No API keys configured, {} is open to anyone",
+                AUTHENTICATED_PATH
+            );
+        }
+    }
+    let (host, port, metrics_port) = (
+        config.server.host.clone(),
+        config.server.port,
+        config.server.metrics_port,
+    );
+    config::init(config).map_err(|err| io::Error::other(err.to_string()))?;
+    let drain_timeout = config::get().drain_timeout();
 
-    let (tx, rx) = mpsc::channel::<()>();
     let server = HttpServer::new(move || {
         App::new()
-            .app_data(tx.clone())
-            .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+            .app_data(policies.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(max_json_bytes(config::get().check.max_batch_size))
+                    .error_handler(json_error_handler),
+            )
             .wrap(middleware::Compress::default())
             .wrap(middleware::Logger::default())
-            .service(email_check)
+            .wrap_fn(|req, srv| {
+                let request_id = request_id(&req);
+                let span = info_span!(
+                    "request",
+                    id = %request_id,
+                    method = %req.method(),
+                    path = %req.path()
+                );
+                let response = span.in_scope(|| srv.call(req));
+
+                async move {
+                    let mut response = response.await?;
+                    if let Ok(value) = HeaderValue::from_str(&request_id) {
+                        response
+                            .headers_mut()
+                            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                    }
+
+                    Ok(response)
+                }
+                .instrument(span)
+            })
+            .service(
+                // Scoped, so the checks below apply to whatever path the
+                // router matched, percent-encoded or not.
+                web::scope(AUTHENTICATED_PATH)
+                    .wrap_fn(|req, srv| {
+                        let refused = if shutdown::is_shutting_down() {
+                            Some(
+                                HttpResponse::ServiceUnavailable()
+                                    .insert_header(("Retry-After", "5"))
+                                    .json(ApiError::new(
+                                        "shutting_down",
+                                        "The server is shutting down, retry shortly",
+                                    )),
+                            )
+                        } else {
+                            auth::authenticate(&req).err()
+                        };
+
+                        match refused {
+                            Some(resp) => Either::Left(future::ok(req.into_response(resp))),
+                            None => Either::Right(srv.call(req)),
+                        }
+                    })
+                    .service(email_check)
+                    .service(email_check_csv),
+            )
             .service(index)
+            .configure(admin::configure)
+            .configure(health::configure)
     })
-    .bind(format!("{}:{}", host, port))?
+    .bind((host.as_str(), port))?
+    .disable_signals()
+    .shutdown_timeout(drain_timeout.as_secs() + SHUTDOWN_GRACE_SECS)
     .run();
 
+    // Metrics go on their own port, which isn't one of the public services.
+    if metrics_port != 0 {
+        let metrics_server = HttpServer::new(|| App::new().service(metrics))
+            .workers(1)
+            .bind((host.as_str(), metrics_port))?
+            .disable_signals()
+            .run();
+        actix_web::rt::spawn(async move {
+            if let Err(err) = metrics_server.await {
+                error!("Metrics server failed: {}", err);
+            }
+        });
+    }
+
+    // Stop accepting work on SIGTERM or SIGINT, give running checks
+    // `drain_timeout` to finish, then cut the rest short.
     let srv = server.clone();
-    thread::spawn(move || {
-        rx.recv().unwrap();
+    actix_web::rt::spawn(async move {
+        shutdown::wait_for_signal().await;
+        info!(
+            "Shutting down, running checks have {}s to finish",
+            drain_timeout.as_secs()
+        );
+        shutdown::begin();
+        actix_web::rt::spawn(async move {
+            actix_web::rt::time::sleep(drain_timeout).await;
+            warn!("Drain timeout reached, cutting running checks short");
+            shutdown::cut_short();
+        });
 
-        executor::block_on(srv.stop(true))
+        srv.stop(true).await
     });
 
-    server.await
+    server.await?;
+    info!("Shut down");
+
+    Ok(())
 }
@@ -0,0 +1,382 @@
+// Prometheus metrics, served as text on `/metrics` of `server.metrics_port`.
+//
+// Everything is registered in one registry on first use. The check code
+// records into the statics below directly; the cache counters are copied
+// from the check cache when the metrics are gathered.
+//
+// Labels stick to small fixed sets like providers and verdicts, never raw
+// MX hosts or domains: those would reveal which domains customers check,
+// and add a series for every one ever seen. In-flight sessions are labelled
+// by MX, but only as one of the big mail hosts in `MX_LABELS`, or "other".
+// API key names are labels too, so the metrics port must stay private.
+
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use trust_dns_proto::rr::Name;
+
+use crate::mail::{self, MyReachable};
+use crate::smtp::{Provider, SmtpDetails, SmtpError};
+
+/// Buckets in seconds for the SMTP stages, which can take up to the SMTP
+/// timeout.
+const SMTP_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+/// MX host suffixes of the big mail hosts, and their `mx` label. Any other
+/// MX host is labelled "other".
+const MX_LABELS: &[(&str, &str)] = &[
+    (".google.com", "google"),
+    (".googlemail.com", "google"),
+    (".outlook.com", "outlook"),
+    (".yahoodns.net", "yahoo"),
+    (".icloud.com", "icloud"),
+    (".pphosted.com", "proofpoint"),
+    (".mimecast.com", "mimecast"),
+    (".messagelabs.com", "messagelabs"),
+    (".zoho.com", "zoho"),
+    (".yandex.net", "yandex"),
+    (".mail.ru", "mailru"),
+];
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("Metric is registered once. qed.");
+    collector
+}
+
+static VERDICTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("extant_checks_total", "Checked addresses, by verdict."),
+            &["verdict"],
+        )
+        .expect("Valid metric. qed."),
+    )
+});
+
+static SMTP_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("extant_smtp_errors_total", "Failed SMTP checks, by error."),
+            &["error"],
+        )
+        .expect("Valid metric. qed."),
+    )
+});
+
+static PROVIDERS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "extant_smtp_checks_total",
+                "Completed SMTP checks, by provider strategy.",
+            ),
+            &["provider"],
+        )
+        .expect("Valid metric. qed."),
+    )
+});
+
+static RETRIES: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "extant_retries_total",
+            "Checks retried after an Unknown verdict.",
+        )
+        .expect("Valid metric. qed."),
+    )
+});
+
+static CACHE_HITS: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "extant_cache_hits_total",
+            "Check results served from the cache.",
+        )
+        .expect("Valid metric. qed."),
+    )
+});
+
+static CACHE_MISSES: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "extant_cache_misses_total",
+            "Check results not found in the cache.",
+        )
+        .expect("Valid metric. qed."),
+    )
+});
+
+static API_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "extant_api_requests_total",
+                "Authenticated check requests, by API key name.",
+            ),
+            &["key"],
+        )
+        .expect("Valid metric. qed."),
+    )
+});
+
+static API_ADDRESSES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "extant_api_addresses_total",
+                "Addresses charged to quotas, by API key name.",
+            ),
+            &["key"],
+        )
+        .expect("Valid metric. qed."),
+    )
+});
+
+static API_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "extant_api_rejections_total",
+                "Requests refused by API key checks, by reason.",
+            ),
+            &["reason"],
+        )
+        .expect("Valid metric. qed."),
+    )
+});
+
+static SMTP_SESSIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "extant_smtp_sessions",
+                "SMTP checks in flight, by provider strategy and MX.",
+            ),
+            &["provider", "mx"],
+        )
+        .expect("Valid metric. qed."),
+    )
+});
+
+static DNS_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register(
+        Histogram::with_opts(HistogramOpts::new(
+            "extant_dns_duration_seconds",
+            "MX lookup latency.",
+        ))
+        .expect("Valid metric. qed."),
+    )
+});
+
+static SMTP_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "extant_smtp_duration_seconds",
+                "SMTP latency, by stage: connect (through MAIL FROM) or rcpt.",
+            )
+            .buckets(SMTP_BUCKETS.to_vec()),
+            &["stage"],
+        )
+        .expect("Valid metric. qed."),
+    )
+});
+
+static CHECK_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register(
+        Histogram::with_opts(
+            HistogramOpts::new(
+                "extant_check_duration_seconds",
+                "Latency of one check attempt, cache hits included.",
+            )
+            .buckets(SMTP_BUCKETS.to_vec()),
+        )
+        .expect("Valid metric. qed."),
+    )
+});
+
+pub fn record_verdict(verdict: MyReachable) {
+    VERDICTS.with_label_values(&[&verdict.to_string()]).inc();
+}
+
+pub fn record_smtp(result: &Result<SmtpDetails, SmtpError>) {
+    match result {
+        Ok(details) => PROVIDERS
+            .with_label_values(&[provider_label(details.provider)])
+            .inc(),
+        Err(err) => SMTP_ERRORS
+            .with_label_values(&[smtp_error_label(err)])
+            .inc(),
+    }
+}
+
+pub fn record_retry() {
+    RETRIES.inc();
+}
+
+pub fn record_api_request(key: &str) {
+    API_REQUESTS.with_label_values(&[key]).inc();
+}
+
+pub fn record_api_addresses(key: &str, addresses: u64) {
+    API_ADDRESSES.with_label_values(&[key]).inc_by(addresses);
+}
+
+pub fn record_rejection(reason: &str) {
+    API_REJECTIONS.with_label_values(&[reason]).inc();
+}
+
+pub fn observe_dns(start: Instant) {
+    DNS_SECONDS.observe(start.elapsed().as_secs_f64());
+}
+
+pub fn observe_connect(start: Instant) {
+    SMTP_SECONDS
+        .with_label_values(&["connect"])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+pub fn observe_rcpt(start: Instant) {
+    SMTP_SECONDS
+        .with_label_values(&["rcpt"])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+pub fn observe_check(start: Instant) {
+    CHECK_SECONDS.observe(start.elapsed().as_secs_f64());
+}
+
+fn provider_label(provider: Provider) -> &'static str {
+    match provider {
+        Provider::Generic => "generic",
+        Provider::Yahoo => "yahoo",
+        Provider::GoogleConsumer => "google_consumer",
+        Provider::GoogleWorkspace => "google_workspace",
+    }
+}
+
+fn mx_label(host: &Name) -> &'static str {
+    let host = host.to_utf8().trim_end_matches('.').to_lowercase();
+
+    MX_LABELS
+        .iter()
+        .find(|(suffix, _)| host.ends_with(suffix))
+        .map_or("other", |(_, label)| label)
+}
+
+fn smtp_error_label(err: &SmtpError) -> &'static str {
+    match err {
+        SmtpError::SocksError(_) => "socks",
+        SmtpError::SmtpError(_) => "smtp",
+        SmtpError::TimeoutError(_) => "timeout",
+        SmtpError::YahooError(_) => "yahoo",
+        SmtpError::SmtpUtf8Unsupported => "smtputf8_unsupported",
+        SmtpError::ShuttingDown => "shutting_down",
+    }
+}
+
+/// Counts an SMTP check against its provider strategy and MX while alive.
+/// Dropped when the check ends, also when it's cancelled by a timeout.
+pub struct SmtpSession {
+    labels: [&'static str; 2],
+}
+
+impl SmtpSession {
+    pub fn start(provider: Provider, host: &Name) -> SmtpSession {
+        let labels = [provider_label(provider), mx_label(host)];
+        SMTP_SESSIONS.with_label_values(&labels).inc();
+        SmtpSession { labels }
+    }
+}
+
+impl Drop for SmtpSession {
+    fn drop(&mut self) {
+        SMTP_SESSIONS.with_label_values(&self.labels).dec();
+    }
+}
+
+/// Bring a counter up to `total`, a running total kept elsewhere.
+fn catch_up(counter: &IntCounter, total: u64) {
+    let current = counter.get();
+    if total > current {
+        counter.inc_by(total - current);
+    }
+}
+
+/// All metrics in the Prometheus text format.
+pub async fn render() -> String {
+    let (hits, misses) = mail::cache_stats().await;
+    catch_up(&CACHE_HITS, hits);
+    catch_up(&CACHE_MISSES, misses);
+
+    // Register every metric, so the ones without labels are listed from
+    // the start.
+    Lazy::force(&VERDICTS);
+    Lazy::force(&SMTP_ERRORS);
+    Lazy::force(&PROVIDERS);
+    Lazy::force(&RETRIES);
+    Lazy::force(&API_REQUESTS);
+    Lazy::force(&API_ADDRESSES);
+    Lazy::force(&API_REJECTIONS);
+    Lazy::force(&SMTP_SESSIONS);
+    Lazy::force(&DNS_SECONDS);
+    Lazy::force(&SMTP_SECONDS);
+    Lazy::force(&CHECK_SECONDS);
+
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .expect("Encoding to a Vec doesn't fail. qed.");
+
+    String::from_utf8(buffer).expect("Prometheus text is UTF-8. qed.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(host: &str) -> Name {
+        Name::from_ascii(host).unwrap()
+    }
+
+    #[test]
+    fn mx_label_names_big_mail_hosts() {
+        assert_eq!(mx_label(&name("gmail-smtp-in.l.google.com.")), "google");
+        assert_eq!(
+            mx_label(&name("example-com.mail.protection.outlook.com.")),
+            "outlook"
+        );
+        assert_eq!(mx_label(&name("MTA5.AM0.YahooDNS.net")), "yahoo");
+    }
+
+    #[test]
+    fn mx_label_hides_other_hosts() {
+        assert_eq!(mx_label(&name("mx.example.com.")), "other");
+        // Only whole labels count.
+        assert_eq!(mx_label(&name("notgoogle.com.")), "other");
+    }
+
+    #[test]
+    fn sessions_are_counted_while_alive() {
+        let host = name("mx1.icloud.com.");
+        let gauge = || {
+            SMTP_SESSIONS
+                .with_label_values(&["generic", "icloud"])
+                .get()
+        };
+
+        let session = SmtpSession::start(Provider::Generic, &host);
+        assert_eq!(gauge(), 1);
+        drop(session);
+        assert_eq!(gauge(), 0);
+    }
+}
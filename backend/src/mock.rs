@@ -0,0 +1,118 @@
+// Deterministic stand-ins for the DNS and SMTP stages.
+//
+// With `EmailCheckInput::mock` set, `check_single_email` runs the syntax,
+// list and dataset checks as usual but takes its MX host and SMTP details
+// from here. Results are derived from a hash of the domain or address, so the
+// same address always gets the same result, and no packet leaves the machine.
+// Frontend work and integration tests get realistic data this way.
+//
+// Enable it for every request with `mock.enabled` in the config, or for one
+// request with the `X-Extant-Mock: true` header if `mock.allow_header` is
+// set.
+
+use std::time::Duration;
+
+use async_smtp::smtp::error::Error as AsyncSmtpError;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use sha2::{Digest, Sha256};
+use trust_dns_proto::rr::Name;
+
+use crate::config;
+use crate::smtp::{Provider, SmtpDetails, SmtpError};
+
+/// Share of domains without MX records.
+const NO_MX_RATE: f64 = 0.05;
+/// Share of domains accepting mail for any address.
+const CATCH_ALL_RATE: f64 = 0.1;
+
+/// A generator seeded from the SHA-256 of `value`. Both are specified
+/// algorithms, unlike `DefaultHasher` and `SmallRng`, so the output stays the
+/// same across Rust releases and platforms.
+fn rng_for(value: &str) -> ChaCha8Rng {
+    let mut seed = [0; 32];
+    seed.copy_from_slice(&Sha256::digest(value.to_lowercase().as_bytes()));
+
+    ChaCha8Rng::from_seed(seed)
+}
+
+/// The fake MX host of `domain`, or `None` for the few domains that get no
+/// MX records.
+pub(crate) fn mx_host(domain: &str) -> Option<Name> {
+    if rng_for(domain).gen_bool(NO_MX_RATE) {
+        return None;
+    }
+
+    Some(Name::from_ascii(format!("mx.{}.", domain)).unwrap_or_else(|_| Name::root()))
+}
+
+fn provider(domain: &str) -> Provider {
+    let domain = domain.to_lowercase();
+
+    if domain == "gmail.com" || domain == "googlemail.com" {
+        Provider::GoogleConsumer
+    } else if domain.starts_with("yahoo.") {
+        Provider::Yahoo
+    } else {
+        Provider::Generic
+    }
+}
+
+/// Fake SMTP details for `to_email`, after the configured latency. Fails at
+/// the configured rate, like a server that times out.
+pub(crate) async fn check_smtp(to_email: &str, domain: &str) -> Result<SmtpDetails, SmtpError> {
+    let mock = &config::get().mock;
+    if mock.latency_ms > 0 {
+        async_std::task::sleep(Duration::from_millis(mock.latency_ms)).await;
+    }
+
+    let mut rng = rng_for(to_email);
+    if rng.gen_bool(mock.failure_rate) {
+        return Err(SmtpError::SmtpError(AsyncSmtpError::Client(
+            "mock SMTP failure",
+        )));
+    }
+
+    let mut details = SmtpDetails {
+        can_connect_smtp: true,
+        is_catch_all: rng_for(&format!("catch-all:{}", domain)).gen_bool(CATCH_ALL_RATE),
+        provider: provider(domain),
+        ..Default::default()
+    };
+
+    // A catch-all server accepts every recipient.
+    if details.is_catch_all {
+        details.is_deliverable = true;
+        return Ok(details);
+    }
+
+    match rng.gen_range(0..100) {
+        0..=69 => details.is_deliverable = true,
+        70..=84 => {}
+        85..=89 => details.has_full_inbox = true,
+        90..=94 => details.is_disabled = true,
+        _ => details.is_banned = true,
+    }
+
+    Ok(details)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mock results are relied on by frontend work and tests, so a change
+    /// here changes every mock verdict and should be deliberate.
+    #[test]
+    fn rng_output_is_pinned() {
+        assert_eq!(
+            rng_for("someone@example.com").gen::<u64>(),
+            5453188867455220842
+        );
+        assert_eq!(
+            rng_for("Someone@Example.com").gen::<u64>(),
+            5453188867455220842
+        );
+        assert_eq!(rng_for("example.com").gen::<u64>(), 1578321324093193135);
+    }
+}
@@ -0,0 +1,174 @@
+// Address normalization, run before checking so that spelling variants of
+// the same mailbox are only checked (and cached) once.
+//
+// The generic rules only touch what is case-insensitive or purely an
+// encoding: surrounding whitespace, the domain's case and its IDNA form.
+// Provider rules go further for providers known to ignore dots or plus tags
+// in the local part, e.g. `John.Doe+news@Gmail.com` -> `johndoe@gmail.com`.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct NormalizeOptions {
+    /// Apply provider-aware dot and plus-tag rules.
+    ///
+    /// Defaults to false.
+    pub provider_rules: bool,
+}
+
+/// How a provider treats the local part of its addresses.
+struct ProviderRule {
+    domains: &'static [&'static str],
+    /// Canonical domain, when the provider has aliases.
+    canonical_domain: Option<&'static str>,
+    ignores_dots: bool,
+    tag_separator: Option<char>,
+}
+
+const PROVIDER_RULES: [ProviderRule; 4] = [
+    ProviderRule {
+        domains: &["gmail.com", "googlemail.com"],
+        canonical_domain: Some("gmail.com"),
+        ignores_dots: true,
+        tag_separator: Some('+'),
+    },
+    ProviderRule {
+        domains: &["outlook.com", "hotmail.com", "live.com", "msn.com"],
+        canonical_domain: None,
+        ignores_dots: false,
+        tag_separator: Some('+'),
+    },
+    ProviderRule {
+        domains: &["icloud.com", "me.com", "mac.com"],
+        canonical_domain: None,
+        ignores_dots: false,
+        tag_separator: Some('+'),
+    },
+    ProviderRule {
+        domains: &[
+            "protonmail.com",
+            "protonmail.ch",
+            "proton.me",
+            "pm.me",
+            "fastmail.com",
+        ],
+        canonical_domain: None,
+        ignores_dots: false,
+        tag_separator: Some('+'),
+    },
+];
+
+/// Lower-case the domain and convert it to its ASCII (punycode) form. Falls
+/// back to the lower-cased domain if it isn't a valid IDN.
+pub fn normalize_domain(domain: &str) -> String {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+
+    idna::domain_to_ascii(&domain).unwrap_or(domain)
+}
+
+/// Normalize `email`. Strings without an `@` are only trimmed, the syntax
+/// check reports them later. Provider rules are skipped when they would leave
+/// nothing of the local part, as for `+tag@gmail.com`.
+pub fn normalize(email: &str, options: &NormalizeOptions) -> String {
+    let email = email.trim();
+    let at = match email.rfind('@') {
+        Some(at) => at,
+        None => return email.to_string(),
+    };
+
+    let local = &email[..at];
+    let domain = normalize_domain(&email[at + 1..]);
+
+    if options.provider_rules {
+        if let Some(rule) = PROVIDER_RULES
+            .iter()
+            .find(|rule| rule.domains.contains(&domain.as_ref()))
+        {
+            let mut provider_local = local.to_lowercase();
+            if let Some(separator) = rule.tag_separator {
+                provider_local = provider_local
+                    .split(separator)
+                    .next()
+                    .unwrap_or("")
+                    .to_string();
+            }
+            if rule.ignores_dots {
+                provider_local = provider_local.replace('.', "");
+            }
+
+            if !provider_local.is_empty() {
+                return format!(
+                    "{}@{}",
+                    provider_local,
+                    rule.canonical_domain.unwrap_or(domain.as_str())
+                );
+            }
+        }
+    }
+
+    format!("{}@{}", local, domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROVIDER_RULES_ON: NormalizeOptions = NormalizeOptions {
+        provider_rules: true,
+    };
+
+    #[test]
+    fn generic_rules_only_touch_the_domain() {
+        let options = NormalizeOptions::default();
+        assert_eq!(
+            normalize("  John.Doe+news@Gmail.COM. ", &options),
+            "John.Doe+news@gmail.com"
+        );
+        assert_eq!(
+            normalize("user@Bücher.de", &options),
+            "user@xn--bcher-kva.de"
+        );
+        assert_eq!(normalize(" no-at-sign ", &options), "no-at-sign");
+    }
+
+    #[test]
+    fn provider_rules_are_off_by_default() {
+        assert!(!NormalizeOptions::default().provider_rules);
+    }
+
+    #[test]
+    fn provider_rules_drop_dots_and_tags() {
+        assert_eq!(
+            normalize("John.Doe+news@Gmail.com", &PROVIDER_RULES_ON),
+            "johndoe@gmail.com"
+        );
+        assert_eq!(
+            normalize("john.doe@googlemail.com", &PROVIDER_RULES_ON),
+            "johndoe@gmail.com"
+        );
+        assert_eq!(
+            normalize("John.Doe+news@outlook.com", &PROVIDER_RULES_ON),
+            "john.doe@outlook.com"
+        );
+        assert_eq!(
+            normalize("John.Doe+news@example.com", &PROVIDER_RULES_ON),
+            "John.Doe+news@example.com"
+        );
+    }
+
+    #[test]
+    fn provider_rules_never_empty_the_local_part() {
+        assert_eq!(
+            normalize("+tag@gmail.com", &PROVIDER_RULES_ON),
+            "+tag@gmail.com"
+        );
+        assert_eq!(
+            normalize("...@gmail.com", &PROVIDER_RULES_ON),
+            "...@gmail.com"
+        );
+        assert_eq!(
+            normalize("+tag@Hotmail.com", &PROVIDER_RULES_ON),
+            "+tag@hotmail.com"
+        );
+    }
+}
@@ -0,0 +1,163 @@
+// Email addresses in log lines.
+//
+// Depending on `log.pii`, addresses are logged as they are, with the local
+// part hidden, or as a keyed hash that stays the same across every line and
+// module but can't be read back. Modules format addresses for logs with
+// `email`, and free text that can contain some, like SMTP replies, with
+// `text`.
+
+use std::borrow::Cow;
+
+use hmac::{Hmac, Mac, NewMac};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use sha2::Sha256;
+
+use crate::config::{self, PiiMode};
+
+static EMAIL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"[^\s<>"'(),;:\[\]@]+@[^\s<>"'(),;:\[\]@]+"#).expect("Correct regex. qed.")
+});
+
+static KEY: Lazy<Vec<u8>> = Lazy::new(|| match &config::get().log.pii_key {
+    Some(key) => key.as_bytes().to_vec(),
+    None => rand::random::<[u8; 32]>().to_vec(),
+});
+
+/// `email`, as it may appear in logs.
+pub fn email(email: &str) -> String {
+    mask_email(email, config::get().log.pii, &KEY)
+}
+
+/// `text` with every address in it formatted by `email`.
+pub fn text(text: &str) -> Cow<'_, str> {
+    mask_text(text, config::get().log.pii, &KEY)
+}
+
+fn mask_email(email: &str, mode: PiiMode, key: &[u8]) -> String {
+    match mode {
+        PiiMode::Plain => email.to_string(),
+        PiiMode::Redact => match email.rfind('@') {
+            Some(at) => format!("***{}", &email[at..]),
+            None => "***".into(),
+        },
+        PiiMode::Hash => format!("email:{}", hash(email, key)),
+    }
+}
+
+fn mask_text<'a>(text: &'a str, mode: PiiMode, key: &[u8]) -> Cow<'a, str> {
+    match mode {
+        PiiMode::Plain => Cow::Borrowed(text),
+        _ => EMAIL.replace_all(text, |caps: &Captures| mask_email(&caps[0], mode, key)),
+    }
+}
+
+/// The first 8 bytes of the HMAC-SHA256 of the address, in hex. Addresses
+/// differing only in case get the same hash.
+fn hash(email: &str, key: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size. qed.");
+    mac.update(email.trim().to_lowercase().as_bytes());
+
+    mac.finalize().into_bytes()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KEY: &[u8] = b"test key";
+
+    #[test]
+    fn plain_keeps_addresses() {
+        assert_eq!(
+            mask_email("alice@example.com", PiiMode::Plain, TEST_KEY),
+            "alice@example.com"
+        );
+        let text = "550 5.1.1 <alice@example.com>: unknown user";
+        assert!(matches!(
+            mask_text(text, PiiMode::Plain, TEST_KEY),
+            Cow::Borrowed(t) if t == text
+        ));
+    }
+
+    #[test]
+    fn redact_hides_the_local_part() {
+        assert_eq!(
+            mask_email("alice@example.com", PiiMode::Redact, TEST_KEY),
+            "***@example.com"
+        );
+        // The last `@` splits a quoted local part.
+        assert_eq!(
+            mask_email("\"a@b\"@example.com", PiiMode::Redact, TEST_KEY),
+            "***@example.com"
+        );
+    }
+
+    #[test]
+    fn hash_is_stable_and_keyed() {
+        let first = mask_email("alice@example.com", PiiMode::Hash, TEST_KEY);
+        assert!(first.starts_with("email:"));
+        assert_eq!(first.len(), "email:".len() + 16);
+        assert_eq!(
+            mask_email("alice@example.com", PiiMode::Hash, TEST_KEY),
+            first
+        );
+        assert_eq!(
+            mask_email(" Alice@Example.COM", PiiMode::Hash, TEST_KEY),
+            first
+        );
+
+        assert_ne!(
+            mask_email("bob@example.com", PiiMode::Hash, TEST_KEY),
+            first
+        );
+        assert_ne!(
+            mask_email("alice@example.com", PiiMode::Hash, b"other key"),
+            first
+        );
+    }
+
+    #[test]
+    fn text_masks_every_address() {
+        let text = "550 <alice@example.com> unknown, try bob@example.org";
+
+        assert_eq!(
+            mask_text(text, PiiMode::Redact, TEST_KEY),
+            "550 <***@example.com> unknown, try ***@example.org"
+        );
+        assert_eq!(
+            mask_text(text, PiiMode::Hash, TEST_KEY),
+            format!(
+                "550 <{}> unknown, try {}",
+                mask_email("alice@example.com", PiiMode::Hash, TEST_KEY),
+                mask_email("bob@example.org", PiiMode::Hash, TEST_KEY)
+            )
+        );
+    }
+
+    #[test]
+    fn empty_and_odd_input() {
+        assert_eq!(mask_email("", PiiMode::Redact, TEST_KEY), "***");
+        assert_eq!(mask_email("no-at-sign", PiiMode::Redact, TEST_KEY), "***");
+        assert_eq!(mask_email("alice@", PiiMode::Redact, TEST_KEY), "***@");
+        assert_eq!(mask_email("@", PiiMode::Redact, TEST_KEY), "***@");
+        assert_eq!(
+            mask_email("", PiiMode::Hash, TEST_KEY).len(),
+            "email:".len() + 16
+        );
+        assert_eq!(hash("", b""), hash("", b""));
+
+        assert_eq!(mask_text("", PiiMode::Hash, TEST_KEY), "");
+        assert_eq!(
+            mask_text("no address @ here", PiiMode::Redact, TEST_KEY),
+            "no address @ here"
+        );
+        assert_eq!(
+            mask_text("ünïcødé@exämple.com", PiiMode::Redact, TEST_KEY),
+            "***@exämple.com"
+        );
+    }
+}
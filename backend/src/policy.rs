@@ -0,0 +1,511 @@
+// Configurable mapping from check results to a `MyReachable` verdict.
+//
+// A policy is an ordered list of rules over the `MiscDetails` and
+// `SmtpDetails` flags; the first matching rule decides the verdict. Callers
+// pick a policy per request by its profile name.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use check_if_email_exists::misc::MiscDetails;
+use serde::{Deserialize, Serialize};
+use serde_json::error::Error as SerdeError;
+
+use crate::mail::MyReachable;
+use crate::smtp::{SmtpDetails, SmtpError};
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// A signal a rule can match on.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Flag {
+    Disposable,
+    RoleAccount,
+    CatchAll,
+    FullInbox,
+    Banned,
+    Undeliverable,
+    CannotConnect,
+    Disabled,
+    /// The SMTP stage failed, so none of the SMTP flags are known. Errors
+    /// that are a definite answer, like a server without SMTPUTF8 for a
+    /// non-ASCII address, set `Undeliverable` instead.
+    SmtpError,
+}
+
+impl Flag {
+    fn is_set(&self, misc: &MiscDetails, smtp: &Result<SmtpDetails, SmtpError>) -> bool {
+        match (self, smtp) {
+            (Flag::SmtpError, Err(err)) => !err.is_definite(),
+            (Flag::Undeliverable, Err(err)) => err.is_definite(),
+            (Flag::Disposable, _) => misc.is_disposable,
+            (Flag::RoleAccount, _) => misc.is_role_account,
+            (Flag::CatchAll, Ok(smtp)) => smtp.is_catch_all,
+            (Flag::FullInbox, Ok(smtp)) => smtp.has_full_inbox,
+            (Flag::Banned, Ok(smtp)) => smtp.is_banned,
+            (Flag::Undeliverable, Ok(smtp)) => !smtp.is_deliverable,
+            (Flag::CannotConnect, Ok(smtp)) => !smtp.can_connect_smtp,
+            (Flag::Disabled, Ok(smtp)) => smtp.is_disabled,
+            (Flag::SmtpError, Ok(_)) | (_, Err(_)) => false,
+        }
+    }
+}
+
+/// Gives `verdict` if any of the flags in `any` is set.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Rule {
+    pub any: Vec<Flag>,
+    pub verdict: MyReachable,
+}
+
+impl Rule {
+    fn new(any: Vec<Flag>, verdict: MyReachable) -> Self {
+        Rule { any, verdict }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Policy {
+    /// Profile name used to select this policy.
+    pub name: String,
+    /// Rules, evaluated in order.
+    pub rules: Vec<Rule>,
+    /// Verdict when no rule matches.
+    pub fallback: MyReachable,
+}
+
+impl Default for Policy {
+    /// Reproduces the historical `calculate_reachable` precedence.
+    fn default() -> Self {
+        Policy {
+            name: DEFAULT_PROFILE.into(),
+            rules: vec![
+                Rule::new(vec![Flag::SmtpError], MyReachable::Unknown),
+                Rule::new(
+                    vec![
+                        Flag::Disposable,
+                        Flag::RoleAccount,
+                        Flag::CatchAll,
+                        Flag::FullInbox,
+                    ],
+                    MyReachable::Risky,
+                ),
+                Rule::new(vec![Flag::Banned], MyReachable::Banned),
+                Rule::new(
+                    vec![Flag::Undeliverable, Flag::CannotConnect, Flag::Disabled],
+                    MyReachable::Invalid,
+                ),
+            ],
+            fallback: MyReachable::Safe,
+        }
+    }
+}
+
+impl Policy {
+    /// Role accounts count as Safe, and being banned wins over Risky.
+    pub fn marketing() -> Self {
+        Policy {
+            name: "marketing".into(),
+            rules: vec![
+                Rule::new(vec![Flag::SmtpError], MyReachable::Unknown),
+                Rule::new(vec![Flag::Banned], MyReachable::Banned),
+                Rule::new(
+                    vec![Flag::Undeliverable, Flag::CannotConnect, Flag::Disabled],
+                    MyReachable::Invalid,
+                ),
+                Rule::new(
+                    vec![Flag::Disposable, Flag::CatchAll, Flag::FullInbox],
+                    MyReachable::Risky,
+                ),
+            ],
+            fallback: MyReachable::Safe,
+        }
+    }
+
+    /// Catch-all and disposable domains are rejected outright at signup.
+    pub fn signup() -> Self {
+        Policy {
+            name: "signup".into(),
+            rules: vec![
+                Rule::new(vec![Flag::SmtpError], MyReachable::Unknown),
+                Rule::new(vec![Flag::Banned], MyReachable::Banned),
+                Rule::new(
+                    vec![
+                        Flag::CatchAll,
+                        Flag::Disposable,
+                        Flag::Undeliverable,
+                        Flag::CannotConnect,
+                        Flag::Disabled,
+                    ],
+                    MyReachable::Invalid,
+                ),
+                Rule::new(vec![Flag::RoleAccount, Flag::FullInbox], MyReachable::Risky),
+            ],
+            fallback: MyReachable::Safe,
+        }
+    }
+
+    pub fn evaluate(
+        &self,
+        misc: &MiscDetails,
+        smtp: &Result<SmtpDetails, SmtpError>,
+    ) -> MyReachable {
+        self.rules
+            .iter()
+            .find(|rule| rule.any.iter().any(|flag| flag.is_set(misc, smtp)))
+            .map(|rule| rule.verdict)
+            .unwrap_or(self.fallback)
+    }
+}
+
+/// Possible errors when loading policies from a file.
+#[derive(Debug)]
+pub enum PolicyError {
+    IoError(io::Error),
+    SerdeError(SerdeError),
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<io::Error> for PolicyError {
+    fn from(error: io::Error) -> Self {
+        PolicyError::IoError(error)
+    }
+}
+
+impl From<SerdeError> for PolicyError {
+    fn from(error: SerdeError) -> Self {
+        PolicyError::SerdeError(error)
+    }
+}
+
+/// All policies selectable by profile name.
+#[derive(Debug, Clone)]
+pub struct Policies {
+    profiles: HashMap<String, Policy>,
+}
+
+impl Default for Policies {
+    fn default() -> Self {
+        let mut policies = Policies {
+            profiles: HashMap::new(),
+        };
+        for policy in [Policy::default(), Policy::marketing(), Policy::signup()] {
+            policies.insert(policy);
+        }
+
+        policies
+    }
+}
+
+impl Policies {
+    /// Built-in profiles, plus any policies from the JSON array in `path`.
+    /// A policy in the file replaces a built-in one with the same name.
+    pub fn load(path: &str) -> Result<Self, PolicyError> {
+        let mut policies = Policies::default();
+        let custom: Vec<Policy> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        for policy in custom {
+            policies.insert(policy);
+        }
+
+        Ok(policies)
+    }
+
+    pub fn insert(&mut self, policy: Policy) {
+        self.profiles.insert(policy.name.clone(), policy);
+    }
+
+    pub fn get(&self, profile: &str) -> Option<&Policy> {
+        self.profiles.get(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `calculate_reachable` as it was before policies.
+    fn baseline(misc: &MiscDetails, smtp: &Result<SmtpDetails, SmtpError>) -> MyReachable {
+        if let Ok(smtp) = smtp {
+            if misc.is_disposable
+                || misc.is_role_account
+                || smtp.is_catch_all
+                || smtp.has_full_inbox
+            {
+                return MyReachable::Risky;
+            }
+
+            if smtp.is_banned {
+                return MyReachable::Banned;
+            }
+
+            if !smtp.is_deliverable || !smtp.can_connect_smtp || smtp.is_disabled {
+                return MyReachable::Invalid;
+            }
+
+            MyReachable::Safe
+        } else {
+            MyReachable::Unknown
+        }
+    }
+
+    fn misc(is_disposable: bool, is_role_account: bool) -> MiscDetails {
+        MiscDetails {
+            is_disposable,
+            is_role_account,
+        }
+    }
+
+    fn deliverable() -> SmtpDetails {
+        SmtpDetails {
+            can_connect_smtp: true,
+            is_deliverable: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn default_matches_the_baseline_table() {
+        let table = vec![
+            (
+                "deliverable",
+                misc(false, false),
+                Ok(deliverable()),
+                MyReachable::Safe,
+            ),
+            (
+                "role account",
+                misc(false, true),
+                Ok(deliverable()),
+                MyReachable::Risky,
+            ),
+            (
+                "disposable",
+                misc(true, false),
+                Ok(deliverable()),
+                MyReachable::Risky,
+            ),
+            (
+                "catch-all",
+                misc(false, false),
+                Ok(SmtpDetails {
+                    is_catch_all: true,
+                    ..deliverable()
+                }),
+                MyReachable::Risky,
+            ),
+            (
+                "full inbox",
+                misc(false, false),
+                Ok(SmtpDetails {
+                    has_full_inbox: true,
+                    is_deliverable: false,
+                    ..deliverable()
+                }),
+                MyReachable::Risky,
+            ),
+            (
+                "banned",
+                misc(false, false),
+                Ok(SmtpDetails {
+                    is_banned: true,
+                    is_deliverable: false,
+                    ..deliverable()
+                }),
+                MyReachable::Banned,
+            ),
+            (
+                "catch-all that banned us",
+                misc(false, false),
+                Ok(SmtpDetails {
+                    is_catch_all: true,
+                    is_banned: true,
+                    ..deliverable()
+                }),
+                MyReachable::Risky,
+            ),
+            (
+                "undeliverable",
+                misc(false, false),
+                Ok(SmtpDetails {
+                    is_deliverable: false,
+                    ..deliverable()
+                }),
+                MyReachable::Invalid,
+            ),
+            (
+                "disabled",
+                misc(false, false),
+                Ok(SmtpDetails {
+                    is_disabled: true,
+                    ..deliverable()
+                }),
+                MyReachable::Invalid,
+            ),
+            (
+                "no SMTP connection",
+                misc(false, false),
+                Ok(SmtpDetails::default()),
+                MyReachable::Invalid,
+            ),
+            (
+                "SMTP error",
+                misc(true, true),
+                Err(SmtpError::ShuttingDown),
+                MyReachable::Unknown,
+            ),
+        ];
+
+        for (case, misc, smtp, expected) in table {
+            assert_eq!(baseline(&misc, &smtp), expected, "baseline: {}", case);
+            assert_eq!(
+                Policy::default().evaluate(&misc, &smtp),
+                expected,
+                "default: {}",
+                case
+            );
+        }
+    }
+
+    #[test]
+    fn default_matches_the_baseline_on_every_flag_combination() {
+        for bits in 0..256u16 {
+            let bit = |n: u16| bits & (1 << n) != 0;
+            let misc = misc(bit(0), bit(1));
+            let smtp = Ok(SmtpDetails {
+                can_connect_smtp: bit(2),
+                has_full_inbox: bit(3),
+                is_catch_all: bit(4),
+                is_deliverable: bit(5),
+                is_disabled: bit(6),
+                is_banned: bit(7),
+                ..Default::default()
+            });
+
+            assert_eq!(
+                Policy::default().evaluate(&misc, &smtp),
+                baseline(&misc, &smtp),
+                "{:?} {:?}",
+                misc,
+                smtp
+            );
+        }
+    }
+
+    #[test]
+    fn marketing_accepts_role_accounts_and_reports_bans() {
+        let policy = Policy::marketing();
+        assert_eq!(
+            policy.evaluate(&misc(false, true), &Ok(deliverable())),
+            MyReachable::Safe
+        );
+        assert_eq!(
+            policy.evaluate(
+                &misc(false, false),
+                &Ok(SmtpDetails {
+                    is_catch_all: true,
+                    is_banned: true,
+                    ..deliverable()
+                })
+            ),
+            MyReachable::Banned
+        );
+        assert_eq!(
+            policy.evaluate(&misc(true, false), &Ok(deliverable())),
+            MyReachable::Risky
+        );
+    }
+
+    #[test]
+    fn signup_rejects_catch_all_and_disposable_domains() {
+        let policy = Policy::signup();
+        let catch_all = Ok(SmtpDetails {
+            is_catch_all: true,
+            ..deliverable()
+        });
+        assert_eq!(
+            policy.evaluate(&misc(false, false), &catch_all),
+            MyReachable::Invalid
+        );
+        assert_eq!(
+            policy.evaluate(&misc(true, false), &Ok(deliverable())),
+            MyReachable::Invalid
+        );
+        assert_eq!(
+            policy.evaluate(&misc(false, true), &Ok(deliverable())),
+            MyReachable::Risky
+        );
+        assert_eq!(
+            policy.evaluate(&misc(false, false), &Ok(deliverable())),
+            MyReachable::Safe
+        );
+    }
+
+    #[test]
+    fn profiles_are_selectable_by_name() {
+        let policies = Policies::default();
+        for name in &[DEFAULT_PROFILE, "marketing", "signup"] {
+            assert_eq!(
+                policies.get(name).map(|policy| policy.name.as_str()),
+                Some(*name)
+            );
+        }
+        assert!(policies.get("unknown").is_none());
+    }
+
+    fn policy_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "extant-policy-{}-{}.json",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, contents).expect("temp file written");
+
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn loads_policies_from_a_file() {
+        let path = policy_file(
+            "custom",
+            r#"[
+                {"name": "signup", "rules": [], "fallback": "Risky"},
+                {"name": "strict", "rules": [{"any": ["catch_all"], "verdict": "Invalid"}], "fallback": "Safe"}
+            ]"#,
+        );
+        let policies = Policies::load(&path).expect("valid policy file");
+        fs::remove_file(&path).ok();
+
+        assert_eq!(
+            policies
+                .get("signup")
+                .map(|policy| policy.evaluate(&misc(false, false), &Ok(deliverable()))),
+            Some(MyReachable::Risky)
+        );
+        assert_eq!(
+            policies.get("strict").map(|policy| policy.rules.len()),
+            Some(1)
+        );
+        assert_eq!(policies.get(DEFAULT_PROFILE), Some(&Policy::default()));
+    }
+
+    #[test]
+    fn refuses_a_bad_policy_file() {
+        let path = policy_file("bad", r#"[{"name": "oops", "rules": [{"any": ["nope"]}]}]"#);
+        let result = Policies::load(&path);
+        fs::remove_file(&path).ok();
+        assert!(matches!(result, Err(PolicyError::SerdeError(_))));
+
+        assert!(matches!(
+            Policies::load("/nonexistent/policies.json"),
+            Err(PolicyError::IoError(_))
+        ));
+    }
+}
@@ -0,0 +1,558 @@
+// Reachability scoring.
+//
+// `MyReachable` only has five buckets, so a role account on a perfectly valid
+// mailbox looks the same as an address on a catch-all domain. The score below
+// adds up weighted contributions from every signal we collect, and keeps a
+// human-readable reason for each one that moved it.
+//
+// `is_reachable` is the bucket the score falls in, see `bucket`. A score we
+// don't trust is Unknown, or Banned if the server blocked us; otherwise it's
+// Safe from `SAFE_SCORE`, Risky from `RISKY_SCORE` and Invalid below that.
+//
+// Checks that stop early score straight into their bucket. For full checks
+// the request's `Policy` has the last word, so teams can tune verdicts and
+// the default keeps the historical ones: when the signals land outside the
+// policy's verdict, `in_bucket` moves the score to the edge of its band and
+// says so in the reasons.
+
+use check_if_email_exists::misc::MiscDetails;
+use serde::{Deserialize, Serialize};
+
+use crate::lists::Action;
+use crate::mail::MyReachable;
+use crate::smtp::{Provider, SmtpDetails, SmtpError};
+use crate::syntax::SyntaxProblem;
+
+const SYNTAX_WEIGHT: i32 = 10;
+const DNS_WEIGHT: i32 = 10;
+const SMTP_DELIVERABLE_WEIGHT: i32 = 80;
+const SMTP_FULL_INBOX_WEIGHT: i32 = 20;
+const SMTP_INCONCLUSIVE_WEIGHT: i32 = 30;
+const CATCH_ALL_WEIGHT: i32 = -35;
+const DISPOSABLE_WEIGHT: i32 = -50;
+const ROLE_ACCOUNT_WEIGHT: i32 = -35;
+const GATEWAY_WEIGHT: i32 = -10;
+const YAHOO_API_WEIGHT: i32 = -10;
+
+/// Lowest score in the Safe bucket.
+pub const SAFE_SCORE: u8 = 70;
+/// Lowest score in the Risky bucket.
+pub const RISKY_SCORE: u8 = 40;
+/// Lowest confidence at which a score is bucketed at all.
+pub const CONCLUSIVE_CONFIDENCE: u8 = 30;
+
+/// MX host suffixes of secure email gateways. These accept or defer every
+/// recipient at `RCPT TO` and filter afterwards, so a positive answer from
+/// them says less about the mailbox.
+const GATEWAY_MX_SUFFIXES: [&str; 9] = [
+    ".pphosted.com",
+    ".ppe-hosted.com",
+    ".mimecast.com",
+    ".barracudanetworks.com",
+    ".messagelabs.com",
+    ".iphmx.com",
+    ".mailcontrol.com",
+    ".trendmicro.com",
+    ".fireeyecloud.com",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReachabilityScore {
+    /// How likely the address is to receive mail, from 0 to 100.
+    pub score: u8,
+    /// How much we trust `score`, from 0 to 100.
+    pub confidence: u8,
+    /// Why the score is what it is, one entry per contributing signal.
+    pub reasons: Vec<String>,
+    /// The mail server blocked us, which is why confidence is low.
+    #[serde(skip)]
+    blocked: bool,
+}
+
+/// Is this MX host a secure email gateway rather than the mailbox provider?
+pub fn is_gateway_mx(host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_lowercase();
+
+    GATEWAY_MX_SUFFIXES
+        .iter()
+        .any(|suffix| host.ends_with(suffix))
+}
+
+struct Builder {
+    score: i32,
+    confidence: i32,
+    reasons: Vec<String>,
+    blocked: bool,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Builder {
+            score: 0,
+            confidence: 100,
+            reasons: vec![],
+            blocked: false,
+        }
+    }
+
+    fn add(&mut self, weight: i32, reason: &str) -> &mut Self {
+        self.score += weight;
+        self.reasons.push(format!("{:+} {}", weight, reason));
+        self
+    }
+
+    fn confidence(&mut self, confidence: i32) -> &mut Self {
+        self.confidence = self.confidence.min(confidence);
+        self
+    }
+
+    fn blocked(&mut self) -> &mut Self {
+        self.blocked = true;
+        self
+    }
+
+    fn build(&mut self) -> ReachabilityScore {
+        ReachabilityScore {
+            score: self.score.clamp(0, 100) as u8,
+            confidence: self.confidence.clamp(0, 100) as u8,
+            reasons: self.reasons.clone(),
+            blocked: self.blocked,
+        }
+    }
+}
+
+impl ReachabilityScore {
+    /// The `is_reachable` bucket this score falls in.
+    pub fn bucket(&self) -> MyReachable {
+        match (self.confidence, self.score) {
+            (confidence, _) if confidence < CONCLUSIVE_CONFIDENCE && self.blocked => {
+                MyReachable::Banned
+            }
+            (confidence, _) if confidence < CONCLUSIVE_CONFIDENCE => MyReachable::Unknown,
+            (_, score) if score >= SAFE_SCORE => MyReachable::Safe,
+            (_, score) if score >= RISKY_SCORE => MyReachable::Risky,
+            _ => MyReachable::Invalid,
+        }
+    }
+
+    /// Move the score into `bucket`, which `why` decided on, if the signals
+    /// put it elsewhere.
+    pub fn in_bucket(mut self, bucket: MyReachable, why: &str) -> Self {
+        if self.bucket() == bucket {
+            return self;
+        }
+
+        let score = match bucket {
+            MyReachable::Safe => self.score.max(SAFE_SCORE),
+            MyReachable::Risky => self.score.clamp(RISKY_SCORE, SAFE_SCORE - 1),
+            MyReachable::Invalid => self.score.min(RISKY_SCORE - 1),
+            MyReachable::Unknown | MyReachable::Banned => self.score,
+        };
+        self.reasons.push(format!(
+            "{:+} {}",
+            i32::from(score) - i32::from(self.score),
+            why
+        ));
+        self.score = score;
+        self.confidence = match bucket {
+            MyReachable::Unknown | MyReachable::Banned => {
+                self.confidence.min(CONCLUSIVE_CONFIDENCE - 1)
+            }
+            _ => self.confidence.max(CONCLUSIVE_CONFIDENCE),
+        };
+        self.blocked = bucket == MyReachable::Banned;
+
+        self
+    }
+
+    /// The address failed the syntax check.
+    pub fn invalid_syntax(problem: SyntaxProblem) -> Self {
+        Builder::new()
+            .add(0, &format!("address has invalid syntax: {}", problem))
+            .build()
+    }
+
+    /// The domain does not accept mail.
+    pub fn no_mx() -> Self {
+        Builder::new()
+            .add(SYNTAX_WEIGHT, "address has valid syntax")
+            .add(0, "domain has no MX records")
+            .confidence(90)
+            .build()
+    }
+
+    /// The MX lookup itself failed, so we know nothing past the syntax.
+    pub fn dns_error() -> Self {
+        Builder::new()
+            .add(SYNTAX_WEIGHT, "address has valid syntax")
+            .add(0, "MX lookup failed")
+            .confidence(10)
+            .build()
+    }
+
+    /// An allow or deny list rule decided the verdict before any check.
+    pub fn listed(action: Action, reason: &str) -> Self {
+        let mut builder = Builder::new();
+        builder.add(SYNTAX_WEIGHT, "address has valid syntax");
+        match action {
+            Action::Allow => builder.add(100 - SYNTAX_WEIGHT, reason),
+            Action::Deny => builder.add(-SYNTAX_WEIGHT, reason),
+            Action::SkipSmtp => builder
+                .add(DNS_WEIGHT, "domain has MX records")
+                .add(0, reason)
+                .confidence(10),
+        };
+
+        builder.build()
+    }
+
+    /// Score an address not checked because the server was shutting down.
+    pub fn shutting_down() -> Self {
+        Builder::new()
+            .add(0, "server shut down before the check")
+            .confidence(0)
+            .build()
+    }
+
+    /// Score an address that made it through the syntax and DNS stages.
+    pub fn from_checks(
+        misc: &MiscDetails,
+        smtp: &Result<SmtpDetails, SmtpError>,
+        mx_host: &str,
+    ) -> Self {
+        let mut builder = Builder::new();
+        builder
+            .add(SYNTAX_WEIGHT, "address has valid syntax")
+            .add(DNS_WEIGHT, "domain has MX records");
+
+        match smtp {
+            Ok(smtp) if smtp.is_banned => {
+                builder
+                    .add(SMTP_INCONCLUSIVE_WEIGHT, "mail server blocked our IP")
+                    .confidence(20)
+                    .blocked();
+            }
+            Ok(smtp) if smtp.has_full_inbox => {
+                builder.add(SMTP_FULL_INBOX_WEIGHT, "mailbox is full");
+            }
+            Ok(smtp) if smtp.is_disabled => {
+                builder.add(0, "mailbox is disabled");
+            }
+            Ok(smtp) if !smtp.can_connect_smtp || !smtp.is_deliverable => {
+                builder.add(0, "mail server rejected the mailbox");
+            }
+            Ok(_) => {
+                builder.add(SMTP_DELIVERABLE_WEIGHT, "mail server accepted the mailbox");
+            }
+            Err(SmtpError::SmtpUtf8Unsupported) => {
+                builder.add(
+                    0,
+                    "mail server lacks SMTPUTF8, so it can't receive mail for this non-ASCII address",
+                );
+            }
+            Err(_) => {
+                builder
+                    .add(SMTP_INCONCLUSIVE_WEIGHT, "SMTP check was inconclusive")
+                    .confidence(20);
+            }
+        }
+
+        if let Ok(smtp) = smtp {
+            if smtp.is_catch_all {
+                builder
+                    .add(CATCH_ALL_WEIGHT, "domain accepts mail for any address")
+                    .confidence(40);
+            }
+
+            match smtp.provider {
+                Provider::Yahoo => {
+                    builder
+                        .add(YAHOO_API_WEIGHT, "verified through Yahoo's signup API")
+                        .confidence(70);
+                }
+                Provider::GoogleConsumer => {
+                    builder.add(0, "Gmail answers RCPT TO reliably");
+                }
+                Provider::GoogleWorkspace | Provider::Generic => {}
+            }
+        }
+
+        if misc.is_disposable {
+            builder.add(DISPOSABLE_WEIGHT, "address is on a disposable domain");
+        }
+
+        if misc.is_role_account {
+            builder.add(ROLE_ACCOUNT_WEIGHT, "address is a role account");
+        }
+
+        if is_gateway_mx(mx_host) {
+            builder
+                .add(GATEWAY_WEIGHT, "MX is a secure email gateway")
+                .confidence(60);
+        }
+
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::Policy;
+
+    fn misc(is_disposable: bool, is_role_account: bool) -> MiscDetails {
+        MiscDetails {
+            is_disposable,
+            is_role_account,
+        }
+    }
+
+    fn deliverable() -> Result<SmtpDetails, SmtpError> {
+        Ok(SmtpDetails {
+            can_connect_smtp: true,
+            is_deliverable: true,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn builder_clamps_score_and_keeps_lowest_confidence() {
+        let score = Builder::new()
+            .add(80, "up")
+            .add(80, "up again")
+            .confidence(60)
+            .confidence(90)
+            .build();
+        assert_eq!(score.score, 100);
+        assert_eq!(score.confidence, 60);
+        assert_eq!(score.reasons, vec!["+80 up", "+80 up again"]);
+
+        let score = Builder::new().add(-50, "down").build();
+        assert_eq!(score.score, 0);
+        assert_eq!(score.reasons, vec!["-50 down"]);
+    }
+
+    #[test]
+    fn deliverable_mailbox_scores_full() {
+        let score =
+            ReachabilityScore::from_checks(&misc(false, false), &deliverable(), "mx.example.com");
+        assert_eq!(score.score, 100);
+        assert_eq!(score.confidence, 100);
+    }
+
+    #[test]
+    fn role_account_scores_below_plain_mailbox() {
+        let score =
+            ReachabilityScore::from_checks(&misc(false, true), &deliverable(), "mx.example.com");
+        assert_eq!(score.score, 65);
+        assert!(score
+            .reasons
+            .iter()
+            .any(|reason| reason.contains("role account")));
+    }
+
+    #[test]
+    fn catch_all_lowers_score_and_confidence() {
+        let smtp = Ok(SmtpDetails {
+            can_connect_smtp: true,
+            is_deliverable: true,
+            is_catch_all: true,
+            ..Default::default()
+        });
+        let score = ReachabilityScore::from_checks(&misc(false, false), &smtp, "mx.example.com");
+        assert_eq!(score.score, 65);
+        assert_eq!(score.confidence, 40);
+    }
+
+    #[test]
+    fn rejected_mailbox_scores_low() {
+        let smtp = Ok(SmtpDetails {
+            can_connect_smtp: true,
+            ..Default::default()
+        });
+        let score = ReachabilityScore::from_checks(&misc(false, false), &smtp, "mx.example.com");
+        assert_eq!(score.score, 20);
+    }
+
+    #[test]
+    fn smtp_error_is_inconclusive() {
+        let score = ReachabilityScore::from_checks(
+            &misc(false, false),
+            &Err(SmtpError::ShuttingDown),
+            "mx.example.com",
+        );
+        assert_eq!(score.score, 50);
+        assert_eq!(score.confidence, 20);
+    }
+
+    #[test]
+    fn missing_smtputf8_is_a_definite_invalid() {
+        let smtp = Err(SmtpError::SmtpUtf8Unsupported);
+        let score = ReachabilityScore::from_checks(&misc(false, false), &smtp, "mx.example.com");
+        assert_eq!(score.score, 20);
+        assert_eq!(score.confidence, 100);
+        assert_eq!(score.bucket(), MyReachable::Invalid);
+        assert_eq!(
+            Policy::default().evaluate(&misc(false, false), &smtp),
+            MyReachable::Invalid
+        );
+    }
+
+    #[test]
+    fn gateway_mx_lowers_confidence() {
+        assert!(is_gateway_mx("mx0a-001.pphosted.com."));
+        assert!(!is_gateway_mx("aspmx.l.google.com"));
+
+        let score =
+            ReachabilityScore::from_checks(&misc(false, false), &deliverable(), "mx1.Mimecast.com");
+        assert_eq!(score.score, 90);
+        assert_eq!(score.confidence, 60);
+    }
+
+    #[test]
+    fn banned_is_its_own_bucket() {
+        let smtp = Ok(SmtpDetails {
+            can_connect_smtp: true,
+            is_banned: true,
+            ..Default::default()
+        });
+        let score = ReachabilityScore::from_checks(&misc(false, false), &smtp, "mx.example.com");
+        assert_eq!(score.bucket(), MyReachable::Banned);
+    }
+
+    #[test]
+    fn buckets_follow_score_and_confidence() {
+        let bucket = |score, confidence| {
+            Builder::new()
+                .add(score, "signals")
+                .confidence(confidence)
+                .build()
+                .bucket()
+        };
+        assert_eq!(bucket(100, 100), MyReachable::Safe);
+        assert_eq!(bucket(70, 30), MyReachable::Safe);
+        assert_eq!(bucket(69, 100), MyReachable::Risky);
+        assert_eq!(bucket(40, 100), MyReachable::Risky);
+        assert_eq!(bucket(39, 100), MyReachable::Invalid);
+        assert_eq!(bucket(0, 100), MyReachable::Invalid);
+        assert_eq!(bucket(100, 29), MyReachable::Unknown);
+        assert_eq!(bucket(0, 0), MyReachable::Unknown);
+    }
+
+    #[test]
+    fn in_bucket_moves_the_score_and_says_why() {
+        let score = ReachabilityScore::from_checks(&misc(false, true), &deliverable(), "mx")
+            .in_bucket(MyReachable::Safe, "marketing policy rates the address safe");
+        assert_eq!(score.score, SAFE_SCORE);
+        assert_eq!(score.bucket(), MyReachable::Safe);
+        assert_eq!(
+            score.reasons.last().map(String::as_str),
+            Some("+5 marketing policy rates the address safe")
+        );
+
+        let score = ReachabilityScore::from_checks(&misc(false, false), &deliverable(), "mx")
+            .in_bucket(
+                MyReachable::Unknown,
+                "custom policy rates the address unknown",
+            );
+        assert_eq!(score.score, 100);
+        assert_eq!(score.bucket(), MyReachable::Unknown);
+    }
+
+    #[test]
+    fn in_bucket_leaves_a_score_in_its_bucket_alone() {
+        let score = ReachabilityScore::from_checks(&misc(false, false), &deliverable(), "mx");
+        assert_eq!(
+            score.clone().in_bucket(MyReachable::Safe, "policy agrees"),
+            score
+        );
+    }
+
+    #[test]
+    fn every_policy_verdict_is_the_score_bucket() {
+        let flags = [false, true];
+        for policy in &[Policy::default(), Policy::marketing(), Policy::signup()] {
+            for &is_disposable in &flags {
+                for &is_role_account in &flags {
+                    let misc = misc(is_disposable, is_role_account);
+                    for smtp in smtp_outcomes() {
+                        let verdict = policy.evaluate(&misc, &smtp);
+                        let score = ReachabilityScore::from_checks(&misc, &smtp, "mx")
+                            .in_bucket(verdict, "policy");
+                        assert_eq!(
+                            score.bucket(),
+                            verdict,
+                            "{} {:?} {:?}",
+                            policy.name,
+                            misc,
+                            smtp
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every combination of the SMTP flags, and an SMTP error.
+    fn smtp_outcomes() -> Vec<Result<SmtpDetails, SmtpError>> {
+        let mut outcomes = vec![Err(SmtpError::ShuttingDown)];
+        for bits in 0..64u8 {
+            let bit = |n: u8| bits & (1 << n) != 0;
+            outcomes.push(Ok(SmtpDetails {
+                can_connect_smtp: bit(0),
+                has_full_inbox: bit(1),
+                is_catch_all: bit(2),
+                is_deliverable: bit(3),
+                is_disabled: bit(4),
+                is_banned: bit(5),
+                ..Default::default()
+            }));
+        }
+
+        outcomes
+    }
+
+    #[test]
+    fn list_rules_decide_the_score() {
+        assert_eq!(
+            ReachabilityScore::listed(Action::Allow, "allowed").score,
+            100
+        );
+        assert_eq!(ReachabilityScore::listed(Action::Deny, "denied").score, 0);
+
+        let skipped = ReachabilityScore::listed(Action::SkipSmtp, "skipped");
+        assert_eq!(skipped.score, 20);
+        assert_eq!(skipped.confidence, 10);
+
+        for action in &[Action::Allow, Action::Deny, Action::SkipSmtp] {
+            assert_eq!(
+                ReachabilityScore::listed(*action, "listed").bucket(),
+                action.verdict()
+            );
+        }
+    }
+
+    #[test]
+    fn early_returns_carry_a_score() {
+        assert_eq!(
+            ReachabilityScore::invalid_syntax(SyntaxProblem::Rejected).score,
+            0
+        );
+        assert_eq!(ReachabilityScore::no_mx().score, 10);
+        assert_eq!(ReachabilityScore::dns_error().confidence, 10);
+
+        assert_eq!(
+            ReachabilityScore::invalid_syntax(SyntaxProblem::Rejected).bucket(),
+            MyReachable::Invalid
+        );
+        assert_eq!(ReachabilityScore::no_mx().bucket(), MyReachable::Invalid);
+        assert_eq!(
+            ReachabilityScore::dns_error().bucket(),
+            MyReachable::Unknown
+        );
+        assert_eq!(
+            ReachabilityScore::shutting_down().bucket(),
+            MyReachable::Unknown
+        );
+    }
+}
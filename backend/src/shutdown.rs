@@ -0,0 +1,181 @@
+// Graceful shutdown.
+//
+// On SIGTERM or SIGINT, `main` calls `begin`: new checks are refused and
+// `/health/ready` fails, while running ones get `server.drain_timeout`
+// seconds to finish. Then `cut_short` fires: SMTP sessions abandon their
+// conversation and send QUIT, addresses not started yet are reported Unknown
+// without a check, and the addresses left in every batch are appended to
+// `server.unfinished_file`, so they can be submitted again.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::{self, Either};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::config;
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Set to true by `cut_short`. The receiver is kept here so sending never
+/// fails.
+static CUT_SHORT: Lazy<(watch::Sender<bool>, watch::Receiver<bool>)> =
+    Lazy::new(|| watch::channel(false));
+
+/// Stop taking new checks.
+pub fn begin() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// The drain deadline passed: cut running checks short.
+pub fn cut_short() {
+    begin();
+    let _ = CUT_SHORT.0.send(true);
+}
+
+pub fn is_cut_short() -> bool {
+    *CUT_SHORT.1.borrow()
+}
+
+/// Resolves when `cut_short` is called.
+pub async fn deadline() {
+    let mut cut_short = CUT_SHORT.1.clone();
+    while !*cut_short.borrow() {
+        if cut_short.changed().await.is_err() {
+            return future::pending().await;
+        }
+    }
+}
+
+/// Run `fut` until it's done, or until `cut_short` is called, in which case
+/// it's dropped and `None` is returned.
+pub async fn or_deadline<F: Future>(fut: F) -> Option<F::Output> {
+    let cut = deadline();
+    futures::pin_mut!(fut, cut);
+
+    match future::select(fut, cut).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+/// Wait for SIGTERM or SIGINT.
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("Listening for SIGTERM works. qed.");
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+static NEXT_BATCH: AtomicU64 = AtomicU64::new(0);
+
+/// Addresses of each running batch not checked yet.
+static PENDING: Lazy<Mutex<HashMap<u64, Vec<String>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Keeps track of the addresses of a batch while it runs. If the batch is
+/// cut short, the addresses left are saved when it's dropped.
+pub struct Batch {
+    id: u64,
+}
+
+impl Batch {
+    pub fn start(emails: &[&String]) -> Batch {
+        let id = NEXT_BATCH.fetch_add(1, Ordering::SeqCst);
+        PENDING
+            .lock()
+            .expect("Pending lock is never poisoned. qed.")
+            .insert(id, emails.iter().map(|email| email.to_string()).collect());
+
+        Batch { id }
+    }
+
+    pub fn done(&self, email: &str) {
+        if let Some(pending) = PENDING
+            .lock()
+            .expect("Pending lock is never poisoned. qed.")
+            .get_mut(&self.id)
+        {
+            if let Some(index) = pending.iter().position(|pending| pending == email) {
+                pending.swap_remove(index);
+            }
+        }
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        let pending = PENDING
+            .lock()
+            .expect("Pending lock is never poisoned. qed.")
+            .remove(&self.id)
+            .unwrap_or_default();
+
+        if is_cut_short() && !pending.is_empty() {
+            match save_unfinished(&pending) {
+                Ok(true) => info!("Saved {} unfinished addresses", pending.len()),
+                Ok(false) => warn!(
+                    "{} addresses unfinished, set server.unfinished_file to keep them",
+                    pending.len()
+                ),
+                Err(err) => warn!(
+                    "Saving {} unfinished addresses failed: {}",
+                    pending.len(),
+                    err
+                ),
+            }
+        }
+    }
+}
+
+/// One line of `server.unfinished_file`.
+#[derive(Serialize)]
+struct Unfinished<'a> {
+    /// Seconds since the Unix epoch.
+    unfinished_at: u64,
+    emails: &'a [String],
+}
+
+/// Append `emails` to `server.unfinished_file`. Returns false if it's not
+/// set.
+fn save_unfinished(emails: &[String]) -> io::Result<bool> {
+    let path = match &config::get().server.unfinished_file {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+
+    let line = serde_json::to_string(&Unfinished {
+        unfinished_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0),
+        emails,
+    })?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)?;
+
+    Ok(true)
+}
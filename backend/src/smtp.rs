@@ -16,14 +16,23 @@
 
 // https://github.com/reacherhq/check-if-email-exists/blob/master/core/src/smtp/mod.rs
 
-use std::{iter, str::FromStr, time::Duration};
+use std::{
+    fmt,
+    future::Future,
+    iter,
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use crate::yahoo;
+use crate::{config, google, metrics, pii, shutdown, yahoo};
 use crate::{util::ser_with_display, yahoo::YahooError};
 use async_smtp::{
     smtp::{
-        client::net::NetworkStream, commands::*, error::Error as AsyncSmtpError,
-        extension::ClientId,
+        client::net::NetworkStream,
+        commands::*,
+        error::Error as AsyncSmtpError,
+        extension::{ClientId, Extension, MailParameter, ServerInfo},
     },
     ClientSecurity, EmailAddress, SmtpClient, SmtpTransport,
 };
@@ -38,6 +47,21 @@ use rand::{distributions::Alphanumeric, prelude::SmallRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use trust_dns_proto::rr::Name;
 
+/// Mailbox provider detected from the domain or its MX records.
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    /// Any provider without a dedicated verification strategy.
+    #[default]
+    Generic,
+    /// Yahoo, verified through their signup API.
+    Yahoo,
+    /// Consumer Gmail (gmail.com, googlemail.com).
+    GoogleConsumer,
+    /// Google Workspace, a custom domain hosted on Google's MX.
+    GoogleWorkspace,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SmtpDetails {
     /// Are we able to connect to the SMTP server?
@@ -52,6 +76,8 @@ pub struct SmtpDetails {
     pub is_disabled: bool,
     /// Has the email provider banned us?
     pub is_banned: bool,
+    /// Which provider strategy produced these details.
+    pub provider: Provider,
 }
 
 impl Default for SmtpDetails {
@@ -63,6 +89,7 @@ impl Default for SmtpDetails {
             is_deliverable: false,
             is_disabled: false,
             is_banned: false,
+            provider: Provider::Generic,
         }
     }
 }
@@ -81,6 +108,20 @@ pub enum SmtpError {
     TimeoutError(future::TimeoutError),
     /// Error when verifying a Yahoo email.
     YahooError(YahooError),
+    /// The address has a non-ASCII local part, but the server doesn't
+    /// announce SMTPUTF8, so it can't receive mail for it. Unlike the other
+    /// errors this is a definite answer, see `is_definite`.
+    SmtpUtf8Unsupported,
+    /// The server shut down before the check finished.
+    ShuttingDown,
+}
+
+impl SmtpError {
+    /// Does this error say the mailbox can't receive mail, rather than that
+    /// we couldn't find out?
+    pub fn is_definite(&self) -> bool {
+        matches!(self, SmtpError::SmtpUtf8Unsupported)
+    }
 }
 
 impl From<AsyncSmtpError> for SmtpError {
@@ -107,20 +148,25 @@ impl From<YahooError> for SmtpError {
     }
 }
 
-struct Deliverability {
+pub(crate) struct Deliverability {
     /// Is this email account's inbox full?
-    has_full_inbox: bool,
+    pub(crate) has_full_inbox: bool,
     /// Can we send an email to this address?
-    is_deliverable: bool,
+    pub(crate) is_deliverable: bool,
     /// Is the email blocked or disabled by the provider?
-    is_disabled: bool,
-    is_banned: bool,
+    pub(crate) is_disabled: bool,
+    pub(crate) is_banned: bool,
 }
 
 macro_rules! try_smtp (
   ($res: expr, $client: ident, $host: expr, $port: expr) => ({
   if let Err(err) = $res {
-    log::debug!("Closing {}:{}, because of error '{}'.", $host, $port, err);
+    log::debug!(
+      "Closing {}:{}, because of error '{}'.",
+      $host,
+      $port,
+      pii::text(&err.to_string())
+    );
     $client.close().await?;
 
     return Err(err.into());
@@ -128,18 +174,68 @@ macro_rules! try_smtp (
   })
 );
 
-async fn connect_to_host(
+/// `RCPT TO` taking the address as a plain string. `EmailAddress` refuses
+/// non-ASCII characters, which SMTPUTF8 addresses need. The address must have
+/// passed the syntax check already.
+pub(crate) struct RcptTo<'a>(pub(crate) &'a str);
+
+impl fmt::Display for RcptTo<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RCPT TO:<{}>\r\n", self.0)
+    }
+}
+
+/// Does this address need the SMTPUTF8 extension (RFC 6531)?
+pub fn needs_smtputf8(email: &str) -> bool {
+    !email.is_ascii()
+}
+
+/// Connect to `host` and send `MAIL FROM`, ready for `RCPT TO`.
+pub(crate) async fn connect_to_host(
     host: &Name,
     port: u16,
     input: &CheckEmailInput,
+    smtputf8: bool,
 ) -> Result<SmtpTransport, SmtpError> {
-    let mut smtp_client =
-        SmtpClient::with_security((host.to_utf8().as_ref(), port), ClientSecurity::None)
-            .await?
-            // FIXME Do not clone?
-            .hello_name(ClientId::Domain(input.hello_name.clone()))
-            .timeout(Some(Duration::new(30, 0))) // Set timeout to 30s
-            .into_transport();
+    let start = Instant::now();
+    let result = connect_and_mail_from(host, port, input, smtputf8).await;
+    metrics::observe_connect(start);
+
+    result
+}
+
+async fn connect_and_mail_from(
+    host: &Name,
+    port: u16,
+    input: &CheckEmailInput,
+    smtputf8: bool,
+) -> Result<SmtpTransport, SmtpError> {
+    // `with_security` resolves the MX host, which we only connect to
+    // directly. Behind a proxy the address just shows up in logs and the
+    // proxy resolves the host itself, so failing to resolve it here is fine.
+    let smtp_client = match SmtpClient::with_security(
+        (host.to_utf8().as_ref(), port),
+        ClientSecurity::None,
+    )
+    .await
+    {
+        Ok(smtp_client) => smtp_client,
+        Err(err) if input.proxy.is_some() => {
+            log::debug!(
+                "Cannot resolve {} locally, leaving it to the proxy: {}",
+                host,
+                err
+            );
+            SmtpClient::with_security(SocketAddr::from(([0, 0, 0, 0], port)), ClientSecurity::None)
+                .await?
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let mut smtp_client = smtp_client
+        // FIXME Do not clone?
+        .hello_name(ClientId::Domain(input.hello_name.clone()))
+        .timeout(Some(config::get().smtp_connect_timeout()))
+        .into_transport();
 
     // Connect to the host. If the proxy argument is set, use it.
     log::debug!("Connecting to {}:{}", host, port);
@@ -164,18 +260,45 @@ async fn connect_to_host(
         try_smtp!(smtp_client.connect().await, smtp_client, host, port);
     }
 
+    // The EHLO response from `connect()` isn't exposed, so ask again when we
+    // need to know whether the server supports SMTPUTF8.
+    let mut mail_parameters = vec![];
+    if smtputf8 {
+        let supports_smtputf8 = smtp_client
+            // FIXME Do not clone?
+            .command(EhloCommand::new(ClientId::Domain(input.hello_name.clone())))
+            .await
+            .ok()
+            .and_then(|response| ServerInfo::from_response(&response).ok())
+            .map(|server_info| server_info.supports_feature(Extension::SmtpUtfEight))
+            .unwrap_or(false);
+
+        if !supports_smtputf8 {
+            log::debug!(
+                "Closing {}:{}, because SMTPUTF8 is not supported.",
+                host,
+                port
+            );
+            let _ = smtp_client.close().await;
+
+            return Err(SmtpError::SmtpUtf8Unsupported);
+        }
+
+        mail_parameters.push(MailParameter::SmtpUtfEight);
+    }
+
     // "MAIL FROM: user@example.org"
     let from_email = EmailAddress::from_str(input.from_email.as_ref()).unwrap_or_else(|_| {
         log::warn!(
             "Inputted from_email \"{}\" is not a valid email, using \"user@example.org\" instead",
-            input.from_email
+            pii::email(&input.from_email)
         );
         EmailAddress::from_str("user@example.org").expect("This is a valid email. qed.")
     });
     try_smtp!(
         smtp_client
             // FIXME Do not clone?
-            .command(MailCommand::new(Some(from_email.clone()), vec![],))
+            .command(MailCommand::new(Some(from_email.clone()), mail_parameters))
             .await,
         smtp_client,
         host,
@@ -185,7 +308,7 @@ async fn connect_to_host(
     Ok(smtp_client)
 }
 
-fn is_io_incomplete_smtp_error<T>(result: &Result<T, SmtpError>) -> bool {
+pub(crate) fn is_io_incomplete_smtp_error<T>(result: &Result<T, SmtpError>) -> bool {
     if let Err(SmtpError::SmtpError(AsyncSmtpError::Io(err))) = result {
         err.to_string().as_str() == "incomplete"
     } else {
@@ -193,37 +316,58 @@ fn is_io_incomplete_smtp_error<T>(result: &Result<T, SmtpError>) -> bool {
     }
 }
 
-async fn smtp_is_catch_all(
-    smtp_client: &mut SmtpTransport,
-    domain: &str,
-) -> Result<bool, SmtpError> {
-    // Create a random 15-char alphanumerical string.
+/// Create an address with a random 15-char alphanumerical local part, which
+/// almost certainly does not exist on `domain`.
+pub(crate) fn random_email(domain: &str) -> String {
     let mut rng = SmallRng::from_entropy();
     let random_email: String = iter::repeat(())
         .map(|()| rng.sample(Alphanumeric))
         .map(char::from)
         .take(15)
         .collect();
-    let random_email = EmailAddress::new(format!("{}@{}", random_email, domain));
 
-    email_deliverable(
-        smtp_client,
-        &random_email.expect("Email is correctly constructed. qed."),
-    )
-    .await
-    .map(|deliverability| deliverability.is_deliverable)
+    format!("{}@{}", random_email, domain)
+}
+
+async fn smtp_is_catch_all(
+    smtp_client: &mut SmtpTransport,
+    domain: &str,
+) -> Result<bool, SmtpError> {
+    email_deliverable(smtp_client, &random_email(domain))
+        .await
+        .map(|deliverability| deliverability.is_deliverable)
+}
+
+/// When the SMTP timeout of a session starting now runs out, if there is one.
+pub(crate) fn smtp_deadline(smtp_timeout: Option<Duration>) -> Option<Instant> {
+    smtp_timeout.map(|smtp_timeout| Instant::now() + smtp_timeout)
+}
+
+/// Run `fut`, bounded by the optional SMTP deadline.
+pub(crate) async fn with_smtp_deadline<T, F>(
+    deadline: Option<Instant>,
+    fut: F,
+) -> Result<T, SmtpError>
+where
+    F: Future<Output = Result<T, SmtpError>>,
+{
+    if let Some(deadline) = deadline {
+        future::timeout(deadline.saturating_duration_since(Instant::now()), fut).await?
+    } else {
+        fut.await
+    }
 }
 
 async fn email_deliverable(
     smtp_client: &mut SmtpTransport,
-    to_email: &EmailAddress,
+    to_email: &str,
 ) -> Result<Deliverability, SmtpError> {
     // "RCPT TO: me@email.com"
-    // FIXME Do not clone?
-    match smtp_client
-        .command(RcptCommand::new(to_email.clone(), vec![]))
-        .await
-    {
+    let start = Instant::now();
+    let response = smtp_client.command(RcptTo(to_email)).await;
+    metrics::observe_rcpt(start);
+
+    match response {
         Ok(_) => {
             // According to RFC 5321, `RCPT TO` command succeeds with 250 and
             // 251 codes only (no 3xx codes at all):
@@ -243,133 +387,165 @@ async fn email_deliverable(
                 is_banned: false,
             })
         }
-        Err(err) => {
-            // We cast to lowercase, because our matched strings below are all
-            // lowercase.
-            let err_string = err.to_string().to_lowercase();
-
-            // Check if the email account has been disabled or blocked.
-            // 554 The email account that you tried to reach is disabled. Learn more at https://support.google.com/mail/?p=DisabledUser"
-            if err_string.contains("disabled")
-				// 554 delivery error: Sorry your message to [email] cannot be delivered. This account has been disabled or discontinued
-				|| err_string.contains("discontinued")
-            {
-                return Ok(Deliverability {
-                    has_full_inbox: false,
-                    is_deliverable: false,
-                    is_disabled: true,
-                    is_banned: false,
-                });
-            }
-
-            // Check if the email account has a full inbox.
-            if err_string.contains("full")
-				|| err_string.contains("insufficient")
-				|| err_string.contains("over quota")
-				|| err_string.contains("space")
-				// 550 user has too many messages on the server
-				|| err_string.contains("too many messages")
-            {
-                return Ok(Deliverability {
-                    has_full_inbox: true,
-                    is_deliverable: false,
-                    is_disabled: false,
-                    is_banned: false,
-                });
-            }
-
-            // Check error messages that say that user can actually receive
-            // emails.
-            // 4.2.1 The user you are trying to contact is receiving mail at a rate that
-            if err_string
-                .contains("the user you are trying to contact is receiving mail at a rate that")
-            {
-                return Ok(Deliverability {
-                    has_full_inbox: false,
-                    is_deliverable: true,
-                    is_disabled: false,
-                    is_banned: false,
-                });
-            }
-
-            // 550 Trend Micro block-list (dynamic ip)
-            if err_string.contains("ers-dul") {
-                return Ok(Deliverability {
-                    has_full_inbox: false,
-                    is_deliverable: false,
-                    is_disabled: false,
-                    is_banned: true,
-                });
-            }
-
-            debug!("FREDRIK");
-            debug!("{}", err_string);
-            // These are the possible error messages when email account doesn't exist.
-            // 550 Address rejected
-            // 550 5.1.1 : Recipient address rejected
-            // 550 5.1.1 : Recipient address rejected: User unknown in virtual alias table
-            // 550 5.1.1 <user@domain.com>: Recipient address rejected: User unknown in relay recipient table
-            if err_string.contains("address rejected")
-				// 550 5.1.1 : Unrouteable address
-				|| err_string.contains("unrouteable")
-				// 550 5.1.1 : The email account that you tried to reach does not exist
-				|| err_string.contains("does not exist")
-				// 550 invalid address
-				// 550 User not local or invalid address – Relay denied
-				|| err_string.contains("invalid address")
-				// 5.1.1 Invalid email address
-				|| err_string.contains("invalid email address")
-				// 550 Invalid recipient
-				|| err_string.contains("invalid recipient")
-				|| err_string.contains("may not exist")
-				|| err_string.contains("recipient invalid")
-				// 550 5.1.1 : Recipient rejected
-				|| err_string.contains("recipient rejected")
-				|| err_string.contains("undeliverable")
-				// 550 User unknown
-				// 550 5.1.1 <EMAIL> User unknown
-				// 550 recipient address rejected: user unknown in local recipient table
-				|| err_string.contains("user unknown")
-				// 550 Unknown user
-				|| err_string.contains("unknown user")
-				// 5.1.1 Recipient unknown <EMAIL>
-				|| err_string.contains("recipient unknown")
-				// 550 5.1.1 No such user - pp
-				// 550 No such user here
-				|| err_string.contains("no such user")
-				// 550 5.1.1 : Mailbox not found
-				// 550 Unknown address error ‘MAILBOX NOT FOUND’
-				|| err_string.contains("not found")
-				// 550 5.1.1 : Invalid mailbox
-				|| err_string.contains("invalid mailbox")
-				// 550 5.1.1 Sorry, no mailbox here by that name
-				|| err_string.contains("no mailbox")
-				// 5.2.0 No such mailbox
-				|| err_string.contains("no such mailbox")
-				// 550 Requested action not taken: mailbox unavailable
-				|| err_string.contains("mailbox unavailable")
-				// 550 5.1.1 Is not a valid mailbox
-				|| err_string.contains("not a valid mailbox")
-				// No such recipient here
-				|| err_string.contains("no such recipient")
-				// 554 delivery error: This user doesn’t have an account
-				|| err_string.contains("have an account")
-            {
-                return Ok(Deliverability {
-                    has_full_inbox: false,
-                    is_deliverable: false,
-                    is_disabled: false,
-                    is_banned: false,
-                });
-            }
-
-            Err(SmtpError::SmtpError(err))
+        Err(err) => parse_smtp_error(err),
+    }
+}
+
+/// Map a rejected `RCPT TO` to a deliverability verdict by matching the
+/// reply text. Returns the error for replies we don't recognise.
+pub(crate) fn parse_smtp_error(err: AsyncSmtpError) -> Result<Deliverability, SmtpError> {
+    // We cast to lowercase, because our matched strings below are all
+    // lowercase.
+    let err_string = err.to_string().to_lowercase();
+
+    // Check if the email account has been disabled or blocked.
+    // 554 The email account that you tried to reach is disabled. Learn more at https://support.google.com/mail/?p=DisabledUser"
+    if err_string.contains("disabled")
+		// 554 delivery error: Sorry your message to [email] cannot be delivered. This account has been disabled or discontinued
+		|| err_string.contains("discontinued")
+    {
+        return Ok(Deliverability {
+            has_full_inbox: false,
+            is_deliverable: false,
+            is_disabled: true,
+            is_banned: false,
+        });
+    }
+
+    // Check if the email account has a full inbox.
+    if err_string.contains("full")
+		|| err_string.contains("insufficient")
+		|| err_string.contains("over quota")
+		|| err_string.contains("space")
+		// 550 user has too many messages on the server
+		|| err_string.contains("too many messages")
+    {
+        return Ok(Deliverability {
+            has_full_inbox: true,
+            is_deliverable: false,
+            is_disabled: false,
+            is_banned: false,
+        });
+    }
+
+    // Check error messages that say that user can actually receive
+    // emails.
+    // 4.2.1 The user you are trying to contact is receiving mail at a rate that
+    if err_string.contains("the user you are trying to contact is receiving mail at a rate that") {
+        return Ok(Deliverability {
+            has_full_inbox: false,
+            is_deliverable: true,
+            is_disabled: false,
+            is_banned: false,
+        });
+    }
+
+    // 550 Trend Micro block-list (dynamic ip)
+    if err_string.contains("ers-dul") {
+        return Ok(Deliverability {
+            has_full_inbox: false,
+            is_deliverable: false,
+            is_disabled: false,
+            is_banned: true,
+        });
+    }
+
+    debug!("{}", pii::text(&err_string));
+    // These are the possible error messages when email account doesn't exist.
+    // 550 Address rejected
+    // 550 5.1.1 : Recipient address rejected
+    // 550 5.1.1 : Recipient address rejected: User unknown in virtual alias table
+    // 550 5.1.1 <user@domain.com>: Recipient address rejected: User unknown in relay recipient table
+    if err_string.contains("address rejected")
+		// 550 5.1.1 : Unrouteable address
+		|| err_string.contains("unrouteable")
+		// 550 5.1.1 : The email account that you tried to reach does not exist
+		|| err_string.contains("does not exist")
+		// 550 invalid address
+		// 550 User not local or invalid address – Relay denied
+		|| err_string.contains("invalid address")
+		// 5.1.1 Invalid email address
+		|| err_string.contains("invalid email address")
+		// 550 Invalid recipient
+		|| err_string.contains("invalid recipient")
+		|| err_string.contains("may not exist")
+		|| err_string.contains("recipient invalid")
+		// 550 5.1.1 : Recipient rejected
+		|| err_string.contains("recipient rejected")
+		|| err_string.contains("undeliverable")
+		// 550 User unknown
+		// 550 5.1.1 <EMAIL> User unknown
+		// 550 recipient address rejected: user unknown in local recipient table
+		|| err_string.contains("user unknown")
+		// 550 Unknown user
+		|| err_string.contains("unknown user")
+		// 5.1.1 Recipient unknown <EMAIL>
+		|| err_string.contains("recipient unknown")
+		// 550 5.1.1 No such user - pp
+		// 550 No such user here
+		|| err_string.contains("no such user")
+		// 550 5.1.1 : Mailbox not found
+		// 550 Unknown address error ‘MAILBOX NOT FOUND’
+		|| err_string.contains("not found")
+		// 550 5.1.1 : Invalid mailbox
+		|| err_string.contains("invalid mailbox")
+		// 550 5.1.1 Sorry, no mailbox here by that name
+		|| err_string.contains("no mailbox")
+		// 5.2.0 No such mailbox
+		|| err_string.contains("no such mailbox")
+		// 550 Requested action not taken: mailbox unavailable
+		|| err_string.contains("mailbox unavailable")
+		// 550 5.1.1 Is not a valid mailbox
+		|| err_string.contains("not a valid mailbox")
+		// No such recipient here
+		|| err_string.contains("no such recipient")
+		// 554 delivery error: This user doesn’t have an account
+		|| err_string.contains("have an account")
+    {
+        return Ok(Deliverability {
+            has_full_inbox: false,
+            is_deliverable: false,
+            is_disabled: false,
+            is_banned: false,
+        });
+    }
+
+    Err(SmtpError::SmtpError(err))
+}
+
+/// How long QUIT may take. It comes after the SMTP deadline on a session cut
+/// short by a timeout.
+const QUIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Close the session with QUIT after `result`, the outcome of its
+/// conversation, or `None` if a shutdown cut it short. Sessions ending in
+/// an error, a timeout included, are closed best-effort.
+pub(crate) async fn finish_session<T>(
+    mut smtp_client: SmtpTransport,
+    result: Option<Result<T, SmtpError>>,
+) -> Result<T, SmtpError> {
+    match result {
+        Some(Ok(value)) => {
+            future::timeout(QUIT_TIMEOUT, smtp_client.close()).await??;
+
+            Ok(value)
+        }
+        // The session may be broken, so don't let QUIT hide the error.
+        Some(Err(err)) => {
+            let _ = future::timeout(QUIT_TIMEOUT, smtp_client.close()).await;
+
+            Err(err)
+        }
+        None => {
+            let _ = future::timeout(QUIT_TIMEOUT, smtp_client.close()).await;
+
+            Err(SmtpError::ShuttingDown)
         }
     }
 }
 
 async fn create_smtp_future(
-    to_email: &EmailAddress,
+    to_email: &str,
     host: &Name,
     port: u16,
     domain: &str,
@@ -377,9 +553,41 @@ async fn create_smtp_future(
 ) -> Result<(bool, Deliverability), SmtpError> {
     // FIXME If the SMTP is not connectable, we should actually return an
     // Ok(SmtpDetails { can_connect_smtp: false, ... }).
-    let mut smtp_client = connect_to_host(host, port, input).await?;
+    let smtputf8 = needs_smtputf8(to_email);
+    let deadline = smtp_deadline(input.smtp_timeout);
+    let mut smtp_client =
+        with_smtp_deadline(deadline, connect_to_host(host, port, input, smtputf8)).await?;
+
+    // The deadline is inside the shutdown one, so a timed out session still
+    // gets its QUIT.
+    let result = shutdown::or_deadline(with_smtp_deadline(
+        deadline,
+        smtp_conversation(
+            &mut smtp_client,
+            to_email,
+            host,
+            port,
+            domain,
+            input,
+            smtputf8,
+        ),
+    ))
+    .await;
+
+    finish_session(smtp_client, result).await
+}
 
-    let is_catch_all = smtp_is_catch_all(&mut smtp_client, domain)
+/// Probe for a catch-all, then check `to_email`.
+async fn smtp_conversation(
+    smtp_client: &mut SmtpTransport,
+    to_email: &str,
+    host: &Name,
+    port: u16,
+    domain: &str,
+    input: &CheckEmailInput,
+    smtputf8: bool,
+) -> Result<(bool, Deliverability), SmtpError> {
+    let is_catch_all = smtp_is_catch_all(smtp_client, domain)
         .await
         .unwrap_or(false);
     let deliverability = if is_catch_all {
@@ -390,7 +598,7 @@ async fn create_smtp_future(
             is_banned: false,
         }
     } else {
-        let mut result = email_deliverable(&mut smtp_client, to_email).await;
+        let mut result = email_deliverable(smtp_client, to_email).await;
 
         // Some SMTP servers automatically close the connection after an error,
         // so we should reconnect to perform a next command.
@@ -400,20 +608,18 @@ async fn create_smtp_future(
         // https://github.com/async-email/async-smtp/issues/37
         if is_io_incomplete_smtp_error(&result) {
             let _ = smtp_client.close().await;
-            smtp_client = connect_to_host(host, port, input).await?;
-            result = email_deliverable(&mut smtp_client, to_email).await;
+            *smtp_client = connect_to_host(host, port, input, smtputf8).await?;
+            result = email_deliverable(smtp_client, to_email).await;
         }
 
         result?
     };
 
-    smtp_client.close().await?;
-
     Ok((is_catch_all, deliverability))
 }
 
 pub async fn check_smtp(
-    to_email: &EmailAddress,
+    to_email: &str,
     host: &Name,
     port: u16,
     domain: &str,
@@ -421,16 +627,24 @@ pub async fn check_smtp(
 ) -> Result<SmtpDetails, SmtpError> {
     // FIXME Is this `contains` too lenient?
     if input.yahoo_use_api && domain.to_lowercase().contains("yahoo") {
+        let _session = metrics::SmtpSession::start(Provider::Yahoo, host);
         return yahoo::check_yahoo(to_email, input)
             .await
             .map_err(|err| err.into());
     }
 
-    let fut = create_smtp_future(to_email, host, port, domain, input);
-    let (is_catch_all, deliverability) = if let Some(smtp_timeout) = input.smtp_timeout {
-        future::timeout(smtp_timeout, fut).await??
+    let provider = if google::is_google_mx(host) {
+        google::provider(domain)
     } else {
-        fut.await?
+        Provider::Generic
+    };
+    let _session = metrics::SmtpSession::start(provider, host);
+
+    let (is_catch_all, deliverability) = match provider {
+        Provider::GoogleConsumer | Provider::GoogleWorkspace => {
+            google::create_google_future(to_email, host, port, domain, provider, input).await?
+        }
+        _ => create_smtp_future(to_email, host, port, domain, input).await?,
     };
 
     Ok(SmtpDetails {
@@ -440,5 +654,6 @@ pub async fn check_smtp(
         is_deliverable: deliverability.is_deliverable,
         is_disabled: deliverability.is_disabled,
         is_banned: deliverability.is_banned,
+        provider,
     })
 }
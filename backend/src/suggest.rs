@@ -0,0 +1,301 @@
+// Typo detection for the domain part of an address ("did you mean
+// gmail.com?").
+//
+// The domain is compared against a bundled list of popular mailbox domains
+// using a Damerau-Levenshtein distance where hitting a neighbouring key on a
+// QWERTY keyboard costs less than an arbitrary substitution. If no popular
+// domain is close, the TLD alone is compared against common TLDs, which
+// catches `example.cmo` style typos on any domain. A popular name under
+// another known TLD, like `yahoo.de`, is a real domain and left alone.
+//
+// Real domains can still be a typo away from a popular one, like `life.com`
+// from `live.com`, so `check_single_email` drops the suggestion when the
+// domain has MX records.
+
+const POPULAR_DOMAINS: [&str; 45] = [
+    "gmail.com",
+    "googlemail.com",
+    "yahoo.com",
+    "yahoo.co.uk",
+    "yahoo.fr",
+    "ymail.com",
+    "hotmail.com",
+    "hotmail.co.uk",
+    "hotmail.fr",
+    "hotmail.no",
+    "outlook.com",
+    "live.com",
+    "live.no",
+    "msn.com",
+    "aol.com",
+    "icloud.com",
+    "me.com",
+    "mac.com",
+    "protonmail.com",
+    "proton.me",
+    "gmx.com",
+    "gmx.de",
+    "gmx.net",
+    "web.de",
+    "mail.com",
+    "mail.ru",
+    "yandex.ru",
+    "yandex.com",
+    "zoho.com",
+    "comcast.net",
+    "verizon.net",
+    "att.net",
+    "sbcglobal.net",
+    "orange.fr",
+    "free.fr",
+    "laposte.net",
+    "t-online.de",
+    "libero.it",
+    "qq.com",
+    "163.com",
+    "naver.com",
+    "online.no",
+    "hotmail.se",
+    "telia.com",
+    "fastmail.com",
+];
+
+const POPULAR_TLDS: [&str; 30] = [
+    "com", "net", "org", "edu", "gov", "io", "co", "info", "biz", "me", "us", "uk", "co.uk", "ca",
+    "au", "com.au", "de", "fr", "it", "es", "nl", "no", "se", "dk", "fi", "ru", "ch", "at", "be",
+    "pl",
+];
+
+/// Country TLDs that are real but too rare to suggest, only used to tell a
+/// popular name under another TLD from a typo.
+const OTHER_KNOWN_TLDS: [&str; 32] = [
+    "ie", "pt", "gr", "cz", "sk", "hu", "ro", "bg", "hr", "si", "lt", "lv", "ee", "is", "lu", "tr",
+    "ua", "il", "in", "jp", "cn", "kr", "tw", "hk", "sg", "nz", "za", "br", "mx", "ar", "cl",
+    "co.jp",
+];
+
+const KEYBOARD_ROWS: [&str; 4] = ["1234567890-", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+/// Cost of substituting a key with one of its neighbours.
+const ADJACENT_KEY_COST: f32 = 0.5;
+
+fn key_position(c: char) -> Option<(i32, i32)> {
+    KEYBOARD_ROWS.iter().enumerate().find_map(|(row, keys)| {
+        keys.chars()
+            .position(|key| key == c)
+            .map(|col| (row as i32, col as i32))
+    })
+}
+
+fn substitution_cost(a: char, b: char) -> f32 {
+    if a == b {
+        return 0.0;
+    }
+
+    match (key_position(a), key_position(b)) {
+        (Some((row_a, col_a)), Some((row_b, col_b)))
+            if (row_a - row_b).abs() <= 1 && (col_a - col_b).abs() <= 1 =>
+        {
+            ADJACENT_KEY_COST
+        }
+        _ => 1.0,
+    }
+}
+
+/// Keyboard-aware Damerau-Levenshtein (optimal string alignment) distance.
+fn distance(a: &str, b: &str) -> f32 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0.0_f32; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i as f32;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j as f32;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let mut cost = (d[i - 1][j] + 1.0)
+                .min(d[i][j - 1] + 1.0)
+                .min(d[i - 1][j - 1] + substitution_cost(a[i - 1], b[j - 1]));
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                cost = cost.min(d[i - 2][j - 2] + 1.0);
+            }
+
+            d[i][j] = cost;
+        }
+    }
+
+    d[a.len()][b.len()]
+}
+
+/// Longest distance from `value` we still consider a typo of `candidate`.
+/// Short candidates get a tighter bound, and only for a mistyped or swapped
+/// character, otherwise every three-letter domain looks like a typo of
+/// another.
+fn max_distance(value: &str, candidate: &str) -> f32 {
+    if candidate.len() >= 8 {
+        2.0
+    } else if value.chars().count() == candidate.chars().count() {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+fn closest<'a>(value: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    if candidates.contains(&value) {
+        return None;
+    }
+
+    candidates
+        .iter()
+        .map(|candidate| (*candidate, distance(value, candidate)))
+        .filter(|(candidate, distance)| *distance <= max_distance(value, candidate))
+        .min_by(|(_, a), (_, b)| a.partial_cmp(b).expect("Distances are never NaN. qed."))
+        .map(|(candidate, _)| candidate)
+}
+
+fn is_known_tld(tld: &str) -> bool {
+    POPULAR_TLDS.contains(&tld) || OTHER_KNOWN_TLDS.contains(&tld)
+}
+
+/// Is `domain` the name of a popular domain under another known TLD, e.g.
+/// `hotmail.it` for `hotmail.no`?
+fn is_popular_name_elsewhere(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+
+    (1..=2).any(|tld_labels| {
+        if labels.len() <= tld_labels {
+            return false;
+        }
+
+        let (name, tld) = labels.split_at(labels.len() - tld_labels);
+        let name = name.join(".");
+        is_known_tld(&tld.join("."))
+            && POPULAR_DOMAINS.iter().any(|popular| {
+                match popular
+                    .strip_prefix(name.as_str())
+                    .and_then(|rest| rest.strip_prefix('.'))
+                {
+                    Some(tld) => is_known_tld(tld),
+                    None => false,
+                }
+            })
+    })
+}
+
+/// Suggest a corrected domain for `domain`, or `None` if it doesn't look like
+/// a typo.
+pub fn suggest_domain(domain: &str) -> Option<String> {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+
+    if is_popular_name_elsewhere(&domain) {
+        return None;
+    }
+
+    if let Some(suggestion) = closest(&domain, &POPULAR_DOMAINS) {
+        return Some(suggestion.to_string());
+    }
+
+    // Only the TLD looks wrong, e.g. "example.cmo". Try the two-label TLDs
+    // ("co.uk") before the single-label ones.
+    let labels: Vec<&str> = domain.split('.').collect();
+    for tld_labels in (1..=2).rev() {
+        if labels.len() <= tld_labels {
+            continue;
+        }
+
+        let (name, tld) = labels.split_at(labels.len() - tld_labels);
+        let tld = tld.join(".");
+        if POPULAR_TLDS.contains(&tld.as_ref()) {
+            return None;
+        }
+        if let Some(suggestion) = closest(&tld, &POPULAR_TLDS) {
+            return Some(format!("{}.{}", name.join("."), suggestion));
+        }
+    }
+
+    None
+}
+
+/// Suggest a corrected address for `email`, keeping the local part as is.
+pub fn suggest_email(email: &str) -> Option<String> {
+    let at = email.rfind('@')?;
+    let (local, domain) = (&email[..at], &email[at + 1..]);
+
+    suggest_domain(domain).map(|domain| format!("{}@{}", local, domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_popular_domains() {
+        assert_eq!(suggest_domain("gmial.com").as_deref(), Some("gmail.com"));
+        assert_eq!(
+            suggest_domain("hotmial.com").as_deref(),
+            Some("hotmail.com")
+        );
+        assert_eq!(suggest_domain("yahoo.cmo").as_deref(), Some("yahoo.com"));
+        assert_eq!(suggest_domain("gmail.cm").as_deref(), Some("gmail.com"));
+    }
+
+    #[test]
+    fn suggests_tlds_on_any_domain() {
+        assert_eq!(
+            suggest_domain("example.cmo").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            suggest_domain("example.co.ku").as_deref(),
+            Some("example.co.uk")
+        );
+    }
+
+    #[test]
+    fn leaves_popular_domains_alone() {
+        assert_eq!(suggest_domain("gmail.com"), None);
+        assert_eq!(suggest_domain("Hotmail.co.uk."), None);
+        assert_eq!(suggest_domain("example.com"), None);
+    }
+
+    #[test]
+    fn leaves_popular_names_under_other_tlds_alone() {
+        for domain in &[
+            "yahoo.de",
+            "hotmail.de",
+            "hotmail.es",
+            "yahoo.es",
+            "hotmail.it",
+            "hotmail.dk",
+            "yahoo.gr",
+            "live.se",
+            "gmx.at",
+        ] {
+            assert_eq!(suggest_domain(domain), None, "{}", domain);
+        }
+    }
+
+    #[test]
+    fn short_candidates_need_the_same_length() {
+        assert_eq!(suggest_domain("amsn.com"), None);
+        assert_eq!(suggest_domain("ao.com"), None);
+        assert_eq!(suggest_domain("aol.cmo").as_deref(), Some("aol.com"));
+        assert_eq!(suggest_domain("aok.com").as_deref(), Some("aol.com"));
+    }
+
+    #[test]
+    fn suggests_addresses() {
+        assert_eq!(
+            suggest_email("someone@gmial.com").as_deref(),
+            Some("someone@gmail.com")
+        );
+        assert_eq!(suggest_email("someone@yahoo.de"), None);
+        assert_eq!(suggest_email("no-at-sign"), None);
+    }
+}
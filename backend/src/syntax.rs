@@ -0,0 +1,426 @@
+// Detailed syntax checking.
+//
+// `check_syntax` from check-if-email-exists only answers yes or no. The parser
+// here splits the address into its local part and domain and names the first
+// problem it finds, under one of two strictness modes: what RFC 5321/5322
+// (and RFC 6531 for non-ASCII) allow, or what real mailbox providers accept.
+//
+// Domain literals like `user@[192.0.2.1]` are valid RFC 5321 but rejected in
+// both modes: they have no MX records to look up, and `check_syntax` refuses
+// them anyway.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::normalize::normalize_domain;
+
+const MAX_ADDRESS_LENGTH: usize = 254;
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Special characters RFC 5322 allows in an unquoted local part.
+const RFC_ATEXT: &str = "!#$%&'*+-/=?^_`{|}~";
+/// Special characters mailbox providers let their users pick.
+const PROVIDER_ATEXT: &str = "+-_";
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Strictness {
+    /// Anything RFC 5321/5322 allows, short of domain literals: quoted local
+    /// parts, the full set of special characters and dotless domains.
+    #[default]
+    Rfc,
+    /// Only what real mailbox providers hand out: letters, digits, `.`, `+`,
+    /// `-` and `_` in the local part, and a domain under an alphabetic TLD.
+    Provider,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SyntaxProblem {
+    MissingAt,
+    EmptyLocalPart,
+    EmptyDomain,
+    AddressTooLong,
+    LocalPartTooLong,
+    ConsecutiveDots,
+    LeadingOrTrailingDot,
+    QuotedLocalPart,
+    InvalidCharacter,
+    InvalidDomain,
+    /// An IP address in brackets instead of a domain name.
+    DomainLiteral,
+    DomainLabelTooLong,
+    InvalidTld,
+    /// Passed the parser here, but not `check_syntax`.
+    Rejected,
+}
+
+impl fmt::Display for SyntaxProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyntaxProblem::MissingAt => write!(f, "missing @"),
+            SyntaxProblem::EmptyLocalPart => write!(f, "empty local part"),
+            SyntaxProblem::EmptyDomain => write!(f, "empty domain"),
+            SyntaxProblem::AddressTooLong => {
+                write!(f, "address longer than {} bytes", MAX_ADDRESS_LENGTH)
+            }
+            SyntaxProblem::LocalPartTooLong => {
+                write!(f, "local part longer than {} bytes", MAX_LOCAL_PART_LENGTH)
+            }
+            SyntaxProblem::ConsecutiveDots => write!(f, "consecutive dots"),
+            SyntaxProblem::LeadingOrTrailingDot => {
+                write!(f, "local part starts or ends with a dot")
+            }
+            SyntaxProblem::QuotedLocalPart => write!(f, "quoted local part"),
+            SyntaxProblem::InvalidCharacter => write!(f, "invalid character in local part"),
+            SyntaxProblem::InvalidDomain => write!(f, "invalid domain"),
+            SyntaxProblem::DomainLiteral => write!(f, "IP address instead of a domain"),
+            SyntaxProblem::DomainLabelTooLong => {
+                write!(f, "domain label longer than {} bytes", MAX_LABEL_LENGTH)
+            }
+            SyntaxProblem::InvalidTld => write!(f, "invalid top-level domain"),
+            SyntaxProblem::Rejected => write!(f, "rejected by the syntax check"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct SyntaxBreakdown {
+    /// Everything before the last `@`.
+    pub local_part: Option<String>,
+    /// Everything after the last `@`.
+    pub domain: Option<String>,
+    /// The first problem found, if any.
+    pub problem: Option<SyntaxProblem>,
+}
+
+/// Parse `email` and report its parts and the first syntax problem.
+pub fn parse(email: &str, strictness: Strictness) -> SyntaxBreakdown {
+    let at = match email.rfind('@') {
+        Some(at) => at,
+        None => {
+            return SyntaxBreakdown {
+                problem: Some(SyntaxProblem::MissingAt),
+                ..Default::default()
+            }
+        }
+    };
+
+    let local = &email[..at];
+    let domain = &email[at + 1..];
+
+    SyntaxBreakdown {
+        local_part: Some(local.to_string()),
+        domain: Some(domain.to_string()),
+        problem: check_address(local, domain, strictness).err(),
+    }
+}
+
+fn check_address(local: &str, domain: &str, strictness: Strictness) -> Result<(), SyntaxProblem> {
+    if local.is_empty() {
+        return Err(SyntaxProblem::EmptyLocalPart);
+    }
+    if domain.is_empty() {
+        return Err(SyntaxProblem::EmptyDomain);
+    }
+
+    let ascii_domain = normalize_domain(domain);
+    if local.len() + 1 + ascii_domain.len() > MAX_ADDRESS_LENGTH {
+        return Err(SyntaxProblem::AddressTooLong);
+    }
+    if local.len() > MAX_LOCAL_PART_LENGTH {
+        return Err(SyntaxProblem::LocalPartTooLong);
+    }
+
+    check_local_part(local, strictness)?;
+    check_domain(&ascii_domain, strictness)
+}
+
+fn check_local_part(local: &str, strictness: Strictness) -> Result<(), SyntaxProblem> {
+    if local.starts_with('"') {
+        return match strictness {
+            Strictness::Rfc => check_quoted_string(local),
+            Strictness::Provider => Err(SyntaxProblem::QuotedLocalPart),
+        };
+    }
+
+    let specials = match strictness {
+        Strictness::Rfc => RFC_ATEXT,
+        Strictness::Provider => PROVIDER_ATEXT,
+    };
+    let is_allowed = |c: char| {
+        c.is_ascii_alphanumeric()
+            || specials.contains(c)
+            // RFC 6531 allows any non-ASCII character; providers only letters
+            // and digits.
+            || (!c.is_ascii() && (strictness == Strictness::Rfc || c.is_alphanumeric()))
+    };
+
+    if local.starts_with('.') || local.ends_with('.') {
+        return Err(SyntaxProblem::LeadingOrTrailingDot);
+    }
+    if local.contains("..") {
+        return Err(SyntaxProblem::ConsecutiveDots);
+    }
+    if !local.chars().all(|c| c == '.' || is_allowed(c)) {
+        return Err(SyntaxProblem::InvalidCharacter);
+    }
+
+    Ok(())
+}
+
+/// Check an RFC 5322 quoted string: printable characters between double
+/// quotes, with `"` and `\` escaped by a backslash.
+fn check_quoted_string(local: &str) -> Result<(), SyntaxProblem> {
+    let quoted = match local
+        .strip_prefix('"')
+        .and_then(|local| local.strip_suffix('"'))
+    {
+        Some(quoted) => quoted,
+        None => return Err(SyntaxProblem::InvalidCharacter),
+    };
+
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        let valid = match c {
+            '\\' => matches!(chars.next(), Some(c) if c == ' ' || c.is_ascii_graphic()),
+            '"' => false,
+            c => c == ' ' || c.is_ascii_graphic() || !c.is_ascii(),
+        };
+        if !valid {
+            return Err(SyntaxProblem::InvalidCharacter);
+        }
+    }
+
+    Ok(())
+}
+
+fn check_domain(domain: &str, strictness: Strictness) -> Result<(), SyntaxProblem> {
+    if domain.starts_with('[') && domain.ends_with(']') {
+        return Err(SyntaxProblem::DomainLiteral);
+    }
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    for label in &labels {
+        if label.len() > MAX_LABEL_LENGTH {
+            return Err(SyntaxProblem::DomainLabelTooLong);
+        }
+        if label.is_empty()
+            || label.starts_with('-')
+            || label.ends_with('-')
+            || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(SyntaxProblem::InvalidDomain);
+        }
+    }
+
+    let tld = labels[labels.len() - 1];
+    let valid_tld = match strictness {
+        // RFC 3696: a TLD is never all-numeric.
+        Strictness::Rfc => !tld.chars().all(|c| c.is_ascii_digit()),
+        Strictness::Provider => {
+            labels.len() > 1
+                && tld.len() >= 2
+                && (tld.starts_with("xn--") || tld.chars().all(|c| c.is_ascii_alphabetic()))
+        }
+    };
+
+    if valid_tld {
+        Ok(())
+    } else {
+        Err(SyntaxProblem::InvalidTld)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problem(email: &str, strictness: Strictness) -> Option<SyntaxProblem> {
+        parse(email, strictness).problem
+    }
+
+    /// The problem found in both modes.
+    fn problem_in_both(email: &str) -> Option<SyntaxProblem> {
+        let rfc = problem(email, Strictness::Rfc);
+        assert_eq!(rfc, problem(email, Strictness::Provider), "{}", email);
+
+        rfc
+    }
+
+    #[test]
+    fn splits_at_the_last_at() {
+        let breakdown = parse(r#""a@b"@example.com"#, Strictness::Rfc);
+        assert_eq!(breakdown.local_part.as_deref(), Some(r#""a@b""#));
+        assert_eq!(breakdown.domain.as_deref(), Some("example.com"));
+        assert_eq!(breakdown.problem, None);
+    }
+
+    #[test]
+    fn plain_addresses_pass_in_both_modes() {
+        for email in &[
+            "alice@example.com",
+            "alice.smith+news@mail.example.co.uk",
+            "a_b-c@xn--bcher-kva.example",
+            "jürgen@bücher.de",
+        ] {
+            assert_eq!(problem_in_both(email), None, "{}", email);
+        }
+    }
+
+    #[test]
+    fn missing_or_empty_parts() {
+        assert_eq!(
+            problem_in_both("alice.example.com"),
+            Some(SyntaxProblem::MissingAt)
+        );
+        assert_eq!(
+            problem_in_both("@example.com"),
+            Some(SyntaxProblem::EmptyLocalPart)
+        );
+        assert_eq!(problem_in_both("alice@"), Some(SyntaxProblem::EmptyDomain));
+    }
+
+    #[test]
+    fn lengths() {
+        let local = "a".repeat(MAX_LOCAL_PART_LENGTH);
+        assert_eq!(problem_in_both(&format!("{}@example.com", local)), None);
+        assert_eq!(
+            problem_in_both(&format!("{}a@example.com", local)),
+            Some(SyntaxProblem::LocalPartTooLong)
+        );
+
+        let label = "b".repeat(MAX_LABEL_LENGTH);
+        assert_eq!(problem_in_both(&format!("a@{}.com", label)), None);
+        assert_eq!(
+            problem_in_both(&format!("a@{}b.com", label)),
+            Some(SyntaxProblem::DomainLabelTooLong)
+        );
+
+        let domain = format!("{}.{}.{}.com", label, label, label);
+        assert_eq!(
+            problem_in_both(&format!("{}@{}", local, domain)),
+            Some(SyntaxProblem::AddressTooLong)
+        );
+    }
+
+    #[test]
+    fn dots_in_the_local_part() {
+        assert_eq!(
+            problem_in_both("alice..smith@example.com"),
+            Some(SyntaxProblem::ConsecutiveDots)
+        );
+        assert_eq!(
+            problem_in_both(".alice@example.com"),
+            Some(SyntaxProblem::LeadingOrTrailingDot)
+        );
+        assert_eq!(
+            problem_in_both("alice.@example.com"),
+            Some(SyntaxProblem::LeadingOrTrailingDot)
+        );
+    }
+
+    #[test]
+    fn quoted_local_parts_only_in_rfc_mode() {
+        assert_eq!(
+            problem(r#""alice smith"@example.com"#, Strictness::Rfc),
+            None
+        );
+        assert_eq!(problem(r#""al\"ice"@example.com"#, Strictness::Rfc), None);
+        assert_eq!(
+            problem(r#""alice smith"@example.com"#, Strictness::Provider),
+            Some(SyntaxProblem::QuotedLocalPart)
+        );
+        assert_eq!(
+            problem(r#""al"ice"@example.com"#, Strictness::Rfc),
+            Some(SyntaxProblem::InvalidCharacter)
+        );
+        assert_eq!(
+            problem(r#""alice@example.com"#, Strictness::Rfc),
+            Some(SyntaxProblem::InvalidCharacter)
+        );
+    }
+
+    #[test]
+    fn special_characters_depend_on_the_mode() {
+        assert_eq!(
+            problem("alice!#$%&'*/=?^`{|}~@example.com", Strictness::Rfc),
+            None
+        );
+        assert_eq!(
+            problem("alice!@example.com", Strictness::Provider),
+            Some(SyntaxProblem::InvalidCharacter)
+        );
+        assert_eq!(
+            problem_in_both("alice smith@example.com"),
+            Some(SyntaxProblem::InvalidCharacter)
+        );
+        assert_eq!(problem("al☃ce@example.com", Strictness::Rfc), None);
+        assert_eq!(
+            problem("al☃ce@example.com", Strictness::Provider),
+            Some(SyntaxProblem::InvalidCharacter)
+        );
+    }
+
+    #[test]
+    fn invalid_domains() {
+        for email in &[
+            "alice@example..com",
+            "alice@-example.com",
+            "alice@example-.com",
+            "alice@exa_mple.com",
+            "alice@.example.com",
+        ] {
+            assert_eq!(
+                problem_in_both(email),
+                Some(SyntaxProblem::InvalidDomain),
+                "{}",
+                email
+            );
+        }
+    }
+
+    #[test]
+    fn domain_literals_are_rejected_in_both_modes() {
+        for email in &[
+            "alice@[192.0.2.1]",
+            "alice@[ipv6:2001:db8::1]",
+            "alice@[nope]",
+        ] {
+            assert_eq!(
+                problem_in_both(email),
+                Some(SyntaxProblem::DomainLiteral),
+                "{}",
+                email
+            );
+        }
+    }
+
+    #[test]
+    fn tlds_depend_on_the_mode() {
+        assert_eq!(problem("alice@localhost", Strictness::Rfc), None);
+        assert_eq!(
+            problem("alice@localhost", Strictness::Provider),
+            Some(SyntaxProblem::InvalidTld)
+        );
+        assert_eq!(problem("alice@example.c0m", Strictness::Rfc), None);
+        assert_eq!(
+            problem("alice@example.c0m", Strictness::Provider),
+            Some(SyntaxProblem::InvalidTld)
+        );
+        assert_eq!(
+            problem("alice@example.c", Strictness::Provider),
+            Some(SyntaxProblem::InvalidTld)
+        );
+        assert_eq!(
+            problem_in_both("alice@192.0.2.1"),
+            Some(SyntaxProblem::InvalidTld)
+        );
+    }
+
+    #[test]
+    fn rfc_is_the_default() {
+        assert_eq!(Strictness::default(), Strictness::Rfc);
+    }
+}
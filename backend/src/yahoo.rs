@@ -16,15 +16,17 @@
 
 // https://github.com/reacherhq/check-if-email-exists/blob/master/core/src/smtp/yahoo.rs
 
-use async_smtp::EmailAddress;
 use check_if_email_exists::CheckEmailInput;
+use futures::future::{BoxFuture, FutureExt};
+use once_cell::sync::Lazy;
 use regex::Regex;
 use reqwest::Error as ReqwestError;
 use serde::{Deserialize, Serialize};
 use serde_json::error::Error as SerdeError;
 use std::fmt;
+use std::sync::{Arc, RwLock};
 
-use crate::smtp::SmtpDetails;
+use crate::smtp::{Provider, SmtpDetails};
 use crate::util::ser_with_display;
 
 const SIGNUP_PAGE: &str = "https://login.yahoo.com/account/create?specId=yidReg&lang=en-US&src=&done=https%3A%2F%2Fwww.yahoo.com&display=login";
@@ -116,79 +118,144 @@ fn create_client(input: &CheckEmailInput) -> Result<reqwest::Client, ReqwestErro
             proxy.port
         );
 
-        let proxy = reqwest::Proxy::all(&format!("socks5://{}:{}", proxy.host, proxy.port))?;
+        let proxy = reqwest::Proxy::all(format!("socks5://{}:{}", proxy.host, proxy.port))?;
         reqwest::Client::builder().proxy(proxy).build()
     } else {
         Ok(reqwest::Client::new())
     }
 }
 
+/// The HTTP side of a Yahoo check. Replaced in tests to record and replay
+/// real exchanges.
+pub trait YahooApi: Send + Sync {
+    /// The `Set-Cookie` header of the signup page, if there is one.
+    fn signup_cookies<'a>(
+        &'a self,
+        input: &'a CheckEmailInput,
+    ) -> BoxFuture<'a, Result<Option<String>, YahooError>>;
+
+    /// The raw body of the response validating `username` on the signup form.
+    fn validate_username<'a>(
+        &'a self,
+        input: &'a CheckEmailInput,
+        cookies: &'a str,
+        acrumb: &'a str,
+        username: &'a str,
+    ) -> BoxFuture<'a, Result<String, YahooError>>;
+}
+
+static API: Lazy<RwLock<Arc<dyn YahooApi>>> = Lazy::new(|| RwLock::new(Arc::new(HttpYahooApi)));
+
+/// The process-wide Yahoo API used by `check_yahoo`.
+pub fn api() -> Arc<dyn YahooApi> {
+    API.read()
+        .expect("Yahoo API lock is never poisoned. qed.")
+        .clone()
+}
+
+/// Replace the process-wide Yahoo API.
+pub fn set_api(api: Arc<dyn YahooApi>) {
+    *API.write().expect("Yahoo API lock is never poisoned. qed.") = api;
+}
+
+/// Talks to login.yahoo.com, through the input's proxy if it has one.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HttpYahooApi;
+
+impl YahooApi for HttpYahooApi {
+    fn signup_cookies<'a>(
+        &'a self,
+        input: &'a CheckEmailInput,
+    ) -> BoxFuture<'a, Result<Option<String>, YahooError>> {
+        async move {
+            let response = create_client(input)?
+                .get(SIGNUP_PAGE)
+                .header("User-Agent", USER_AGENT)
+                .send()
+                .await?;
+            log::debug!("Yahoo 1st response: {:?}", response);
+
+            match response.headers().get("Set-Cookie") {
+                Some(cookies) => match cookies.to_str() {
+                    Ok(cookies) => Ok(Some(cookies.to_string())),
+                    _ => Err(YahooError::NoAcrumb),
+                },
+                None => Ok(None),
+            }
+        }
+        .boxed()
+    }
+
+    fn validate_username<'a>(
+        &'a self,
+        input: &'a CheckEmailInput,
+        cookies: &'a str,
+        acrumb: &'a str,
+        username: &'a str,
+    ) -> BoxFuture<'a, Result<String, YahooError>> {
+        async move {
+            // Mimic a real HTTP request.
+            let body = create_client(input)?
+                .post(SIGNUP_API)
+                .header("Origin", "https://login.yahoo.com")
+                .header("X-Requested-With", "XMLHttpRequest")
+                .header("User-Agent", USER_AGENT)
+                .header(
+                    "Content-type",
+                    "application/x-www-form-urlencoded; charset=UTF-8",
+                )
+                .header("Accept", "*/*")
+                .header("Referer", SIGNUP_PAGE)
+                .header("Accept-Encoding", "gzip, deflate, br")
+                .header("Accept-Language", "en-US,en;q=0.8,ar;q=0.6")
+                .header("Cookie", cookies)
+                .json(&FormRequest::new(acrumb.into(), username.into()))
+                .send()
+                .await?
+                .text()
+                .await?;
+
+            Ok(body)
+        }
+        .boxed()
+    }
+}
+
 /// Use well-crafted HTTP requests to verify if a Yahoo email address exists.
 /// Inspired by https://github.com/hbattat/verifyEmail.
 pub async fn check_yahoo(
-    to_email: &EmailAddress,
+    to_email: &str,
     input: &CheckEmailInput,
 ) -> Result<SmtpDetails, YahooError> {
-    let response = create_client(input)?
-        .get(SIGNUP_PAGE)
-        .header("User-Agent", USER_AGENT)
-        .send()
-        .await?;
+    let api = api();
 
     // Get the cookies from the response.
-    let cookies = match response.headers().get("Set-Cookie") {
+    let cookies = match api.signup_cookies(input).await? {
         Some(x) => x,
         _ => {
             return Err(YahooError::NoCookie);
         }
     };
-    log::debug!("Yahoo 1st response: {:?}", response);
     log::debug!("Yahoo cookies: {:?}", cookies);
 
-    let to_email = to_email.to_string();
     let username = to_email
         .split('@')
         .next()
         .expect("The email is well-formed. qed.");
 
     // From the cookies, fetch the "acrumb" field.
-    let acrumb = match cookies.to_str() {
-        Ok(x) => x,
-        _ => {
-            return Err(YahooError::NoAcrumb);
-        }
-    };
     let re = Regex::new(r"s=(?P<acrumb>[^;]*)").expect("Correct regex. qed.");
-    let acrumb = match re.captures(acrumb) {
+    let acrumb = match re.captures(&cookies) {
         Some(x) => x,
         _ => {
             return Err(YahooError::NoAcrumb);
         }
     };
 
-    // Mimic a real HTTP request.
-    let response = create_client(input)?
-        .post(SIGNUP_API)
-        .header("Origin", "https://login.yahoo.com")
-        .header("X-Requested-With", "XMLHttpRequest")
-        .header("User-Agent", USER_AGENT)
-        .header(
-            "Content-type",
-            "application/x-www-form-urlencoded; charset=UTF-8",
-        )
-        .header("Accept", "*/*")
-        .header("Referer", SIGNUP_PAGE)
-        .header("Accept-Encoding", "gzip, deflate, br")
-        .header("Accept-Language", "en-US,en;q=0.8,ar;q=0.6")
-        .header("Cookie", cookies)
-        .json(&FormRequest::new(
-            acrumb["acrumb"].to_string(),
-            username.into(),
-        ))
-        .send()
-        .await?
-        .json::<FormResponse>()
+    let body = api
+        .validate_username(input, &cookies, &acrumb["acrumb"], username)
         .await?;
+    let response: FormResponse = serde_json::from_str(&body)?;
 
     log::debug!("Yahoo 2nd response: {:?}", response);
 
@@ -200,6 +267,7 @@ pub async fn check_yahoo(
     Ok(SmtpDetails {
         can_connect_smtp: true,
         is_deliverable: username_exists,
+        provider: Provider::Yahoo,
         ..Default::default()
     })
 }
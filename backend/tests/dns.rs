@@ -0,0 +1,169 @@
+// The DNS stage of `check_single_email`, run against a `FakeResolver`.
+
+mod support;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use extant::dns::{self, FakeResolver, MxResolver};
+use extant::mail::{check_single_email, EmailCheckInput, EmailCheckResponse, MyReachable};
+use extant::syntax::SyntaxProblem;
+use trust_dns_proto::op::ResponseCode;
+use trust_dns_resolver::error::ResolveErrorKind;
+
+use support::smtp_server::{Action, SmtpScript};
+
+/// Every test installs the same table, so they can share the process-wide
+/// resolver while running in parallel.
+fn install_fake_resolver() {
+    let resolver = FakeResolver::new()
+        .mx("with-mx.test", &["127.0.0.1", "192.0.2.1"])
+        .mx("skip-smtp.test", &["192.0.2.1"])
+        .mx("life.com", &["192.0.2.1"])
+        .mx("email.com", &["192.0.2.1"])
+        .mx("empty-mx.test", &[])
+        .fail("broken-dns.test", "request timed out");
+
+    dns::set_resolver(Arc::new(resolver));
+}
+
+async fn check(
+    email: &str,
+    input: &mut EmailCheckInput,
+) -> Result<EmailCheckResponse, EmailCheckResponse> {
+    install_fake_resolver();
+    input.to_emails = vec![email.into()];
+    input.set_smtp_timeout(Duration::from_secs(5));
+
+    check_single_email(input.clone()).await
+}
+
+#[tokio::test]
+async fn fake_resolver_answers() {
+    let resolver = FakeResolver::new()
+        .mx("example.com", &["mx1.example.com", "mx2.example.com"])
+        .fail("broken.example", "SERVFAIL");
+
+    let hosts = resolver.mx_hosts("Example.COM.").await.unwrap();
+    let hosts: Vec<String> = hosts.iter().map(|host| host.to_utf8()).collect();
+    assert_eq!(hosts, vec!["mx1.example.com", "mx2.example.com"]);
+
+    let unknown = resolver.mx_hosts("unknown.example").await.unwrap_err();
+    assert!(matches!(
+        unknown.kind(),
+        ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::NXDomain,
+            ..
+        }
+    ));
+    let broken = resolver.mx_hosts("broken.example").await.unwrap_err();
+    assert!(matches!(broken.kind(), ResolveErrorKind::Msg(_)));
+}
+
+#[tokio::test]
+async fn mx_found_checks_most_preferred_host() {
+    let server = SmtpScript::new()
+        .recipient("alice@with-mx.test", vec![Action::reply(250, "2.1.5 OK")])
+        .start();
+
+    let mut input = EmailCheckInput::default();
+    input.set_smtp_port(server.port());
+    let result = check("alice@with-mx.test", &mut input).await.unwrap();
+
+    assert_eq!(result.is_reachable, MyReachable::Safe);
+    assert_eq!(result.is_deliverable, Some(true));
+    assert_eq!(server.connections(), 1);
+}
+
+#[tokio::test]
+async fn mx_found_and_smtp_skipped() {
+    let mut input = EmailCheckInput::default();
+    input.set_skip_smtp(true);
+    let result = check("alice@skip-smtp.test", &mut input).await.unwrap();
+
+    assert_eq!(result.is_reachable, MyReachable::Unknown);
+    assert!(result
+        .reasons
+        .iter()
+        .any(|reason| reason.ends_with("SMTP check skipped by request")));
+    assert_eq!(result.can_connect_smtp, None);
+}
+
+#[tokio::test]
+async fn no_mx_records() {
+    // Like the real resolver, the fake fails the lookup, which is retried
+    // rather than taken for a verdict.
+    let result = check("alice@no-records.test", &mut EmailCheckInput::default())
+        .await
+        .unwrap_err();
+
+    assert_eq!(result.is_reachable, MyReachable::Unknown);
+    assert!(result
+        .reasons
+        .iter()
+        .any(|reason| reason.ends_with("MX lookup failed")));
+}
+
+#[tokio::test]
+async fn empty_mx_answer() {
+    let resolver = FakeResolver::new().mx("empty-mx.test", &[]);
+    let err = resolver.mx_hosts("empty-mx.test").await.unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::NoError,
+            ..
+        }
+    ));
+
+    let result = check("alice@empty-mx.test", &mut EmailCheckInput::default())
+        .await
+        .unwrap_err();
+
+    assert_eq!(result.is_reachable, MyReachable::Unknown);
+}
+
+#[tokio::test]
+async fn lookup_failure() {
+    let result = check("alice@broken-dns.test", &mut EmailCheckInput::default())
+        .await
+        .unwrap_err();
+
+    assert_eq!(result.is_reachable, MyReachable::Unknown);
+    assert!(result
+        .reasons
+        .iter()
+        .any(|reason| reason.ends_with("MX lookup failed")));
+    assert_eq!(result.can_connect_smtp, None);
+}
+
+#[tokio::test]
+async fn no_suggestion_for_domains_with_mx() {
+    for email in &["alice@life.com", "alice@email.com"] {
+        let mut input = EmailCheckInput::default();
+        input.set_skip_smtp(true);
+        let result = check(email, &mut input).await.unwrap();
+
+        assert_eq!(result.suggestion, None, "{}", email);
+    }
+}
+
+#[tokio::test]
+async fn suggestion_for_domains_without_mx() {
+    let result = check("alice@gmial.com", &mut EmailCheckInput::default())
+        .await
+        .unwrap_err();
+
+    assert_eq!(result.suggestion.as_deref(), Some("alice@gmail.com"));
+}
+
+#[tokio::test]
+async fn dotless_domain_is_rejected_before_the_lookup() {
+    // The parser allows dotless domains in RFC mode, `check_syntax` doesn't.
+    let result = check("alice@intranet", &mut EmailCheckInput::default())
+        .await
+        .unwrap();
+
+    assert_eq!(result.syntax_problem, Some(SyntaxProblem::Rejected));
+    assert_eq!(result.is_reachable, MyReachable::Invalid);
+}
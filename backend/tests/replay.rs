@@ -0,0 +1,66 @@
+// Replays the fixtures in `tests/fixtures/replay`, offline. The fixtures
+// there now are synthetic, written by hand in the recorded format, see the
+// README next to them.
+//
+// To record new ones from live checks, with outbound port 25 open:
+//
+//     RECORD_EMAILS=someone@example.com,other@example.org \
+//         cargo test --test replay -- --ignored
+//
+// Recording uses the config from `CONFIG_FILE`, `extant.toml` or the env, like
+// the server does. Review the fixtures before committing them: they hold the
+// addresses and whatever the servers answered.
+
+mod support;
+
+use std::env;
+use std::fs;
+
+use support::fixtures::{fixtures_dir, Expected, Fixture};
+
+#[tokio::test]
+async fn replay_fixtures() {
+    let mut paths: Vec<_> = fs::read_dir(fixtures_dir())
+        .expect("fixtures directory")
+        .map(|entry| entry.expect("fixture entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no fixtures to replay");
+
+    // The resolver and the Yahoo API are process-wide, so one fixture at a
+    // time.
+    for path in paths {
+        let fixture = Fixture::load(&path).expect("valid fixture");
+        let (result, mismatches) = fixture.replay().await;
+
+        assert!(
+            mismatches.is_empty(),
+            "{}: {:#?}",
+            path.display(),
+            mismatches
+        );
+        assert_eq!(
+            Expected::from(&result),
+            fixture.expected,
+            "{}",
+            path.display()
+        );
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn record_fixtures() {
+    let emails = env::var("RECORD_EMAILS").expect("RECORD_EMAILS to record");
+
+    for email in emails
+        .split(',')
+        .map(str::trim)
+        .filter(|email| !email.is_empty())
+    {
+        let fixture = Fixture::record(email).await;
+        let path = fixture.save(&fixtures_dir()).expect("fixture written");
+        println!("{}: {:?}", path.display(), fixture.expected.is_reachable);
+    }
+}
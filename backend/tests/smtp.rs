@@ -0,0 +1,249 @@
+// `check_smtp` against the scripted server in `support::smtp_server`.
+
+mod support;
+
+use std::time::Duration;
+
+use check_if_email_exists::CheckEmailInput;
+use extant::smtp::{check_smtp, SmtpDetails, SmtpError};
+
+use support::smtp_server::{Action, MockSmtpServer, SmtpScript};
+
+const DOMAIN: &str = "example.com";
+const EMAIL: &str = "someone@example.com";
+
+async fn check(
+    server: &MockSmtpServer,
+    to_email: &str,
+    timeout: u64,
+) -> Result<SmtpDetails, SmtpError> {
+    let mut input = CheckEmailInput::new(vec![to_email.into()]);
+    input
+        .set_hello_name("localhost".into())
+        .set_smtp_timeout(Duration::from_secs(timeout));
+
+    check_smtp(to_email, &server.host(), server.port(), DOMAIN, &input).await
+}
+
+#[tokio::test]
+async fn deliverable_recipient() {
+    let server = SmtpScript::new()
+        .recipient(EMAIL, vec![Action::reply(250, "2.1.5 OK")])
+        .start();
+
+    let details = check(&server, EMAIL, 5).await.unwrap();
+
+    assert!(details.can_connect_smtp);
+    assert!(details.is_deliverable);
+    assert!(!details.is_catch_all);
+    // The catch-all probe, then the address itself.
+    assert_eq!(server.recipients().len(), 2);
+    assert_eq!(server.recipients()[1], EMAIL);
+}
+
+#[tokio::test]
+async fn unknown_recipient() {
+    let server = SmtpScript::new().start();
+
+    let details = check(&server, EMAIL, 5).await.unwrap();
+
+    assert!(!details.is_deliverable);
+    assert!(!details.has_full_inbox);
+    assert!(!details.is_disabled);
+    assert!(!details.is_banned);
+}
+
+#[tokio::test]
+async fn catch_all() {
+    let server = SmtpScript::new().catch_all().start();
+
+    let details = check(&server, EMAIL, 5).await.unwrap();
+
+    assert!(details.is_catch_all);
+    assert!(details.is_deliverable);
+    // The address itself is never asked for.
+    assert_eq!(server.recipients().len(), 1);
+    assert_ne!(server.recipients()[0], EMAIL);
+}
+
+#[tokio::test]
+async fn full_inbox() {
+    let server = SmtpScript::new()
+        .recipient(
+            EMAIL,
+            vec![Action::reply(
+                552,
+                "5.2.2 The email account that you tried to reach is over quota",
+            )],
+        )
+        .start();
+
+    let details = check(&server, EMAIL, 5).await.unwrap();
+
+    assert!(details.has_full_inbox);
+    assert!(!details.is_deliverable);
+}
+
+#[tokio::test]
+async fn disabled() {
+    let server = SmtpScript::new()
+        .recipient(
+            EMAIL,
+            vec![Action::reply(
+                550,
+                "5.2.1 The email account that you tried to reach is disabled",
+            )],
+        )
+        .start();
+
+    let details = check(&server, EMAIL, 5).await.unwrap();
+
+    assert!(details.is_disabled);
+    assert!(!details.is_deliverable);
+}
+
+#[tokio::test]
+async fn banned() {
+    let server = SmtpScript::new()
+        .recipient(
+            EMAIL,
+            vec![Action::reply(
+                550,
+                "5.7.1 Service unavailable; client host blocked using Trend Micro RBL+ ers-dul",
+            )],
+        )
+        .start();
+
+    let details = check(&server, EMAIL, 5).await.unwrap();
+
+    assert!(details.is_banned);
+    assert!(!details.is_deliverable);
+}
+
+#[tokio::test]
+async fn reconnects_when_server_hangs_up_after_error() {
+    let server = SmtpScript::new()
+        .other_recipients(vec![Action::reply_and_disconnect(
+            550,
+            "5.1.1 User unknown",
+        )])
+        .recipient(EMAIL, vec![Action::reply(250, "2.1.5 OK")])
+        .start();
+
+    let details = check(&server, EMAIL, 5).await.unwrap();
+
+    assert!(details.is_deliverable);
+    assert!(!details.is_catch_all);
+    assert_eq!(server.connections(), 2);
+}
+
+#[tokio::test]
+async fn reconnects_when_server_drops_without_reply() {
+    let server = SmtpScript::new()
+        .recipient(
+            EMAIL,
+            vec![Action::Disconnect, Action::reply(250, "2.1.5 OK")],
+        )
+        .start();
+
+    let details = check(&server, EMAIL, 5).await.unwrap();
+
+    assert!(details.is_deliverable);
+    assert_eq!(server.connections(), 2);
+}
+
+#[tokio::test]
+async fn slow_recipient_times_out() {
+    let server = SmtpScript::new()
+        .recipient(
+            EMAIL,
+            vec![Action::reply(250, "2.1.5 OK").after(Duration::from_secs(3))],
+        )
+        .start();
+
+    let result = check(&server, EMAIL, 1).await;
+
+    assert!(matches!(result, Err(SmtpError::TimeoutError(_))));
+}
+
+#[tokio::test]
+async fn timed_out_session_sends_quit() {
+    let server = SmtpScript::new()
+        .recipient(
+            EMAIL,
+            vec![Action::reply(250, "2.1.5 OK").after(Duration::from_secs(2))],
+        )
+        .start();
+
+    let result = check(&server, EMAIL, 1).await;
+    assert!(matches!(result, Err(SmtpError::TimeoutError(_))));
+
+    // The server reads QUIT once it's done sleeping on the RCPT TO.
+    for _ in 0..20 {
+        if server.commands().iter().any(|command| command == "QUIT") {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("No QUIT in {:?}", server.commands());
+}
+
+#[tokio::test]
+async fn slow_banner_times_out() {
+    let server = SmtpScript::new()
+        .banner_delay(Duration::from_secs(3))
+        .start();
+
+    let result = check(&server, EMAIL, 1).await;
+
+    assert!(matches!(result, Err(SmtpError::TimeoutError(_))));
+}
+
+#[tokio::test]
+async fn greylisted_recipient_errors_then_passes() {
+    let server = SmtpScript::new().greylist(EMAIL).start();
+
+    // A 451 isn't a verdict, so the check fails and gets retried.
+    let first = check(&server, EMAIL, 5).await;
+    assert!(matches!(first, Err(SmtpError::SmtpError(_))));
+
+    let second = check(&server, EMAIL, 5).await.unwrap();
+    assert!(second.is_deliverable);
+}
+
+#[tokio::test]
+async fn smtputf8_needs_capability() {
+    let email = "jürgen@example.com";
+
+    let server = SmtpScript::new()
+        .recipient(email, vec![Action::reply(250, "2.1.5 OK")])
+        .start();
+    let result = check(&server, email, 5).await;
+    assert!(matches!(result, Err(SmtpError::SmtpUtf8Unsupported)));
+    assert!(server.recipients().is_empty());
+
+    let server = SmtpScript::new()
+        .capabilities(&["PIPELINING", "SMTPUTF8"])
+        .recipient(email, vec![Action::reply(250, "2.1.5 OK")])
+        .start();
+    let details = check(&server, email, 5).await.unwrap();
+    assert!(details.is_deliverable);
+    assert!(server
+        .commands()
+        .iter()
+        .any(|command| command.starts_with("MAIL FROM") && command.ends_with("SMTPUTF8")));
+}
+
+#[tokio::test]
+async fn custom_banner_and_capabilities() {
+    let server = SmtpScript::new()
+        .banner("220-mx.example.com ESMTP\r\n220 No UCE")
+        .capabilities(&[])
+        .recipient(EMAIL, vec![Action::reply(250, "2.1.5 OK")])
+        .start();
+
+    let details = check(&server, EMAIL, 5).await.unwrap();
+
+    assert!(details.is_deliverable);
+    assert!(server.commands()[0].starts_with("EHLO localhost"));
+}
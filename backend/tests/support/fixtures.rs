@@ -0,0 +1,653 @@
+// Record-and-replay fixtures for end-to-end checks.
+//
+// A fixture holds everything `check_single_email` learned from the network
+// for one address: the DNS answers, the SMTP dialogues and the Yahoo HTTP
+// exchange, plus the verdict it reached. Recording runs a live check with a
+// `RecordingResolver`, a `RecordingYahooApi` and a SOCKS5 proxy that relays
+// and transcribes SMTP sessions. Replaying serves the same answers from
+// memory and a local proxy, so the check runs offline and must reach the
+// same verdict.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use async_std_resolver::ResolveError;
+use check_if_email_exists::CheckEmailInput;
+use extant::config::{self, Config};
+use extant::dns::{self, DnsResolver, FakeResolver, MxResolver};
+use extant::mail::{
+    check_single_email, EmailCheckInput, EmailCheckResponse, MyReachable, ProxyInput,
+};
+use extant::smtp::Provider;
+use extant::yahoo::{self, HttpYahooApi, YahooApi, YahooError};
+use futures::future::{self, BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::rr::Name;
+
+/// Ports whose traffic is transcribed as SMTP. Anything else, like the Yahoo
+/// API over HTTPS, is relayed untouched.
+const SMTP_PORTS: [u16; 3] = [25, 465, 587];
+
+/// Where `cargo test` finds the fixtures.
+pub fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay")
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Fixture {
+    pub email: String,
+    /// MX hosts per domain, or the lookup error message.
+    pub dns: BTreeMap<String, Result<Vec<String>, String>>,
+    /// SMTP sessions in the order they were opened.
+    pub smtp: Vec<SmtpSession>,
+    pub yahoo: Option<YahooExchange>,
+    pub expected: Expected,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SmtpSession {
+    pub host: String,
+    pub port: u16,
+    /// The connection to the server failed.
+    #[serde(default)]
+    pub refused: bool,
+    /// The greeting, `None` if the server hung up before sending one.
+    pub banner: Option<String>,
+    pub exchanges: Vec<SmtpExchange>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmtpExchange {
+    pub command: String,
+    /// The full reply with its line endings, `None` if the server hung up
+    /// instead of replying.
+    pub reply: Option<String>,
+}
+
+/// The Yahoo API exchange. A failed request is recorded as `None` and
+/// replayed as `YahooError::NoCookie`, which leads to the same verdict.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct YahooExchange {
+    pub cookies: Option<String>,
+    pub body: Option<String>,
+}
+
+/// The parts of the result a replay must reproduce.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Expected {
+    pub is_reachable: MyReachable,
+    pub can_connect_smtp: Option<bool>,
+    pub has_full_inbox: Option<bool>,
+    pub is_catch_all: Option<bool>,
+    pub is_deliverable: Option<bool>,
+    pub is_disabled: Option<bool>,
+    pub is_banned: Option<bool>,
+    pub provider: Option<Provider>,
+}
+
+impl Default for Expected {
+    fn default() -> Self {
+        Expected::from(&EmailCheckResponse::default())
+    }
+}
+
+impl From<&EmailCheckResponse> for Expected {
+    fn from(result: &EmailCheckResponse) -> Self {
+        Expected {
+            is_reachable: result.is_reachable,
+            can_connect_smtp: result.can_connect_smtp,
+            has_full_inbox: result.has_full_inbox,
+            is_catch_all: result.is_catch_all,
+            is_deliverable: result.is_deliverable,
+            is_disabled: result.is_disabled,
+            is_banned: result.is_banned,
+            provider: result.provider,
+        }
+    }
+}
+
+/// Things a replay did differently from the recording.
+pub type Mismatches = Arc<Mutex<Vec<String>>>;
+
+impl Fixture {
+    pub fn load(path: &Path) -> io::Result<Fixture> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Write the fixture to `dir`, named after the address.
+    pub fn save(&self, dir: &Path) -> io::Result<PathBuf> {
+        let name: String = self
+            .email
+            .chars()
+            .map(|c| match c {
+                '@' => '_',
+                c if c.is_ascii_alphanumeric() || c == '.' || c == '-' => c,
+                _ => '-',
+            })
+            .collect();
+        let path = dir.join(format!("{}.json", name));
+
+        fs::create_dir_all(dir)?;
+        fs::write(&path, serde_json::to_string_pretty(self)? + "\n")?;
+
+        Ok(path)
+    }
+
+    /// Check `email` against the real network and record what happened.
+    pub async fn record(email: &str) -> Fixture {
+        let resolver = Arc::new(RecordingResolver::default());
+        dns::set_resolver(resolver.clone());
+        let yahoo_api = Arc::new(RecordingYahooApi::default());
+        yahoo::set_api(yahoo_api.clone());
+        let proxy = SocksProxy::recording();
+
+        let config = Config::load(None).expect("valid config");
+        // Recordings in the same run share the first config.
+        let _ = config::init(config.clone());
+        let result = check(email, EmailCheckInput::from_config(&config), &proxy).await;
+
+        let dns = resolver.answers.lock().unwrap().clone();
+        let yahoo = yahoo_api.exchange.lock().unwrap().clone();
+
+        Fixture {
+            email: email.into(),
+            dns,
+            smtp: proxy.sessions(),
+            yahoo,
+            expected: Expected::from(&result),
+        }
+    }
+
+    /// Check the address against the recorded answers. Returns the result,
+    /// and every way the check strayed from the recording.
+    pub async fn replay(&self) -> (EmailCheckResponse, Vec<String>) {
+        let mismatches = Mismatches::default();
+
+        let mut resolver = FakeResolver::new();
+        for (domain, answer) in &self.dns {
+            resolver = match answer {
+                Ok(hosts) => {
+                    let hosts: Vec<&str> = hosts.iter().map(String::as_str).collect();
+                    resolver.mx(domain, &hosts)
+                }
+                Err(message) => resolver.fail(domain, message),
+            };
+        }
+        dns::set_resolver(Arc::new(resolver));
+        yahoo::set_api(Arc::new(ReplayYahooApi {
+            exchange: self.yahoo.clone(),
+            mismatches: mismatches.clone(),
+        }));
+        let proxy = SocksProxy::replaying(self.smtp.clone(), mismatches.clone());
+
+        let result = check(&self.email, EmailCheckInput::default(), &proxy).await;
+        if proxy.unused_sessions() > 0 {
+            mismatches.lock().unwrap().push(format!(
+                "{} recorded SMTP sessions were never opened",
+                proxy.unused_sessions()
+            ));
+        }
+
+        let mismatches = mismatches.lock().unwrap().clone();
+        (result, mismatches)
+    }
+}
+
+async fn check(email: &str, mut input: EmailCheckInput, proxy: &SocksProxy) -> EmailCheckResponse {
+    input.to_emails = vec![email.into()];
+    input
+        .set_smtp_timeout(Duration::from_secs(30))
+        .set_proxy(ProxyInput {
+            host: "127.0.0.1".into(),
+            port: proxy.port(),
+        });
+
+    match check_single_email(input).await {
+        Ok(result) => result,
+        Err(result) => result,
+    }
+}
+
+/// The real resolver, keeping every answer.
+#[derive(Debug, Default)]
+pub struct RecordingResolver {
+    answers: Mutex<BTreeMap<String, Result<Vec<String>, String>>>,
+}
+
+impl MxResolver for RecordingResolver {
+    fn mx_hosts<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, Result<Vec<Name>, ResolveError>> {
+        async move {
+            let answer = DnsResolver.mx_hosts(domain).await;
+            let recorded = match &answer {
+                Ok(hosts) => Ok(hosts.iter().map(Name::to_utf8).collect()),
+                Err(err) => Err(err.to_string()),
+            };
+            self.answers
+                .lock()
+                .unwrap()
+                .insert(domain.to_lowercase(), recorded);
+
+            answer
+        }
+        .boxed()
+    }
+}
+
+/// The real Yahoo API, keeping the exchange.
+#[derive(Debug, Default)]
+pub struct RecordingYahooApi {
+    exchange: Mutex<Option<YahooExchange>>,
+}
+
+impl YahooApi for RecordingYahooApi {
+    fn signup_cookies<'a>(
+        &'a self,
+        input: &'a CheckEmailInput,
+    ) -> BoxFuture<'a, Result<Option<String>, YahooError>> {
+        async move {
+            let cookies = HttpYahooApi.signup_cookies(input).await;
+            *self.exchange.lock().unwrap() = Some(YahooExchange {
+                cookies: cookies.as_ref().ok().cloned().flatten(),
+                body: None,
+            });
+
+            cookies
+        }
+        .boxed()
+    }
+
+    fn validate_username<'a>(
+        &'a self,
+        input: &'a CheckEmailInput,
+        cookies: &'a str,
+        acrumb: &'a str,
+        username: &'a str,
+    ) -> BoxFuture<'a, Result<String, YahooError>> {
+        async move {
+            let body = HttpYahooApi
+                .validate_username(input, cookies, acrumb, username)
+                .await;
+            if let Some(exchange) = self.exchange.lock().unwrap().as_mut() {
+                exchange.body = body.as_ref().ok().cloned();
+            }
+
+            body
+        }
+        .boxed()
+    }
+}
+
+struct ReplayYahooApi {
+    exchange: Option<YahooExchange>,
+    mismatches: Mismatches,
+}
+
+impl ReplayYahooApi {
+    fn recorded<T>(&self, field: impl Fn(&YahooExchange) -> Option<T>) -> Option<T> {
+        match &self.exchange {
+            Some(exchange) => field(exchange),
+            None => {
+                self.mismatches
+                    .lock()
+                    .unwrap()
+                    .push("Yahoo API called, but not recorded".into());
+                None
+            }
+        }
+    }
+}
+
+impl YahooApi for ReplayYahooApi {
+    fn signup_cookies<'a>(
+        &'a self,
+        _input: &'a CheckEmailInput,
+    ) -> BoxFuture<'a, Result<Option<String>, YahooError>> {
+        future::ready(Ok(self.recorded(|exchange| exchange.cookies.clone()))).boxed()
+    }
+
+    fn validate_username<'a>(
+        &'a self,
+        _input: &'a CheckEmailInput,
+        _cookies: &'a str,
+        _acrumb: &'a str,
+        _username: &'a str,
+    ) -> BoxFuture<'a, Result<String, YahooError>> {
+        let body = self
+            .recorded(|exchange| exchange.body.clone())
+            .ok_or(YahooError::NoCookie);
+
+        future::ready(body).boxed()
+    }
+}
+
+enum Mode {
+    Record,
+    Replay(Mismatches),
+}
+
+struct ProxyState {
+    mode: Mode,
+    /// Recorded sessions, or the ones left to replay.
+    sessions: Vec<SmtpSession>,
+    /// How many SMTP sessions were opened so far.
+    opened: usize,
+}
+
+/// A SOCKS5 proxy on 127.0.0.1 that either relays to the real servers and
+/// transcribes the SMTP sessions, or plays recorded sessions back.
+pub struct SocksProxy {
+    addr: SocketAddr,
+    state: Arc<Mutex<ProxyState>>,
+}
+
+impl SocksProxy {
+    pub fn recording() -> SocksProxy {
+        SocksProxy::start(Mode::Record, vec![])
+    }
+
+    pub fn replaying(sessions: Vec<SmtpSession>, mismatches: Mismatches) -> SocksProxy {
+        SocksProxy::start(Mode::Replay(mismatches), sessions)
+    }
+
+    fn start(mode: Mode, sessions: Vec<SmtpSession>) -> SocksProxy {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind SOCKS5 proxy");
+        let addr = listener.local_addr().expect("SOCKS5 proxy address");
+        let state = Arc::new(Mutex::new(ProxyState {
+            mode,
+            sessions,
+            opened: 0,
+        }));
+
+        let proxy_state = Arc::clone(&state);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = Arc::clone(&proxy_state);
+                thread::spawn(move || {
+                    let _ = serve(stream, &state);
+                });
+            }
+        });
+
+        SocksProxy { addr, state }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// The recorded SMTP sessions.
+    pub fn sessions(&self) -> Vec<SmtpSession> {
+        self.state.lock().unwrap().sessions.clone()
+    }
+
+    /// How many recorded sessions were not replayed.
+    pub fn unused_sessions(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.sessions.len().saturating_sub(state.opened)
+    }
+}
+
+/// Read the SOCKS5 greeting and `CONNECT` request, returning the target.
+fn handshake(stream: &mut TcpStream) -> io::Result<(String, u16)> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header)?;
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods)?;
+    // No authentication.
+    stream.write_all(&[5, 0])?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request)?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip)?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            let mut name = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut name)?;
+            String::from_utf8_lossy(&name).into_owned()
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip)?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad SOCKS5 address",
+            ))
+        }
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port)?;
+
+    Ok((host, u16::from_be_bytes(port)))
+}
+
+/// Answer the `CONNECT` request: success, or "connection refused".
+fn connect_reply(stream: &mut TcpStream, success: bool) -> io::Result<()> {
+    let status = if success { 0 } else { 5 };
+    stream.write_all(&[5, status, 0, 1, 0, 0, 0, 0, 0, 0])
+}
+
+fn serve(mut client: TcpStream, state: &Mutex<ProxyState>) -> io::Result<()> {
+    let (host, port) = handshake(&mut client)?;
+    let smtp = SMTP_PORTS.contains(&port);
+
+    let index = {
+        let mut state = state.lock().unwrap();
+        if !smtp {
+            None
+        } else {
+            state.opened += 1;
+            if let Mode::Record = state.mode {
+                state.sessions.push(SmtpSession {
+                    host: host.clone(),
+                    port,
+                    ..Default::default()
+                });
+            }
+            Some(state.opened - 1)
+        }
+    };
+
+    let replay = match &state.lock().unwrap().mode {
+        Mode::Record => None,
+        Mode::Replay(mismatches) => Some(mismatches.clone()),
+    };
+    match (index, replay) {
+        (Some(index), None) => {
+            let mut session = SmtpSession {
+                host: host.clone(),
+                port,
+                ..Default::default()
+            };
+            let result = record_smtp(client, &host, port, &mut session);
+            state.lock().unwrap().sessions[index] = session;
+            result
+        }
+        (Some(index), Some(mismatches)) => {
+            let session = state.lock().unwrap().sessions.get(index).cloned();
+            replay_smtp(client, &host, port, session, &mismatches)
+        }
+        (None, None) => relay(client, &host, port),
+        (None, Some(mismatches)) => {
+            mismatches
+                .lock()
+                .unwrap()
+                .push(format!("unexpected connection to {}:{}", host, port));
+            connect_reply(&mut client, false)
+        }
+    }
+}
+
+/// Relay bytes both ways without looking at them.
+fn relay(mut client: TcpStream, host: &str, port: u16) -> io::Result<()> {
+    let upstream = match TcpStream::connect((host.trim_end_matches('.'), port)) {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            connect_reply(&mut client, false)?;
+            return Err(err);
+        }
+    };
+    connect_reply(&mut client, true)?;
+
+    let (mut client_reader, mut upstream_writer) = (client.try_clone()?, upstream.try_clone()?);
+    thread::spawn(move || io::copy(&mut client_reader, &mut upstream_writer));
+    io::copy(&mut &upstream, &mut client)?;
+
+    Ok(())
+}
+
+/// Read one reply, all its lines with their endings. `None` if the server
+/// hung up before finishing it.
+fn read_reply(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut reply = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        reply.push_str(&line);
+        // "250-..." continues the reply, "250 ..." ends it.
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(Some(reply));
+        }
+    }
+}
+
+/// Relay an SMTP session one command and reply at a time, writing them down.
+fn record_smtp(
+    mut client: TcpStream,
+    host: &str,
+    port: u16,
+    session: &mut SmtpSession,
+) -> io::Result<()> {
+    let mut upstream = match TcpStream::connect((host.trim_end_matches('.'), port)) {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            session.refused = true;
+            connect_reply(&mut client, false)?;
+            return Err(err);
+        }
+    };
+    connect_reply(&mut client, true)?;
+
+    let mut client_reader = BufReader::new(client.try_clone()?);
+    let mut upstream_reader = BufReader::new(upstream.try_clone()?);
+
+    session.banner = read_reply(&mut upstream_reader)?;
+    match &session.banner {
+        Some(banner) => client.write_all(banner.as_bytes())?,
+        None => return Ok(()),
+    }
+
+    loop {
+        let mut command = String::new();
+        if client_reader.read_line(&mut command)? == 0 {
+            return Ok(());
+        }
+        upstream.write_all(command.as_bytes())?;
+
+        let reply = read_reply(&mut upstream_reader)?;
+        session.exchanges.push(SmtpExchange {
+            command: command.trim_end().into(),
+            reply: reply.clone(),
+        });
+        match reply {
+            Some(reply) => client.write_all(reply.as_bytes())?,
+            None => return Ok(()),
+        }
+    }
+}
+
+fn verb(command: &str) -> String {
+    command
+        .trim_end()
+        .split(&[' ', ':'][..])
+        .next()
+        .unwrap_or("")
+        .to_uppercase()
+}
+
+/// Play a recorded SMTP session back. Commands are matched by verb only, as
+/// the catch-all probe uses a new random address every time.
+fn replay_smtp(
+    mut client: TcpStream,
+    host: &str,
+    port: u16,
+    session: Option<SmtpSession>,
+    mismatches: &Mismatches,
+) -> io::Result<()> {
+    let mismatch = |message: String| mismatches.lock().unwrap().push(message);
+
+    let session = match session {
+        Some(session) => session,
+        None => {
+            mismatch(format!("unrecorded SMTP session to {}:{}", host, port));
+            return connect_reply(&mut client, false);
+        }
+    };
+    if session.host != host || session.port != port {
+        mismatch(format!(
+            "SMTP session to {}:{}, recorded to {}:{}",
+            host, port, session.host, session.port
+        ));
+    }
+    if session.refused {
+        return connect_reply(&mut client, false);
+    }
+    connect_reply(&mut client, true)?;
+
+    match &session.banner {
+        Some(banner) => client.write_all(banner.as_bytes())?,
+        None => return Ok(()),
+    }
+
+    let mut reader = BufReader::new(client.try_clone()?);
+    for exchange in &session.exchanges {
+        let mut command = String::new();
+        if reader.read_line(&mut command)? == 0 {
+            mismatch(format!(
+                "{}: client hung up, recording continues with {}",
+                host, exchange.command
+            ));
+            return Ok(());
+        }
+        if verb(&command) != verb(&exchange.command) {
+            mismatch(format!(
+                "{}: got {}, recorded {}",
+                host,
+                command.trim_end(),
+                exchange.command
+            ));
+        }
+        match &exchange.reply {
+            Some(reply) => client.write_all(reply.as_bytes())?,
+            None => return Ok(()),
+        }
+    }
+
+    let mut command = String::new();
+    if reader.read_line(&mut command)? > 0 {
+        mismatch(format!(
+            "{}: got {} past the end of the recording",
+            host,
+            command.trim_end()
+        ));
+        client.write_all(b"421 4.3.0 End of recording\r\n")?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,6 @@
+// Shared helpers for the integration tests. Each test binary uses only some
+// of them.
+#![allow(dead_code)]
+
+pub mod fixtures;
+pub mod smtp_server;
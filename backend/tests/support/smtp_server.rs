@@ -0,0 +1,301 @@
+// A scriptable SMTP server for the integration tests.
+//
+// It listens on a random port on 127.0.0.1 and serves every connection on its
+// own thread, so it works under any async runtime. The script sets the
+// banner, the EHLO capabilities and the reply to each `RCPT TO`, including
+// delays, abrupt disconnects and greylisting. Other commands get a plain
+// `250`. Point `check_smtp` at it with `host()` and `port()`.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use trust_dns_proto::rr::Name;
+
+/// What the server does in response to a command.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Send a single-line reply.
+    Reply(u16, String),
+    /// Send a reply, then close the connection, like servers that hang up
+    /// after an error.
+    ReplyAndDisconnect(u16, String),
+    /// Wait, then run the inner action.
+    Delay(Duration, Box<Action>),
+    /// Close the connection without replying.
+    Disconnect,
+}
+
+impl Action {
+    pub fn reply(code: u16, text: &str) -> Action {
+        Action::Reply(code, text.into())
+    }
+
+    pub fn reply_and_disconnect(code: u16, text: &str) -> Action {
+        Action::ReplyAndDisconnect(code, text.into())
+    }
+
+    /// Run this action after `delay`.
+    pub fn after(self, delay: Duration) -> Action {
+        Action::Delay(delay, Box::new(self))
+    }
+}
+
+/// The behaviour of a `MockSmtpServer`.
+///
+/// Replies to `RCPT TO` are given as a sequence per recipient: the n-th
+/// attempt for an address, counted over all connections, gets the n-th
+/// action, and the last one repeats.
+#[derive(Debug, Clone)]
+pub struct SmtpScript {
+    banner: String,
+    banner_delay: Duration,
+    capabilities: Vec<String>,
+    recipients: HashMap<String, Vec<Action>>,
+    other_recipients: Vec<Action>,
+}
+
+impl Default for SmtpScript {
+    fn default() -> Self {
+        SmtpScript {
+            banner: "220 mock.test ESMTP ready".into(),
+            banner_delay: Duration::from_secs(0),
+            capabilities: vec!["PIPELINING".into(), "8BITMIME".into()],
+            recipients: HashMap::new(),
+            other_recipients: vec![Action::reply(550, "5.1.1 User unknown")],
+        }
+    }
+}
+
+impl SmtpScript {
+    /// A server knowing no recipients, which rejects every `RCPT TO` with
+    /// "550 5.1.1 User unknown".
+    pub fn new() -> SmtpScript {
+        SmtpScript::default()
+    }
+
+    /// Set the greeting line, including its code.
+    pub fn banner(mut self, banner: &str) -> SmtpScript {
+        self.banner = banner.into();
+        self
+    }
+
+    /// Wait before sending the greeting.
+    pub fn banner_delay(mut self, delay: Duration) -> SmtpScript {
+        self.banner_delay = delay;
+        self
+    }
+
+    /// Replace the capabilities announced in the EHLO reply.
+    pub fn capabilities(mut self, capabilities: &[&str]) -> SmtpScript {
+        self.capabilities = capabilities.iter().map(|cap| cap.to_string()).collect();
+        self
+    }
+
+    /// Set the replies to `RCPT TO:<email>`.
+    pub fn recipient(mut self, email: &str, actions: Vec<Action>) -> SmtpScript {
+        self.recipients.insert(email.to_lowercase(), actions);
+        self
+    }
+
+    /// Set the replies to recipients without their own script, like the
+    /// random address of the catch-all probe.
+    pub fn other_recipients(mut self, actions: Vec<Action>) -> SmtpScript {
+        self.other_recipients = actions;
+        self
+    }
+
+    /// Accept every recipient without its own script.
+    pub fn catch_all(self) -> SmtpScript {
+        self.other_recipients(vec![Action::reply(250, "2.1.5 OK")])
+    }
+
+    /// Defer the first attempt for `email` with a 451, and accept it after.
+    pub fn greylist(self, email: &str) -> SmtpScript {
+        self.recipient(
+            email,
+            vec![
+                Action::reply(451, "4.7.1 Greylisted, please try again later"),
+                Action::reply(250, "2.1.5 OK"),
+            ],
+        )
+    }
+
+    /// Start serving this script.
+    pub fn start(self) -> MockSmtpServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind mock SMTP server");
+        let addr = listener.local_addr().expect("mock SMTP server address");
+        let state = Arc::new(Mutex::new(State {
+            script: self,
+            attempts: HashMap::new(),
+            connections: 0,
+            commands: vec![],
+        }));
+
+        let server_state = Arc::clone(&state);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = Arc::clone(&server_state);
+                thread::spawn(move || {
+                    let _ = serve(stream, &state);
+                });
+            }
+        });
+
+        MockSmtpServer { addr, state }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    script: SmtpScript,
+    /// `RCPT TO` attempts so far, per lowercased address.
+    attempts: HashMap<String, usize>,
+    connections: usize,
+    commands: Vec<String>,
+}
+
+impl State {
+    fn rcpt_action(&mut self, email: &str) -> Action {
+        let email = email.to_lowercase();
+        let attempt = self.attempts.entry(email.clone()).or_insert(0);
+        let actions = self
+            .script
+            .recipients
+            .get(&email)
+            .unwrap_or(&self.script.other_recipients);
+        let action = actions
+            .get(*attempt)
+            .or_else(|| actions.last())
+            .cloned()
+            .unwrap_or(Action::Disconnect);
+        *attempt += 1;
+
+        action
+    }
+}
+
+/// A running scripted SMTP server. It stops with the test process.
+pub struct MockSmtpServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockSmtpServer {
+    /// The host to pass to `check_smtp`.
+    pub fn host(&self) -> Name {
+        Name::from_ascii(self.addr.ip().to_string()).expect("IP is a valid name")
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// How many connections were accepted so far.
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    /// Every command received so far, without the line ending.
+    pub fn commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    /// The addresses of every `RCPT TO` received so far.
+    pub fn recipients(&self) -> Vec<String> {
+        self.commands()
+            .iter()
+            .filter_map(|command| rcpt_address(command))
+            .collect()
+    }
+}
+
+/// The address in `RCPT TO:<address>`.
+fn rcpt_address(command: &str) -> Option<String> {
+    if !command.to_uppercase().starts_with("RCPT TO:") {
+        return None;
+    }
+    let start = command.find('<')? + 1;
+    let end = command.rfind('>')?;
+
+    command.get(start..end).map(String::from)
+}
+
+/// Run `action`. Returns whether the connection stays open.
+fn run(writer: &mut TcpStream, action: Action) -> io::Result<bool> {
+    match action {
+        Action::Reply(code, text) => {
+            write!(writer, "{} {}\r\n", code, text)?;
+            Ok(true)
+        }
+        Action::ReplyAndDisconnect(code, text) => {
+            write!(writer, "{} {}\r\n", code, text)?;
+            Ok(false)
+        }
+        Action::Delay(delay, action) => {
+            thread::sleep(delay);
+            run(writer, *action)
+        }
+        Action::Disconnect => Ok(false),
+    }
+}
+
+fn serve(mut writer: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    let mut reader = BufReader::new(writer.try_clone()?);
+    let (banner, banner_delay, capabilities) = {
+        let mut state = state.lock().unwrap();
+        state.connections += 1;
+        let script = &state.script;
+
+        (
+            script.banner.clone(),
+            script.banner_delay,
+            script.capabilities.clone(),
+        )
+    };
+
+    thread::sleep(banner_delay);
+    write!(writer, "{}\r\n", banner)?;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let command = line.trim_end().to_string();
+        state.lock().unwrap().commands.push(command.clone());
+
+        let verb = command
+            .split(&[' ', ':'][..])
+            .next()
+            .unwrap_or("")
+            .to_uppercase();
+        let action = match verb.as_str() {
+            "EHLO" => {
+                let mut lines = vec!["mock.test".to_string()];
+                lines.extend(capabilities.iter().cloned());
+                for (i, text) in lines.iter().enumerate() {
+                    let separator = if i + 1 == lines.len() { ' ' } else { '-' };
+                    write!(writer, "250{}{}\r\n", separator, text)?;
+                }
+                continue;
+            }
+            "HELO" => Action::reply(250, "mock.test"),
+            "MAIL" | "RSET" | "NOOP" => Action::reply(250, "2.0.0 OK"),
+            "RCPT" => match rcpt_address(&command) {
+                Some(email) => state.lock().unwrap().rcpt_action(&email),
+                None => Action::reply(501, "5.1.3 Bad recipient address syntax"),
+            },
+            "QUIT" => Action::reply_and_disconnect(221, "2.0.0 Bye"),
+            _ => Action::reply(502, "5.5.2 Command not recognized"),
+        };
+
+        if !run(&mut writer, action)? {
+            return Ok(());
+        }
+    }
+}